@@ -0,0 +1,336 @@
+use std::cmp::Ordering;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::Autoindex;
+use crate::interface::OKResponse;
+use crate::utils;
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortKey {
+    fn name(&self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "modified",
+        }
+    }
+}
+
+/// Sort order requested with the `sort=name|size|modified` and `order=asc|desc`
+/// query parameters. Unknown values fall back to ascending by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Sort {
+    key: SortKey,
+    descending: bool,
+}
+
+impl Sort {
+    fn from_query(query: &str) -> Self {
+        let mut sort = Sort {
+            key: SortKey::Name,
+            descending: false,
+        };
+        for (name, value) in utils::parse_query(query) {
+            match (name.as_str(), value.as_str()) {
+                ("sort", "name") => sort.key = SortKey::Name,
+                ("sort", "size") => sort.key = SortKey::Size,
+                ("sort", "modified") => sort.key = SortKey::Modified,
+                ("order", "asc") => sort.descending = false,
+                ("order", "desc") => sort.descending = true,
+                _ => {}
+            }
+        }
+        sort
+    }
+
+    fn compare(&self, a: &Entry, b: &Entry) -> Ordering {
+        let ordering = match self.key {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        // Directories stay on top whichever way the rest is sorted
+        let ordering = if self.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+        b.is_dir.cmp(&a.is_dir).then(ordering)
+    }
+
+    /// Query string for a column header: sorts by `key`, flipping the order if
+    /// the listing is already sorted by it.
+    fn link(&self, key: SortKey) -> String {
+        let descending = self.key == key && !self.descending;
+        let order = if descending { "desc" } else { "asc" };
+        format!("?sort={}&amp;order={}", key.name(), order)
+    }
+}
+
+fn read_entries(directory: &Path, show_hidden: bool) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') && !show_hidden {
+            continue;
+        }
+        // Follow symlinks so they are listed as what they point to
+        let metadata = match fs::metadata(entry.path()) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                println!("Skipping {:?} in listing: {}", entry.path(), e);
+                continue;
+            }
+        };
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Whether the `Accept` header ranks `application/json` above `text/html`.
+fn wants_json(headers: &[String]) -> bool {
+    let accept = match utils::get_header(headers, "Accept") {
+        Some(accept) => utils::parse_qvalues(accept),
+        None => return false,
+    };
+    // The most specific matching media range decides the weight of a type
+    let quality = |mime: &str| {
+        let (kind, _) = mime.split_once('/').unwrap_or((mime, ""));
+        let wildcard = format!("{}/*", kind);
+        [mime, wildcard.as_str(), "*/*"]
+            .iter()
+            .find_map(|range| accept.iter().find(|(r, _)| r == range))
+            .map_or(0.0, |(_, quality)| *quality)
+    };
+    quality("application/json") > quality("text/html")
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unix_seconds(time: Option<SystemTime>) -> Option<u64> {
+    time?.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+fn render_html(url_path: &str, entries: &[Entry], sort: Sort) -> String {
+    let title = escape_html(&format!("Index of {}", url_path));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{title}</title></head>\n\
+         <body>\n<h1>{title}</h1>\n<table>\n<tr><th><a href=\"{}\">Name</a></th>\
+         <th><a href=\"{}\">Size</a></th><th><a href=\"{}\">Modified</a></th></tr>\n",
+        sort.link(SortKey::Name),
+        sort.link(SortKey::Size),
+        sort.link(SortKey::Modified),
+    );
+
+    if url_path != "/files/" {
+        if let Some((parent, _)) = url_path.trim_end_matches('/').rsplit_once('/') {
+            html.push_str(&format!(
+                "<tr><td><a href=\"{}/\">../</a></td><td></td><td></td></tr>\n",
+                escape_html(&utils::percent_encode(parent))
+            ));
+        }
+    }
+
+    for entry in entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let href = format!("{}{}{}", url_path, entry.name, suffix);
+        let size = if entry.is_dir {
+            "-".to_string()
+        } else {
+            entry.size.to_string()
+        };
+        let modified = entry.modified.map(utils::http_date).unwrap_or_default();
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            escape_html(&utils::percent_encode(&href)),
+            escape_html(&entry.name),
+            suffix,
+            size,
+            modified,
+        ));
+    }
+
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+fn render_json(url_path: &str, entries: &[Entry]) -> String {
+    let entries: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let size = if entry.is_dir {
+                "null".to_string()
+            } else {
+                entry.size.to_string()
+            };
+            let modified =
+                unix_seconds(entry.modified).map_or("null".to_string(), |s| s.to_string());
+            format!(
+                "{{\"name\":{},\"type\":\"{}\",\"size\":{},\"modified\":{}}}",
+                utils::escape_json(&entry.name),
+                if entry.is_dir { "directory" } else { "file" },
+                size,
+                modified,
+            )
+        })
+        .collect();
+    format!(
+        "{{\"path\":{},\"entries\":[{}]}}",
+        utils::escape_json(url_path),
+        entries.join(",")
+    )
+}
+
+/// Lists `directory`, which was requested as `url_path` (ending in `/`), as HTML
+/// or JSON depending on the request's `Accept` header.
+pub fn listing(
+    directory: &Path,
+    url_path: &str,
+    query: &str,
+    headers: &[String],
+    config: &Autoindex,
+) -> io::Result<OKResponse> {
+    let mut entries = read_entries(directory, config.show_hidden)?;
+    let sort = Sort::from_query(query);
+    entries.sort_by(|a, b| sort.compare(a, b));
+
+    let response = if wants_json(headers) {
+        OKResponse::new(render_json(url_path, &entries)).with_content_type("application/json")
+    } else {
+        OKResponse::new(render_html(url_path, &entries, sort))
+            .with_content_type("text/html; charset=utf-8")
+    };
+    Ok(response.with_vary("Accept"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::HttpResponse;
+
+    fn names(body: &str) -> Vec<String> {
+        body.split("\"name\":\"")
+            .skip(1)
+            .map(|rest| rest.split('"').next().unwrap().to_string())
+            .collect()
+    }
+
+    fn list(directory: &Path, query: &str, config: &Autoindex) -> String {
+        let headers = vec!["Accept: application/json".to_string()];
+        let response = listing(directory, "/files/dir/", query, &headers, config).unwrap();
+        let response = String::from_utf8(response.response()).unwrap();
+        response.split_once("\r\n\r\n").unwrap().1.to_string()
+    }
+
+    #[test]
+    fn listing_sorts_and_hides_entries() {
+        let directory = utils::temp_dir("autoindex");
+        fs::write(directory.join("b.txt"), "1").unwrap();
+        fs::write(directory.join("a.txt"), "12345").unwrap();
+        fs::write(directory.join(".secret"), "").unwrap();
+        fs::create_dir(directory.join("sub")).unwrap();
+
+        let config = Autoindex::default();
+        assert_eq!(
+            names(&list(&directory, "", &config)),
+            ["sub", "a.txt", "b.txt"]
+        );
+        assert_eq!(
+            names(&list(&directory, "sort=size", &config)),
+            ["sub", "b.txt", "a.txt"]
+        );
+        assert_eq!(
+            names(&list(&directory, "sort=name&order=desc", &config)),
+            ["sub", "b.txt", "a.txt"]
+        );
+
+        let config = Autoindex {
+            show_hidden: true,
+            ..Autoindex::default()
+        };
+        assert_eq!(
+            names(&list(&directory, "", &config)),
+            ["sub", ".secret", "a.txt", "b.txt"]
+        );
+
+        let body = list(&directory, "", &config);
+        assert!(body.starts_with("{\"path\":\"/files/dir/\",\"entries\":["));
+        assert!(body.contains("{\"name\":\"a.txt\",\"type\":\"file\",\"size\":5,\"modified\":"));
+        assert!(body.contains("{\"name\":\"sub\",\"type\":\"directory\",\"size\":null,"));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn listing_renders_html_by_default() {
+        let directory = utils::temp_dir("autoindex-html");
+        fs::write(directory.join("<b> & c.txt"), "").unwrap();
+
+        let headers = vec!["Accept: text/html,application/json;q=0.9".to_string()];
+        let response = listing(
+            &directory,
+            "/files/dir/",
+            "",
+            &headers,
+            &Autoindex::default(),
+        )
+        .unwrap();
+        assert_eq!(response.content_type(), Some("text/html; charset=utf-8"));
+
+        let body = String::from_utf8(response.response()).unwrap();
+        assert!(body.contains("<title>Index of /files/dir/</title>"));
+        assert!(
+            body.contains("<a href=\"/files/dir/%3Cb%3E%20%26%20c.txt\">&lt;b&gt; &amp; c.txt</a>")
+        );
+        assert!(body.contains("<a href=\"/files/\">../</a>"));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn wants_json_follows_accept_weights() {
+        let accept = |value: &str| wants_json(&[format!("Accept: {}", value)]);
+        assert!(!wants_json(&[]));
+        assert!(accept("application/json"));
+        assert!(accept("application/*"));
+        assert!(!accept("text/html, application/json"));
+        assert!(accept("text/html;q=0.5, application/json"));
+        assert!(!accept("*/*"));
+    }
+}
@@ -0,0 +1,426 @@
+use std::collections::HashMap;
+use std::fs::{File, Metadata};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::SystemTime;
+
+use crate::conditional::Validators;
+use crate::config::{CacheLimits, CompressionLevels, ETagMode};
+use crate::encoding::Encoding;
+use crate::utils;
+
+/// Identifies one version of a file. An entry whose version no longer matches
+/// the file on disk is stale.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Version {
+    modified: Option<SystemTime>,
+    length: u64,
+    /// Tells apart a file replaced by a rename, which can keep size and mtime.
+    inode: u64,
+}
+
+impl Version {
+    fn of(metadata: &Metadata) -> Self {
+        Self {
+            modified: metadata.modified().ok(),
+            length: metadata.len(),
+            inode: utils::inode(metadata),
+        }
+    }
+}
+
+/// A file's contents together with their validators.
+#[derive(Debug, Clone)]
+pub struct CachedFile {
+    pub contents: Arc<Vec<u8>>,
+    pub validators: Validators,
+    version: Version,
+}
+
+struct Variant {
+    encoding: Encoding,
+    levels: CompressionLevels,
+    body: Arc<Vec<u8>>,
+}
+
+struct Entry {
+    file: CachedFile,
+    etag_mode: ETagMode,
+    variants: Vec<Variant>,
+    last_used: u64,
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        let variants: usize = self.variants.iter().map(|variant| variant.body.len()).sum();
+        self.file.contents.len() + variants
+    }
+}
+
+/// Counters reported by `/stats/cache`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub invalidations: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+impl CacheStats {
+    pub fn to_json(self) -> String {
+        format!(
+            "{{\"hits\":{},\"misses\":{},\"evictions\":{},\"invalidations\":{},\"entries\":{},\"bytes\":{}}}",
+            self.hits, self.misses, self.evictions, self.invalidations, self.entries, self.bytes
+        )
+    }
+}
+
+/// Least recently used file contents and their compressed variants, bounded by
+/// the total size of both.
+#[derive(Default)]
+pub struct FileCache {
+    entries: HashMap<PathBuf, Entry>,
+    /// Bumped on every use; the entry with the lowest `last_used` goes first.
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl FileCache {
+    /// The cached copy of `path`, if it is still the given version.
+    fn get(&mut self, path: &Path, version: &Version, etag_mode: ETagMode) -> Option<CachedFile> {
+        self.clock += 1;
+        let current = match self.entries.get_mut(path) {
+            Some(entry) if entry.file.version == *version && entry.etag_mode == etag_mode => {
+                entry.last_used = self.clock;
+                Some(entry.file.clone())
+            }
+            Some(_) => {
+                self.remove(path);
+                self.stats.invalidations += 1;
+                None
+            }
+            None => None,
+        };
+        match current {
+            Some(_) => self.stats.hits += 1,
+            None => self.stats.misses += 1,
+        }
+        current
+    }
+
+    fn insert(
+        &mut self,
+        path: &Path,
+        file: &CachedFile,
+        etag_mode: ETagMode,
+        limits: &CacheLimits,
+    ) {
+        if file.contents.len() > limits.max_size {
+            return;
+        }
+        self.clock += 1;
+        self.remove(path);
+        let entry = Entry {
+            file: file.clone(),
+            etag_mode,
+            variants: Vec::new(),
+            last_used: self.clock,
+        };
+        self.stats.bytes += entry.size();
+        self.stats.entries += 1;
+        self.entries.insert(path.to_path_buf(), entry);
+        self.evict(limits.max_size);
+    }
+
+    fn variant(
+        &self,
+        path: &Path,
+        version: &Version,
+        encoding: Encoding,
+        levels: &CompressionLevels,
+    ) -> Option<Arc<Vec<u8>>> {
+        let entry = self
+            .entries
+            .get(path)
+            .filter(|entry| entry.file.version == *version)?;
+        entry
+            .variants
+            .iter()
+            .find(|variant| variant.encoding == encoding && variant.levels == *levels)
+            .map(|variant| variant.body.clone())
+    }
+
+    /// Adds a compressed variant to the entry for `version` of `path`, unless that
+    /// entry has been replaced or evicted in the meantime.
+    fn insert_variant(
+        &mut self,
+        path: &Path,
+        version: &Version,
+        variant: Variant,
+        limits: &CacheLimits,
+    ) {
+        let entry = match self.entries.get_mut(path) {
+            Some(entry) if entry.file.version == *version => entry,
+            _ => return,
+        };
+        let size = variant.body.len();
+        let previous: usize = entry
+            .variants
+            .iter()
+            .filter(|existing| existing.encoding == variant.encoding)
+            .map(|existing| existing.body.len())
+            .sum();
+        entry
+            .variants
+            .retain(|existing| existing.encoding != variant.encoding);
+        entry.variants.push(variant);
+        self.stats.bytes = self.stats.bytes - previous + size;
+        self.evict(limits.max_size);
+    }
+
+    /// Drops the entry for `path`, and if it is a directory, those of all files
+    /// below it.
+    pub fn invalidate(&mut self, path: &Path) {
+        let stale: Vec<PathBuf> = self
+            .entries
+            .keys()
+            .filter(|cached| cached.starts_with(path))
+            .cloned()
+            .collect();
+        for cached in stale {
+            self.remove(&cached);
+            self.stats.invalidations += 1;
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    fn remove(&mut self, path: &Path) -> bool {
+        match self.entries.remove(path) {
+            Some(entry) => {
+                self.stats.bytes -= entry.size();
+                self.stats.entries -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn evict(&mut self, max_size: usize) {
+        while self.stats.bytes > max_size {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone());
+            match oldest {
+                Some(path) => {
+                    println!("Evicting from cache: {:?}", path);
+                    self.remove(&path);
+                    self.stats.evictions += 1;
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// The cache shared by all connections.
+pub fn global() -> MutexGuard<'static, FileCache> {
+    static CACHE: OnceLock<Mutex<FileCache>> = OnceLock::new();
+    CACHE
+        .get_or_init(|| Mutex::new(FileCache::default()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// Contents and validators of `file`, opened from `path`: from the cache if it
+/// holds the current version, otherwise read and added to it. `None` if the cache
+/// is disabled or the file is too large for it. The cache is only locked around
+/// lookups and updates, never while the file is read.
+pub fn load(
+    path: &Path,
+    file: &mut File,
+    etag_mode: ETagMode,
+    limits: &CacheLimits,
+) -> io::Result<Option<CachedFile>> {
+    let metadata = file.metadata()?;
+    if limits.max_size == 0 || metadata.len() > limits.max_file_size as u64 {
+        return Ok(None);
+    }
+
+    let version = Version::of(&metadata);
+    if let Some(cached) = global().get(path, &version, etag_mode) {
+        println!("Cache hit: {:?}", path);
+        return Ok(Some(cached));
+    }
+
+    println!("Cache miss: {:?}", path);
+    let mut contents = Vec::with_capacity(metadata.len() as usize);
+    file.read_to_end(&mut contents)?;
+    let validators = Validators::new(&contents, &metadata, etag_mode);
+    let cached = CachedFile {
+        contents: Arc::new(contents),
+        validators,
+        version,
+    };
+    global().insert(path, &cached, etag_mode, limits);
+    Ok(Some(cached))
+}
+
+/// `cached` compressed with `encoding`, compressing it on first use.
+pub fn compressed(
+    path: &Path,
+    cached: &CachedFile,
+    encoding: Encoding,
+    levels: &CompressionLevels,
+    limits: &CacheLimits,
+) -> io::Result<Arc<Vec<u8>>> {
+    if let Some(body) = global().variant(path, &cached.version, encoding, levels) {
+        return Ok(body);
+    }
+
+    let mut body = Vec::new();
+    let mut encoder = encoding.encoder(&mut body, levels)?;
+    encoder.write_all(&cached.contents)?;
+    encoder.finish()?;
+
+    let body = Arc::new(body);
+    let variant = Variant {
+        encoding,
+        levels: levels.clone(),
+        body: body.clone(),
+    };
+    global().insert_variant(path, &cached.version, variant, limits);
+    Ok(body)
+}
+
+/// Forgets `path` and anything below it after it changed, so the change shows
+/// even if it left size and modification time as they were.
+pub fn invalidate(path: &Path) {
+    global().invalidate(path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn cached(contents: &[u8], seconds: u64) -> CachedFile {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(seconds);
+        CachedFile {
+            contents: Arc::new(contents.to_vec()),
+            validators: Validators {
+                etag: Some(format!("\"{}\"", seconds)),
+                last_modified: Some(modified),
+            },
+            version: Version {
+                modified: Some(modified),
+                length: contents.len() as u64,
+                inode: 1,
+            },
+        }
+    }
+
+    fn limits(max_size: usize) -> CacheLimits {
+        CacheLimits {
+            max_size,
+            max_file_size: max_size,
+        }
+    }
+
+    #[test]
+    fn file_cache_evicts_least_recently_used() {
+        let mut cache = FileCache::default();
+        let limits = limits(10);
+        let (a, b, c) = (Path::new("a"), Path::new("b"), Path::new("c"));
+        let (file_a, file_b, file_c) = (cached(b"aaaa", 1), cached(b"bbbb", 1), cached(b"cccc", 1));
+
+        cache.insert(a, &file_a, ETagMode::Metadata, &limits);
+        cache.insert(b, &file_b, ETagMode::Metadata, &limits);
+        assert!(cache.get(a, &file_a.version, ETagMode::Metadata).is_some());
+        cache.insert(c, &file_c, ETagMode::Metadata, &limits);
+
+        assert!(cache.get(b, &file_b.version, ETagMode::Metadata).is_none());
+        assert!(cache.get(a, &file_a.version, ETagMode::Metadata).is_some());
+        assert!(cache.get(c, &file_c.version, ETagMode::Metadata).is_some());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (3, 1, 1));
+        assert_eq!((stats.entries, stats.bytes), (2, 8));
+
+        // Larger than the whole cache: never stored
+        cache.insert(b, &cached(&[0; 11], 1), ETagMode::Metadata, &limits);
+        assert_eq!(cache.stats().entries, 2);
+    }
+
+    #[test]
+    fn file_cache_drops_stale_versions() {
+        let mut cache = FileCache::default();
+        let limits = limits(100);
+        let path = Path::new("file");
+        let old = cached(b"old", 1);
+        cache.insert(path, &old, ETagMode::Metadata, &limits);
+
+        let new = cached(b"new", 2);
+        assert!(cache.get(path, &new.version, ETagMode::Metadata).is_none());
+        assert_eq!(cache.stats().invalidations, 1);
+        assert_eq!(cache.stats().entries, 0);
+
+        cache.insert(path, &new, ETagMode::Metadata, &limits);
+        assert!(cache.get(path, &new.version, ETagMode::Weak).is_none());
+        cache.insert(path, &new, ETagMode::Metadata, &limits);
+        cache.invalidate(path);
+        assert_eq!(cache.stats().invalidations, 3);
+        assert_eq!(cache.stats().bytes, 0);
+
+        // A directory takes everything below it along, but not its namesakes
+        for name in ["dir/a", "dir/sub/b", "directory"] {
+            cache.insert(Path::new(name), &new, ETagMode::Metadata, &limits);
+        }
+        cache.invalidate(Path::new("dir"));
+        assert_eq!(cache.stats().invalidations, 5);
+        assert_eq!(cache.stats().entries, 1);
+    }
+
+    #[test]
+    fn file_cache_keeps_variants_with_their_entry() {
+        let mut cache = FileCache::default();
+        let limits = limits(100);
+        let path = Path::new("file");
+        let levels = CompressionLevels::default();
+        let file = cached(&[b'x'; 40], 1);
+        cache.insert(path, &file, ETagMode::Metadata, &limits);
+
+        let variant = |body: &[u8]| Variant {
+            encoding: Encoding::Gzip,
+            levels: levels.clone(),
+            body: Arc::new(body.to_vec()),
+        };
+        cache.insert_variant(path, &file.version, variant(&[1; 20]), &limits);
+        cache.insert_variant(path, &file.version, variant(&[2; 10]), &limits);
+        let body = cache
+            .variant(path, &file.version, Encoding::Gzip, &levels)
+            .unwrap();
+        assert_eq!(body[..], [2; 10]);
+        assert_eq!(cache.stats().bytes, 50);
+
+        let mut other_levels = levels.clone();
+        other_levels.gzip = 1;
+        assert!(cache
+            .variant(path, &file.version, Encoding::Gzip, &other_levels)
+            .is_none());
+
+        // A variant of a version no longer cached is dropped
+        let stale = cached(&[b'y'; 40], 2);
+        cache.insert_variant(path, &stale.version, variant(&[3; 10]), &limits);
+        assert_eq!(cache.stats().bytes, 50);
+        assert!(cache
+            .variant(path, &stale.version, Encoding::Gzip, &levels)
+            .is_none());
+    }
+}
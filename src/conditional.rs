@@ -0,0 +1,401 @@
+use std::fs::{File, Metadata};
+use std::io::{self, Seek, SeekFrom};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sha1::{Digest, Sha1};
+
+use crate::config::ETagMode;
+use crate::utils;
+
+/// Validators of the current representation of a file.
+#[derive(Debug, Clone)]
+pub struct Validators {
+    pub etag: Option<String>,
+    /// Truncated to whole seconds, the precision of HTTP dates.
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// Validators of `contents`, read from a file with `metadata`.
+    pub fn new(contents: &[u8], metadata: &Metadata, mode: ETagMode) -> Self {
+        let digest = (mode == ETagMode::Content).then(|| Sha1::digest(contents));
+        Self::from_parts(
+            digest.as_deref(),
+            metadata.len(),
+            metadata.modified().ok(),
+            utils::inode(metadata),
+            mode,
+        )
+    }
+
+    /// Like `new`, but only reads the file when `mode` hashes its contents, and
+    /// then without needing it in memory. The file is left positioned at its start.
+    pub fn from_file(file: &mut File, mode: ETagMode) -> io::Result<Self> {
+        let metadata = file.metadata()?;
+        let digest = match mode {
+            ETagMode::Content => {
+                let mut hasher = Sha1::new();
+                io::copy(file, &mut hasher)?;
+                file.seek(SeekFrom::Start(0))?;
+                Some(hasher.finalize())
+            }
+            _ => None,
+        };
+        Ok(Self::from_parts(
+            digest.as_deref(),
+            metadata.len(),
+            metadata.modified().ok(),
+            utils::inode(&metadata),
+            mode,
+        ))
+    }
+
+    fn from_parts(
+        digest: Option<&[u8]>,
+        length: u64,
+        modified: Option<SystemTime>,
+        inode: u64,
+        mode: ETagMode,
+    ) -> Self {
+        let modified = modified.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+        let etag = match (mode, digest) {
+            // Every write through the server renames a new file into place, so the
+            // inode tells apart even writes within the clock's resolution
+            (ETagMode::Metadata, _) => modified.map(|modified| {
+                format!("\"{:x}-{:x}-{:x}\"", inode, length, modified.as_nanos())
+            }),
+            (ETagMode::Content, Some(digest)) => {
+                let hex: String = digest[..16].iter().map(|b| format!("{:02x}", b)).collect();
+                Some(format!("\"{}\"", hex))
+            }
+            // Two writes within the clock's resolution that keep the size would
+            // look the same, hence weak
+            (ETagMode::Weak, _) => {
+                modified.map(|modified| format!("W/\"{:x}-{:x}\"", length, modified.as_nanos()))
+            }
+            _ => None,
+        };
+
+        Self {
+            etag,
+            last_modified: modified
+                .map(|modified| UNIX_EPOCH + Duration::from_secs(modified.as_secs())),
+        }
+    }
+
+    /// The validators of an encoded form of the same contents, whose bytes a
+    /// strong entity tag would no longer vouch for.
+    pub fn weakened(mut self) -> Self {
+        self.etag = self.etag.map(|etag| match etag.starts_with("W/") {
+            true => etag,
+            false => format!("W/{}", etag),
+        });
+        self
+    }
+
+    /// Response headers announcing these validators.
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = Vec::new();
+        if let Some(etag) = &self.etag {
+            headers.push(("ETag", etag.clone()));
+        }
+        if let Some(last_modified) = self.last_modified {
+            headers.push(("Last-Modified", utils::http_date(last_modified)));
+        }
+        headers
+    }
+}
+
+/// Outcome of evaluating a request's preconditions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precondition {
+    Proceed,
+    /// `304 Not Modified`: the client's cached copy is current.
+    NotModified,
+    /// `412 Precondition Failed`.
+    Failed,
+}
+
+fn opaque_tag(etag: &str) -> &str {
+    etag.trim().trim_start_matches("W/")
+}
+
+fn is_weak(etag: &str) -> bool {
+    etag.trim().starts_with("W/")
+}
+
+/// Whether a comma separated entity-tag list (or `*`) matches `current`. Strong
+/// comparison requires both tags to be strong; weak comparison ignores `W/`.
+fn etag_list_matches(list: &str, current: Option<&str>, strong: bool) -> bool {
+    let current = match current {
+        Some(current) => current,
+        None => return false,
+    };
+    if list.trim() == "*" {
+        return true;
+    }
+    list.split(',').any(|tag| {
+        if strong && (is_weak(tag) || is_weak(current)) {
+            return false;
+        }
+        opaque_tag(tag) == opaque_tag(current)
+    })
+}
+
+/// Evaluates `If-Match`, `If-Unmodified-Since`, `If-None-Match` and
+/// `If-Modified-Since` in the order given by RFC 9110 section 13.2.2. `current` is
+/// `None` when the target does not exist yet.
+pub fn evaluate(method: &str, headers: &[String], current: Option<&Validators>) -> Precondition {
+    let etag = current.and_then(|current| current.etag.as_deref());
+    let last_modified = current.and_then(|current| current.last_modified);
+    let is_read = matches!(method.to_uppercase().as_str(), "GET" | "HEAD");
+
+    if let Some(if_match) = utils::get_header(headers, "If-Match") {
+        // `*` only asks for the target to exist, whether or not it has an ETag
+        let matches = if if_match.trim() == "*" {
+            current.is_some()
+        } else {
+            etag_list_matches(if_match, etag, true)
+        };
+        if !matches {
+            return Precondition::Failed;
+        }
+    } else if let Some(since) = utils::get_header(headers, "If-Unmodified-Since") {
+        if let (Some(since), Some(last_modified)) = (utils::parse_http_date(since), last_modified) {
+            if last_modified > since {
+                return Precondition::Failed;
+            }
+        }
+    }
+
+    if let Some(if_none_match) = utils::get_header(headers, "If-None-Match") {
+        let matches = if if_none_match.trim() == "*" {
+            current.is_some()
+        } else {
+            etag_list_matches(if_none_match, etag, false)
+        };
+        if matches {
+            return if is_read {
+                Precondition::NotModified
+            } else {
+                Precondition::Failed
+            };
+        }
+    } else if is_read {
+        if let Some(since) = utils::get_header(headers, "If-Modified-Since") {
+            if let (Some(since), Some(last_modified)) =
+                (utils::parse_http_date(since), last_modified)
+            {
+                if last_modified <= since {
+                    return Precondition::NotModified;
+                }
+            }
+        }
+    }
+
+    Precondition::Proceed
+}
+
+/// Whether a `Range` request may be honored: `If-Range` makes it conditional on
+/// the file still having the validator the client saw. Only strong entity tags and
+/// an exactly matching date count.
+pub fn if_range_matches(headers: &[String], current: &Validators) -> bool {
+    let validator = match utils::get_header(headers, "If-Range") {
+        Some(validator) => validator,
+        None => return true,
+    };
+    if validator.starts_with('"') || validator.starts_with("W/") {
+        return etag_list_matches(validator, current.etag.as_deref(), true);
+    }
+    match (utils::parse_http_date(validator), current.last_modified) {
+        (Some(date), Some(last_modified)) => date == last_modified,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn validators(etag: &str) -> Validators {
+        Validators {
+            etag: Some(etag.to_string()),
+            last_modified: Some(UNIX_EPOCH + Duration::from_secs(784_111_777)),
+        }
+    }
+
+    fn check(method: &str, headers: &[&str], current: Option<&Validators>) -> Precondition {
+        let headers: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+        evaluate(method, &headers, current)
+    }
+
+    #[test]
+    fn etags_from_contents_and_metadata() {
+        let modified = Some(UNIX_EPOCH + Duration::from_millis(1500));
+        let etag = |digest: Option<&[u8]>, inode: u64, mode: ETagMode| {
+            Validators::from_parts(digest, 3, modified, inode, mode).etag
+        };
+
+        let metadata = Validators::from_parts(None, 3, modified, 0x2a, ETagMode::Metadata);
+        assert_eq!(metadata.etag.as_deref(), Some("\"2a-3-59682f00\""));
+        assert_eq!(
+            metadata.last_modified,
+            Some(UNIX_EPOCH + Duration::from_secs(1))
+        );
+        assert_ne!(etag(None, 0x2b, ETagMode::Metadata), metadata.etag);
+
+        let digest = Sha1::digest(b"abc");
+        assert_eq!(
+            etag(Some(&digest), 0x2a, ETagMode::Content).as_deref(),
+            Some("\"a9993e364706816aba3e25717850c26c\"")
+        );
+        assert_eq!(
+            etag(Some(&digest), 0x2b, ETagMode::Content),
+            etag(Some(&digest), 0x2a, ETagMode::Content)
+        );
+
+        let weak = etag(None, 0x2a, ETagMode::Weak);
+        assert_eq!(weak.as_deref(), Some("W/\"3-59682f00\""));
+
+        let none = Validators::from_parts(None, 3, modified, 0x2a, ETagMode::Off);
+        assert_eq!(none.etag, None);
+        assert_eq!(none.headers().len(), 1);
+    }
+
+    #[test]
+    fn only_content_etags_read_the_file() {
+        let path = utils::temp_dir("etag").join("large.bin");
+        fs::write(&path, b"contents").unwrap();
+        let mut file = File::open(&path).unwrap();
+
+        file.seek(SeekFrom::End(0)).unwrap();
+        let metadata = Validators::from_file(&mut file, ETagMode::Metadata).unwrap();
+        assert!(metadata.etag.unwrap().starts_with('"'));
+        assert_eq!(file.stream_position().unwrap(), 8);
+
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let content = Validators::from_file(&mut file, ETagMode::Content).unwrap();
+        assert_eq!(
+            content.etag.as_deref(),
+            Some("\"4a756ca07e9487f482465a99e8286abc\"")
+        );
+        assert_eq!(file.stream_position().unwrap(), 0);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn if_none_match_uses_weak_comparison() {
+        let current = validators("\"v1\"");
+        assert_eq!(
+            check("GET", &["If-None-Match: W/\"v1\""], Some(&current)),
+            Precondition::NotModified
+        );
+        assert_eq!(
+            check("GET", &["If-None-Match: \"v0\", \"v1\""], Some(&current)),
+            Precondition::NotModified
+        );
+        assert_eq!(
+            check("GET", &["If-None-Match: \"v2\""], Some(&current)),
+            Precondition::Proceed
+        );
+        assert_eq!(
+            check("POST", &["If-None-Match: *"], Some(&current)),
+            Precondition::Failed
+        );
+        assert_eq!(
+            check("POST", &["If-None-Match: *"], None),
+            Precondition::Proceed
+        );
+    }
+
+    #[test]
+    fn if_match_uses_strong_comparison() {
+        assert_eq!(
+            check("GET", &["If-Match: \"v1\""], Some(&validators("\"v1\""))),
+            Precondition::Proceed
+        );
+        assert_eq!(
+            check(
+                "GET",
+                &["If-Match: W/\"v1\""],
+                Some(&validators("W/\"v1\""))
+            ),
+            Precondition::Failed
+        );
+        assert_eq!(check("PUT", &["If-Match: *"], None), Precondition::Failed);
+    }
+
+    #[test]
+    fn dates_are_compared_at_second_precision() {
+        let current = validators("\"v1\"");
+        let modified = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let earlier = "Sun, 06 Nov 1994 08:49:36 GMT";
+
+        let since = format!("If-Modified-Since: {}", modified);
+        assert_eq!(
+            check("GET", &[&since], Some(&current)),
+            Precondition::NotModified
+        );
+        let since = format!("If-Modified-Since: {}", earlier);
+        assert_eq!(
+            check("GET", &[&since], Some(&current)),
+            Precondition::Proceed
+        );
+        assert_eq!(
+            check("GET", &["If-Modified-Since: garbage"], Some(&current)),
+            Precondition::Proceed
+        );
+
+        let since = format!("If-Unmodified-Since: {}", earlier);
+        assert_eq!(
+            check("GET", &[&since], Some(&current)),
+            Precondition::Failed
+        );
+    }
+
+    #[test]
+    fn precedence_follows_rfc_9110() {
+        let current = validators("\"v1\"");
+        let stale = "If-Modified-Since: Thu, 01 Jan 1970 00:00:00 GMT";
+        let fresh = "If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT";
+
+        // If-None-Match wins over If-Modified-Since
+        assert_eq!(
+            check("GET", &["If-None-Match: \"v0\"", fresh], Some(&current)),
+            Precondition::Proceed
+        );
+        assert_eq!(
+            check("GET", &["If-None-Match: \"v1\"", stale], Some(&current)),
+            Precondition::NotModified
+        );
+        // If-Match wins over If-Unmodified-Since
+        let unmodified = "If-Unmodified-Since: Thu, 01 Jan 1970 00:00:00 GMT";
+        assert_eq!(
+            check("GET", &["If-Match: \"v1\"", unmodified], Some(&current)),
+            Precondition::Proceed
+        );
+        // A failed If-Match is final
+        assert_eq!(
+            check(
+                "GET",
+                &["If-Match: \"v0\"", "If-None-Match: \"v1\""],
+                Some(&current)
+            ),
+            Precondition::Failed
+        );
+    }
+
+    #[test]
+    fn if_range_needs_an_exact_strong_validator() {
+        let current = validators("\"v1\"");
+        let if_range = |value: &str| if_range_matches(&[format!("If-Range: {}", value)], &current);
+        assert!(if_range_matches(&[], &current));
+        assert!(if_range("\"v1\""));
+        assert!(!if_range("W/\"v1\""));
+        assert!(!if_range("\"v0\""));
+        assert!(if_range("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert!(!if_range("Sun, 06 Nov 1994 08:49:38 GMT"));
+    }
+}
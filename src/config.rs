@@ -0,0 +1,598 @@
+use std::collections::HashMap;
+use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::ValueEnum;
+
+use crate::mime;
+
+// Settings are passed from the command line to the request handlers through
+// `APP_*` environment variables (see `main.rs`), the same way as `APP_DIRECTORY`.
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Parses `name` if it is set. Unlike with `env_or`, a value that does not parse
+/// is an error, for settings where a silent fallback to the default is unsafe.
+fn env_strict<T: FromStr<Err = String>>(name: &str) -> Result<Option<T>, String> {
+    match env::var(name) {
+        Ok(value) => value.parse().map(Some).map_err(|e| format!("{}: {}", name, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Checks the settings that must not fall back to their defaults when given an
+/// invalid value, so that the server can refuse to start instead.
+pub fn check_env() -> Result<(), String> {
+    env_strict::<SymlinkPolicy>("APP_FOLLOW_SYMLINKS")?;
+    env_strict::<ETagMode>("APP_ETAG")?;
+    Ok(())
+}
+
+/// Size limits applied while reading a request.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Longest request line, answered with `414 URI Too Long`.
+    pub max_request_line: usize,
+    /// Most header lines, answered with `431 Request Header Fields Too Large`.
+    pub max_headers: usize,
+    /// Most bytes across all header lines, also answered with `431`.
+    pub max_header_bytes: usize,
+    /// Largest body, answered with `413 Payload Too Large`.
+    pub max_body_size: usize,
+    /// Whether bodies sent with a `Content-Encoding` are decoded before they reach
+    /// the handlers. Otherwise they are passed on as received.
+    pub decode_bodies: bool,
+    /// Largest body after decoding, also answered with `413`.
+    pub max_decoded_body_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_request_line: 8 * 1024,
+            max_headers: 100,
+            max_header_bytes: 64 * 1024,
+            max_body_size: 100 * 1024 * 1024,
+            decode_bodies: true,
+            max_decoded_body_size: 100 * 1024 * 1024,
+        }
+    }
+}
+
+impl Limits {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            max_request_line: env_or("APP_MAX_REQUEST_LINE", default.max_request_line),
+            max_headers: env_or("APP_MAX_HEADERS", default.max_headers),
+            max_header_bytes: env_or("APP_MAX_HEADER_BYTES", default.max_header_bytes),
+            max_body_size: env_or("APP_MAX_BODY_SIZE", default.max_body_size),
+            decode_bodies: env_or("APP_DECODE_BODIES", default.decode_bodies),
+            max_decoded_body_size: env_or(
+                "APP_MAX_DECODED_BODY_SIZE",
+                default.max_decoded_body_size,
+            ),
+        }
+    }
+}
+
+/// Connection timeouts, guarding against clients that trickle in requests (slowloris)
+/// or never read their responses.
+#[derive(Debug, Clone)]
+pub struct Timeouts {
+    /// Time allowed to receive a complete request line and headers.
+    pub header: Duration,
+    /// Base time allowed to receive a request body, on top of `min_body_rate`.
+    pub body: Duration,
+    /// Slowest body upload we tolerate, in bytes per second.
+    pub min_body_rate: u64,
+    /// Base time allowed to send a response, on top of `min_write_rate`. Streams
+    /// that stay open, like WebSockets, only have each single write bounded by it.
+    pub write: Duration,
+    /// Slowest a client may read a response, in bytes per second.
+    pub min_write_rate: u64,
+    /// How long a kept-alive connection may sit idle between requests.
+    pub keep_alive: Duration,
+    /// How long an upgraded WebSocket may go without a frame from the client.
+    pub websocket_idle: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            header: Duration::from_secs(10),
+            body: Duration::from_secs(30),
+            min_body_rate: 16 * 1024,
+            write: Duration::from_secs(30),
+            min_write_rate: 16 * 1024,
+            keep_alive: Duration::from_secs(5),
+            websocket_idle: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let seconds = |name: &str, default: Duration| {
+            Duration::try_from_secs_f64(env_or(name, default.as_secs_f64())).unwrap_or(default)
+        };
+        Self {
+            header: seconds("APP_HEADER_TIMEOUT", default.header),
+            body: seconds("APP_BODY_TIMEOUT", default.body),
+            min_body_rate: env_or("APP_MIN_BODY_RATE", default.min_body_rate),
+            write: seconds("APP_WRITE_TIMEOUT", default.write),
+            min_write_rate: env_or("APP_MIN_WRITE_RATE", default.min_write_rate),
+            keep_alive: seconds("APP_KEEP_ALIVE_TIMEOUT", default.keep_alive),
+            websocket_idle: seconds("APP_WEBSOCKET_IDLE_TIMEOUT", default.websocket_idle),
+        }
+    }
+
+    /// Total time allowed to receive a body of `length` bytes.
+    pub fn body_timeout(&self, length: usize) -> Duration {
+        let transfer = length as f64 / self.min_body_rate.max(1) as f64;
+        self.body + Duration::from_secs_f64(transfer)
+    }
+
+    /// Time allowed to send the first `length` bytes of a response.
+    pub fn write_timeout(&self, length: u64) -> Duration {
+        let transfer = length as f64 / self.min_write_rate.max(1) as f64;
+        self.write + Duration::from_secs_f64(transfer)
+    }
+}
+
+/// Compression level used for each response encoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressionLevels {
+    /// 0-9
+    pub gzip: u32,
+    /// 0-9
+    #[cfg(feature = "deflate")]
+    pub deflate: u32,
+    /// 0-11
+    #[cfg(feature = "brotli")]
+    pub brotli: u32,
+    /// 1-22
+    #[cfg(feature = "zstd")]
+    pub zstd: i32,
+}
+
+impl Default for CompressionLevels {
+    fn default() -> Self {
+        Self {
+            gzip: 6,
+            #[cfg(feature = "deflate")]
+            deflate: 6,
+            // The higher brotli levels are far too slow for on-the-fly compression
+            #[cfg(feature = "brotli")]
+            brotli: 4,
+            #[cfg(feature = "zstd")]
+            zstd: 3,
+        }
+    }
+}
+
+impl CompressionLevels {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            gzip: env_or("APP_GZIP_LEVEL", default.gzip).min(9),
+            #[cfg(feature = "deflate")]
+            deflate: env_or("APP_DEFLATE_LEVEL", default.deflate).min(9),
+            #[cfg(feature = "brotli")]
+            brotli: env_or("APP_BROTLI_LEVEL", default.brotli).min(11),
+            #[cfg(feature = "zstd")]
+            zstd: env_or("APP_ZSTD_LEVEL", default.zstd).clamp(1, 22),
+        }
+    }
+}
+
+/// Decides which responses are worth compressing.
+#[derive(Debug, Clone)]
+pub struct CompressionPolicy {
+    /// Bodies smaller than this are sent as is. The default only skips empty bodies.
+    pub min_size: usize,
+    /// MIME types to compress (`text/*` style wildcards allowed). Empty means any
+    /// type not in `deny`.
+    pub allow: Vec<String>,
+    /// MIME types that are never compressed, typically because they already are.
+    pub deny: Vec<String>,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        let deny = [
+            "image/*",
+            "video/*",
+            "audio/*",
+            "font/woff",
+            "font/woff2",
+            "application/zip",
+            "application/gzip",
+            "application/x-gzip",
+            "application/x-bzip2",
+            "application/x-xz",
+            "application/x-7z-compressed",
+            "application/vnd.rar",
+            "application/zstd",
+        ];
+        Self {
+            min_size: 1,
+            allow: Vec::new(),
+            deny: deny.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+}
+
+fn env_list(name: &str, default: Vec<String>) -> Vec<String> {
+    match env::var(name) {
+        Ok(value) => value
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect(),
+        Err(_) => default,
+    }
+}
+
+fn mime_matches(pattern: &str, mime: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(kind) => mime
+            .split_once('/')
+            .is_some_and(|(mime_kind, _)| mime_kind.eq_ignore_ascii_case(kind)),
+        None => pattern == "*" || pattern.eq_ignore_ascii_case(mime),
+    }
+}
+
+impl CompressionPolicy {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            min_size: env_or("APP_COMPRESS_MIN_SIZE", default.min_size),
+            allow: env_list("APP_COMPRESS_TYPES", default.allow),
+            deny: env_list("APP_NO_COMPRESS_TYPES", default.deny),
+        }
+    }
+
+    /// Whether bodies of `content_type` (parameters such as `charset` are ignored)
+    /// may be compressed.
+    pub fn allows_type(&self, content_type: Option<&str>) -> bool {
+        let mime = content_type
+            .and_then(|content_type| content_type.split(';').next())
+            .unwrap_or("application/octet-stream")
+            .trim();
+
+        let allowed =
+            self.allow.is_empty() || self.allow.iter().any(|pattern| mime_matches(pattern, mime));
+        allowed && !self.deny.iter().any(|pattern| mime_matches(pattern, mime))
+    }
+}
+
+/// Maps file extensions to the `Content-Type` they are served with.
+#[derive(Debug, Clone)]
+pub struct MimeTypes {
+    /// Lowercase extension (without the dot) to MIME type.
+    extensions: HashMap<String, String>,
+    /// Guess the type from the file contents when the extension is unknown.
+    pub sniff: bool,
+}
+
+impl Default for MimeTypes {
+    fn default() -> Self {
+        let mut types = Self {
+            extensions: HashMap::new(),
+            sniff: false,
+        };
+        for (extension, mime) in mime::BUILTIN_TYPES {
+            types.insert(extension, mime);
+        }
+        types
+    }
+}
+
+impl MimeTypes {
+    /// `APP_MIME_TYPES` holds extra `extension=type` pairs, separated by commas,
+    /// that extend or override the built-in table.
+    pub fn from_env() -> Self {
+        let mut types = Self::default();
+        for entry in env_list("APP_MIME_TYPES", Vec::new()) {
+            match entry.split_once('=') {
+                Some((extension, mime)) => types.insert(extension, mime),
+                None => println!("Ignoring malformed MIME type mapping: {:?}", entry),
+            }
+        }
+        types.sniff = env_or("APP_SNIFF_MIME_TYPES", types.sniff);
+        types
+    }
+
+    pub fn insert(&mut self, extension: &str, mime: &str) {
+        let extension = extension
+            .trim()
+            .trim_start_matches('.')
+            .to_ascii_lowercase();
+        self.extensions.insert(extension, mime.trim().to_string());
+    }
+
+    pub fn get(&self, extension: &str) -> Option<&str> {
+        self.extensions
+            .get(&extension.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+}
+
+/// Directory listings for `/files/` paths that name a directory.
+#[derive(Debug, Clone, Default)]
+pub struct Autoindex {
+    /// Without listings, directories are answered with `404 Not Found`.
+    pub enabled: bool,
+    /// List dotfiles too.
+    pub show_hidden: bool,
+}
+
+impl Autoindex {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            enabled: env_or("APP_AUTOINDEX", default.enabled),
+            show_hidden: env_or("APP_AUTOINDEX_HIDDEN", default.show_hidden),
+        }
+    }
+}
+
+/// How `/files/` requests map onto a static site in the served directory.
+#[derive(Debug, Clone)]
+pub struct StaticSite {
+    /// Files served for a directory request, tried in order.
+    pub index_files: Vec<String>,
+    /// Single-page-app entry point, relative to the served directory. Missing
+    /// paths under its directory are answered with it instead of a 404.
+    pub spa_fallback: Option<String>,
+}
+
+impl Default for StaticSite {
+    fn default() -> Self {
+        Self {
+            index_files: vec!["index.html".to_string()],
+            spa_fallback: None,
+        }
+    }
+}
+
+impl StaticSite {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            index_files: env_list("APP_INDEX_FILES", default.index_files),
+            spa_fallback: env::var("APP_SPA_FALLBACK").ok().or(default.spa_fallback),
+        }
+    }
+}
+
+/// How uploads under `/files/` are stored.
+#[derive(Debug, Clone, Default)]
+pub struct Uploads {
+    /// Also sync the directory after renaming a written file into place, so the
+    /// new file survives a crash and not just its data.
+    pub sync_directory: bool,
+    /// Create missing directories on the way to an uploaded file. Without this,
+    /// such uploads get `409 Conflict`.
+    pub create_parents: bool,
+}
+
+impl Uploads {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            sync_directory: env_or("APP_FSYNC_DIRECTORY", default.sync_directory),
+            create_parents: env_or("APP_CREATE_PARENTS", default.create_parents),
+        }
+    }
+}
+
+/// Limits on `multipart/form-data` uploads, answered with `413 Payload Too Large`.
+#[derive(Debug, Clone)]
+pub struct FormLimits {
+    /// Largest single field or file.
+    pub max_part_size: usize,
+    /// Most bytes across all parts, their headers included.
+    pub max_form_size: usize,
+    /// Most parts, counting every field and file. Each file part stays open until
+    /// the whole form has been read.
+    pub max_parts: usize,
+}
+
+impl Default for FormLimits {
+    fn default() -> Self {
+        Self {
+            max_part_size: 32 * 1024 * 1024,
+            max_form_size: 100 * 1024 * 1024,
+            max_parts: 100,
+        }
+    }
+}
+
+impl FormLimits {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            max_part_size: env_or("APP_MAX_PART_SIZE", default.max_part_size),
+            max_form_size: env_or("APP_MAX_FORM_SIZE", default.max_form_size),
+            max_parts: env_or("APP_MAX_FORM_PARTS", default.max_parts),
+        }
+    }
+}
+
+/// Bounds on the in-memory cache of static files.
+#[derive(Debug, Clone, Default)]
+pub struct CacheLimits {
+    /// Most bytes of contents and compressed variants held. 0 disables the cache.
+    pub max_size: usize,
+    /// Larger files are always read from disk.
+    pub max_file_size: usize,
+}
+
+impl CacheLimits {
+    pub fn from_env() -> Self {
+        let max_size = env_or("APP_CACHE_SIZE", 0);
+        Self {
+            max_size,
+            max_file_size: env_or("APP_CACHE_MAX_FILE_SIZE", max_size.min(1024 * 1024)),
+        }
+    }
+}
+
+/// Watching the served directory for changes.
+#[derive(Debug, Clone, Default)]
+pub struct Watch {
+    /// Drop cached files as soon as they change on disk.
+    pub enabled: bool,
+    /// Serve `/events/reload`, which tells browsers to reload when files change.
+    /// Implies `enabled`.
+    pub live_reload: bool,
+}
+
+impl Watch {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let live_reload = env_or("APP_LIVE_RELOAD", default.live_reload);
+        Self {
+            enabled: live_reload || env_or("APP_WATCH", default.enabled),
+            live_reload,
+        }
+    }
+}
+
+/// How entity tags for served files are generated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ETagMode {
+    /// Derived from inode, size and modification time, so the file is never read
+    /// for it. Strong, hence usable for `If-Range`.
+    #[default]
+    Metadata,
+    /// A hash of the contents, which reads the whole file on every request.
+    #[value(alias = "strong")]
+    Content,
+    /// Derived from size and modification time.
+    Weak,
+    #[value(alias = "none")]
+    Off,
+}
+
+impl FromStr for ETagMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "metadata" => Ok(ETagMode::Metadata),
+            "content" | "strong" => Ok(ETagMode::Content),
+            "weak" => Ok(ETagMode::Weak),
+            "off" | "none" => Ok(ETagMode::Off),
+            _ => Err(format!("unknown ETag mode: {}", value)),
+        }
+    }
+}
+
+impl ETagMode {
+    pub fn from_env() -> Self {
+        env_or("APP_ETAG", Self::default())
+    }
+}
+
+/// Which symbolic links under the served directory are followed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum SymlinkPolicy {
+    /// Paths through a symbolic link are refused, wherever it points.
+    Never,
+    /// Links are followed as long as they resolve to somewhere inside the directory.
+    #[default]
+    WithinRoot,
+    /// Links are followed wherever they point. Paths still cannot climb out with `..`.
+    Always,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "never" => Ok(SymlinkPolicy::Never),
+            "within-root" => Ok(SymlinkPolicy::WithinRoot),
+            "always" => Ok(SymlinkPolicy::Always),
+            _ => Err(format!("unknown symlink policy: {}", value)),
+        }
+    }
+}
+
+/// Which paths under the served directory may be accessed at all. Others are
+/// answered with `403 Forbidden`.
+#[derive(Debug, Clone, Default)]
+pub struct PathPolicy {
+    pub symlinks: SymlinkPolicy,
+    /// Allow dotfiles and paths through dot-directories, like `.env` or
+    /// `.git/config`. `.well-known` is always allowed.
+    pub allow_hidden: bool,
+}
+
+impl PathPolicy {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            symlinks: env_or("APP_FOLLOW_SYMLINKS", default.symlinks),
+            allow_hidden: env_or("APP_ALLOW_HIDDEN", default.allow_hidden),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compression_policy_matches_mime_types() {
+        let policy = CompressionPolicy::default();
+        assert!(policy.allows_type(Some("text/html; charset=utf-8")));
+        assert!(policy.allows_type(Some("application/octet-stream")));
+        assert!(policy.allows_type(None));
+        assert!(!policy.allows_type(Some("image/png")));
+        assert!(!policy.allows_type(Some("Application/Zip")));
+
+        let policy = CompressionPolicy {
+            allow: vec!["text/*".to_string(), "application/json".to_string()],
+            ..CompressionPolicy::default()
+        };
+        assert!(policy.allows_type(Some("text/css")));
+        assert!(policy.allows_type(Some("application/json")));
+        assert!(!policy.allows_type(Some("application/octet-stream")));
+    }
+
+    #[test]
+    fn check_env_rejects_unknown_values() {
+        let _env = crate::utils::env_lock();
+        for policy in SymlinkPolicy::value_variants() {
+            let name = policy.to_possible_value().unwrap().get_name().to_string();
+            assert_eq!(name.parse::<SymlinkPolicy>().unwrap(), *policy);
+        }
+        for mode in ETagMode::value_variants() {
+            let name = mode.to_possible_value().unwrap().get_name().to_string();
+            assert_eq!(name.parse::<ETagMode>().unwrap(), *mode);
+        }
+
+        env::set_var("APP_FOLLOW_SYMLINKS", "nevr");
+        assert!(check_env().is_err());
+        env::set_var("APP_FOLLOW_SYMLINKS", "never");
+        assert!(check_env().is_ok());
+        env::remove_var("APP_FOLLOW_SYMLINKS");
+
+        env::set_var("APP_ETAG", "of");
+        assert!(check_env().is_err());
+        env::set_var("APP_ETAG", "none");
+        assert!(check_env().is_ok());
+        env::remove_var("APP_ETAG");
+    }
+}
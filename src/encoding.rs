@@ -0,0 +1,467 @@
+use std::io::{self, Read, Write};
+
+use flate2::read::GzDecoder;
+#[cfg(feature = "deflate")]
+use flate2::read::ZlibDecoder;
+use flate2::write::GzEncoder;
+#[cfg(feature = "deflate")]
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use thiserror::Error;
+
+use crate::config::CompressionLevels;
+use crate::utils;
+
+/// Content codings the server can apply to a response body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    #[cfg(feature = "deflate")]
+    Deflate,
+    #[cfg(feature = "brotli")]
+    Brotli,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            Encoding::Gzip => "gzip",
+            #[cfg(feature = "deflate")]
+            Encoding::Deflate => "deflate",
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => "br",
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    /// Looks up a coding by its `Content-Encoding` token.
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "identity" => Some(Encoding::Identity),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            #[cfg(feature = "deflate")]
+            "deflate" => Some(Encoding::Deflate),
+            #[cfg(feature = "brotli")]
+            "br" => Some(Encoding::Brotli),
+            #[cfg(feature = "zstd")]
+            "zstd" => Some(Encoding::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Extension of precompressed sidecar files (`app.js.gz`, `app.js.br`, ...).
+    pub fn file_extension(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Gzip => Some("gz"),
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => Some("br"),
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => Some("zst"),
+            _ => None,
+        }
+    }
+
+    /// Wraps `writer` so that everything written to it comes out encoded.
+    /// `finish` must be called to flush the encoder's trailer.
+    pub fn encoder<'a, W: Write + 'a>(
+        &self,
+        writer: W,
+        levels: &CompressionLevels,
+    ) -> io::Result<Box<dyn Encoder + 'a>> {
+        let encoder: Box<dyn Encoder + 'a> = match self {
+            Encoding::Identity => Box::new(IdentityEncoder(writer)),
+            Encoding::Gzip => Box::new(GzEncoder::new(writer, Compression::new(levels.gzip))),
+            #[cfg(feature = "deflate")]
+            Encoding::Deflate => {
+                // HTTP's "deflate" is the zlib format, not a raw deflate stream
+                Box::new(ZlibEncoder::new(writer, Compression::new(levels.deflate)))
+            }
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => Box::new(brotli::CompressorWriter::new(
+                ErrorTracking::new(writer),
+                BROTLI_BUFFER_SIZE,
+                levels.brotli,
+                BROTLI_WINDOW_BITS,
+            )),
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => Box::new(zstd::stream::write::Encoder::new(writer, levels.zstd)?),
+        };
+        Ok(encoder)
+    }
+
+    /// Wraps `reader` so that reading from it yields the decoded data.
+    pub fn decoder<'a, R: Read + 'a>(&self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        let decoder: Box<dyn Read + 'a> = match self {
+            Encoding::Identity => Box::new(reader),
+            Encoding::Gzip => Box::new(GzDecoder::new(reader)),
+            #[cfg(feature = "deflate")]
+            Encoding::Deflate => Box::new(ZlibDecoder::new(reader)),
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => Box::new(brotli::Decompressor::new(reader, BROTLI_BUFFER_SIZE)),
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+        };
+        Ok(decoder)
+    }
+}
+
+#[cfg(feature = "brotli")]
+const BROTLI_BUFFER_SIZE: usize = 4096;
+#[cfg(feature = "brotli")]
+const BROTLI_WINDOW_BITS: u32 = 22;
+
+/// A streaming content encoder. Implementations exist for each supported codec.
+pub trait Encoder: Write {
+    /// Writes out buffered data along with the format's trailer.
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+struct IdentityEncoder<W: Write>(W);
+
+impl<W: Write> Write for IdentityEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write> Encoder for IdentityEncoder<W> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write> Encoder for GzEncoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        GzEncoder::finish(*self).map(|_| ())
+    }
+}
+
+#[cfg(feature = "deflate")]
+impl<W: Write> Encoder for ZlibEncoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        ZlibEncoder::finish(*self).map(|_| ())
+    }
+}
+
+/// Remembers the first error from the wrapped writer. The brotli compressor
+/// writes its final meta-block when consumed and drops any error doing so.
+#[cfg(feature = "brotli")]
+struct ErrorTracking<W: Write> {
+    inner: W,
+    error: Option<io::Error>,
+}
+
+#[cfg(feature = "brotli")]
+impl<W: Write> ErrorTracking<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, error: None }
+    }
+
+    fn track<T>(&mut self, result: io::Result<T>) -> io::Result<T> {
+        if let Err(e) = &result {
+            if self.error.is_none() {
+                self.error = Some(io::Error::new(e.kind(), e.to_string()));
+            }
+        }
+        result
+    }
+}
+
+#[cfg(feature = "brotli")]
+impl<W: Write> Write for ErrorTracking<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.inner.write(buf);
+        self.track(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = self.inner.flush();
+        self.track(result)
+    }
+}
+
+#[cfg(feature = "brotli")]
+impl<W: Write> Encoder for brotli::CompressorWriter<ErrorTracking<W>> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        // Consuming the writer emits the final meta-block
+        match self.into_inner().error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<W: Write> Encoder for zstd::stream::write::Encoder<'_, W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        zstd::stream::write::Encoder::finish(*self).map(|_| ())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    #[error("unsupported content coding: {0}")]
+    Unsupported(String),
+    #[error("decoded body exceeds {0} bytes")]
+    TooLarge(usize),
+    #[error("malformed encoded body: {0}")]
+    Malformed(#[from] io::Error),
+}
+
+/// Parses a `Content-Encoding` header into the codings it lists, in the order
+/// they were applied.
+pub fn parse_content_encoding(header: &str) -> Result<Vec<Encoding>, DecodeError> {
+    header
+        .split(',')
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty())
+        .map(|coding| {
+            Encoding::from_name(coding).ok_or_else(|| DecodeError::Unsupported(coding.to_string()))
+        })
+        .collect()
+}
+
+/// Undoes the `Content-Encoding` of a request body. Decoding stops as soon as the
+/// output grows past `limit` bytes, so a small compression bomb cannot exhaust memory.
+pub fn decode(content_encoding: &str, body: Vec<u8>, limit: usize) -> Result<Vec<u8>, DecodeError> {
+    let mut body = body;
+    // Codings are listed in the order they were applied, so undo them backwards
+    for encoding in parse_content_encoding(content_encoding)?.into_iter().rev() {
+        if encoding == Encoding::Identity {
+            continue;
+        }
+        let mut decoded = Vec::new();
+        encoding
+            .decoder(&body[..])?
+            .take(limit as u64 + 1)
+            .read_to_end(&mut decoded)?;
+        if decoded.len() > limit {
+            return Err(DecodeError::TooLarge(limit));
+        }
+        body = decoded;
+    }
+    Ok(body)
+}
+
+/// Encodings we can produce, most preferred first. Ties between equally weighted
+/// codings in `Accept-Encoding` are broken by this order.
+pub const SUPPORTED_ENCODINGS: &[Encoding] = &[
+    #[cfg(feature = "brotli")]
+    Encoding::Brotli,
+    #[cfg(feature = "zstd")]
+    Encoding::Zstd,
+    Encoding::Gzip,
+    #[cfg(feature = "deflate")]
+    Encoding::Deflate,
+    Encoding::Identity,
+];
+
+/// Picks the encoding for a response following RFC 9110 section 12.5.3.
+///
+/// Returns `None` when none of the `supported` encodings is acceptable to the
+/// client (e.g. `identity;q=0` and no supported compression), which should be
+/// answered with `406 Not Acceptable`.
+pub fn negotiate(accept_encoding: Option<&str>, supported: &[Encoding]) -> Option<Encoding> {
+    // Without the header any coding is acceptable, but we keep the body as is
+    let header = match accept_encoding {
+        Some(header) => header,
+        None => return Some(Encoding::Identity),
+    };
+
+    let preferences = utils::parse_qvalues(header);
+    let weight_of = |name: &str| {
+        preferences
+            .iter()
+            .find(|(coding, _)| coding == name)
+            .map(|(_, quality)| *quality)
+    };
+    let wildcard = weight_of("*");
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in supported {
+        let quality = match (weight_of(encoding.name()), wildcard, encoding) {
+            (Some(quality), _, _) => quality,
+            (None, Some(quality), _) => quality,
+            // Identity stays acceptable unless it is explicitly ruled out, but any
+            // coding the client did list is preferred over it
+            (None, None, Encoding::Identity) => 0.001,
+            (None, None, _) => 0.0,
+        };
+        match best {
+            Some((_, best_quality)) if best_quality >= quality => {}
+            _ if quality > 0.0 => best = Some((encoding, quality)),
+            _ => {}
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn decompress(encoding: Encoding, data: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::new();
+        match encoding {
+            Encoding::Identity => decoded.extend_from_slice(data),
+            Encoding::Gzip => {
+                flate2::read::GzDecoder::new(data)
+                    .read_to_end(&mut decoded)
+                    .unwrap();
+            }
+            #[cfg(feature = "deflate")]
+            Encoding::Deflate => {
+                flate2::read::ZlibDecoder::new(data)
+                    .read_to_end(&mut decoded)
+                    .unwrap();
+            }
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => {
+                brotli::Decompressor::new(data, 4096)
+                    .read_to_end(&mut decoded)
+                    .unwrap();
+            }
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => decoded = zstd::decode_all(data).unwrap(),
+        }
+        decoded
+    }
+
+    #[test]
+    fn encoder_round_trips_every_encoding() {
+        let data = "hello hello hello hello hello".repeat(100);
+        for &encoding in SUPPORTED_ENCODINGS {
+            let compressed = encode(encoding, data.as_bytes());
+            assert_eq!(decompress(encoding, &compressed), data.as_bytes());
+        }
+    }
+
+    #[test]
+    fn encoder_reports_write_errors() {
+        struct FailingWriter;
+
+        impl Write for FailingWriter {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+        }
+
+        for &encoding in SUPPORTED_ENCODINGS {
+            let result = encoding
+                .encoder(FailingWriter, &CompressionLevels::default())
+                .and_then(|mut encoder| {
+                    encoder.write_all(b"data")?;
+                    encoder.finish()
+                });
+            assert!(result.is_err(), "{} swallowed an error", encoding.name());
+        }
+    }
+
+    fn encode(encoding: Encoding, data: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        let mut encoder = encoding
+            .encoder(&mut compressed, &CompressionLevels::default())
+            .unwrap();
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap();
+        compressed
+    }
+
+    #[test]
+    fn decode_undoes_every_encoding() {
+        let data = b"uploaded file contents ".repeat(100);
+        for &encoding in SUPPORTED_ENCODINGS {
+            let decoded = decode(encoding.name(), encode(encoding, &data), data.len()).unwrap();
+            assert_eq!(decoded, data);
+        }
+
+        let twice = encode(Encoding::Gzip, &encode(Encoding::Gzip, &data));
+        assert_eq!(decode("gzip, x-gzip", twice, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn decode_rejects_bad_input() {
+        let bomb = encode(Encoding::Gzip, &vec![0; 1024 * 1024]);
+        assert!(matches!(
+            decode("gzip", bomb, 1024),
+            Err(DecodeError::TooLarge(1024))
+        ));
+        assert!(matches!(
+            decode("compress", b"data".to_vec(), 1024),
+            Err(DecodeError::Unsupported(coding)) if coding == "compress"
+        ));
+        assert!(matches!(
+            decode("gzip", b"not gzip".to_vec(), 1024),
+            Err(DecodeError::Malformed(_))
+        ));
+    }
+
+    fn pick(header: &str) -> Option<Encoding> {
+        negotiate(Some(header), SUPPORTED_ENCODINGS)
+    }
+
+    #[test]
+    fn negotiate_without_header_keeps_identity() {
+        assert_eq!(
+            negotiate(None, SUPPORTED_ENCODINGS),
+            Some(Encoding::Identity)
+        );
+    }
+
+    #[test]
+    fn negotiate_matches_codings_loosely() {
+        assert_eq!(pick("gzip"), Some(Encoding::Gzip));
+        assert_eq!(pick("GZIP"), Some(Encoding::Gzip));
+        assert_eq!(pick("compress,gzip"), Some(Encoding::Gzip));
+        assert_eq!(pick(" lzma ; q=0.9 , gzip ; q=0.5 "), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn negotiate_honors_qvalues() {
+        assert_eq!(pick("gzip;q=0"), Some(Encoding::Identity));
+        assert_eq!(pick("gzip;q=0.2, identity;q=0.8"), Some(Encoding::Identity));
+        assert_eq!(pick("gzip;q=0.8, identity;q=0.2"), Some(Encoding::Gzip));
+        assert_eq!(pick("*;q=0, identity"), Some(Encoding::Identity));
+        assert_eq!(pick("gzip;q=abc"), Some(Encoding::Identity));
+    }
+
+    #[test]
+    fn negotiate_rejects_when_nothing_is_acceptable() {
+        assert_eq!(pick("identity;q=0"), None);
+        assert_eq!(pick("*;q=0"), None);
+        assert_eq!(pick("compress, identity;q=0"), None);
+        assert_eq!(pick("gzip;q=0, identity;q=0"), None);
+    }
+
+    #[cfg(all(feature = "brotli", feature = "zstd", feature = "deflate"))]
+    #[test]
+    fn negotiate_picks_most_preferred_codec() {
+        assert_eq!(pick("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(pick("gzip;q=0.5, zstd"), Some(Encoding::Zstd));
+        assert_eq!(
+            pick("br;q=0.1, deflate;q=0.9, gzip;q=0.8"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(pick("*"), Some(Encoding::Brotli));
+    }
+
+    #[test]
+    fn negotiate_empty_header_means_identity() {
+        assert_eq!(pick(""), Some(Encoding::Identity));
+    }
+}
@@ -1,11 +1,63 @@
 use std::error::Error;
-use std::fs;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
 
+use crate::autoindex;
+use crate::cache;
+use crate::conditional::{self, Precondition, Validators};
+use crate::config::{
+    Autoindex, CacheLimits, CompressionLevels, CompressionPolicy, ETagMode, FormLimits, MimeTypes,
+    PathPolicy, StaticSite, Uploads, Watch,
+};
+use crate::encoding::{self, Encoding, SUPPORTED_ENCODINGS};
 use crate::interface::{
-    self, HttpResponse, InternalServerErrorResponse, NotFoundResponse, OKResponse
+    self, FileResponse, HttpResponse, InternalServerErrorResponse, NotFoundResponse, OKResponse,
+    PartialContentResponse,
 };
+use crate::mime;
+use crate::multipart::{self, MultipartError};
+use crate::range::{self, RangeRequest};
+use crate::sse::{self, Event, EventSender};
 use crate::utils;
+use crate::watch;
+use crate::websocket::{self, WebSocket, WebSocketError};
+
+/// Methods served under `/files/`, as announced in `Allow`.
+const FILE_METHODS: &str = "GET, HEAD, POST, PUT, PATCH, DELETE, MKCOL";
+/// Directories can only be read.
+const DIRECTORY_METHODS: &str = "GET, HEAD";
+
+/// The body of a request. Form uploads are saved as they arrive, so the server
+/// leaves theirs on the connection for the handler to read; see
+/// `streamed_body_limit`.
+pub enum RequestBody<'a> {
+    Bytes(&'a [u8]),
+    Stream(&'a mut dyn Read),
+}
+
+impl RequestBody<'_> {
+    /// The body as read by the server, which is empty if it was left to stream.
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            RequestBody::Bytes(bytes) => bytes,
+            RequestBody::Stream(_) => &[],
+        }
+    }
+}
+
+impl Read for RequestBody<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            RequestBody::Bytes(bytes) => bytes.read(buf),
+            RequestBody::Stream(reader) => reader.read(buf),
+        }
+    }
+}
 
 fn handle_root() -> Box<dyn HttpResponse> {
     Box::new(OKResponse::new(""))
@@ -19,7 +71,113 @@ fn handle_echo(content: &String) -> Box<dyn HttpResponse> {
     Box::new(OKResponse::new(content))
 }
 
-fn handle_user_agent(headers: &Vec<String>) -> Box<dyn HttpResponse> {
+fn handle_ws_echo(socket: &mut WebSocket) -> Result<(), WebSocketError> {
+    while let Some(message) = socket.recv()? {
+        socket.send(&message)?;
+    }
+    Ok(())
+}
+
+const TICKER_INTERVAL: Duration = Duration::from_secs(1);
+
+fn run_ticker(last_event_id: Option<String>, sender: EventSender, interval: Duration) {
+    // Resume after the last tick the client saw
+    let start = last_event_id
+        .and_then(|id| id.parse::<u64>().ok())
+        .map_or(0, |id| id + 1);
+
+    for tick in start.. {
+        let mut event = Event::new(tick.to_string())
+            .with_id(tick.to_string())
+            .with_event("tick");
+        if tick == start {
+            // Ask the browser to reconnect quickly if the stream drops
+            event = event.with_retry(TICKER_INTERVAL.as_millis() as u64);
+        }
+        if sender.send(event).is_err() {
+            println!("Ticker client disconnected");
+            break;
+        }
+        thread::sleep(interval);
+    }
+}
+
+fn handle_event_ticker(headers: &[String]) -> Box<dyn HttpResponse> {
+    Box::new(sse::stream(headers, |last_event_id, sender| {
+        run_ticker(last_event_id, sender, TICKER_INTERVAL)
+    }))
+}
+
+/// How long to wait for more changes before telling browsers to reload. Saving
+/// a file often takes an editor several writes and renames.
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// How often a live reload stream that sees no changes checks for its client.
+const RELOAD_CLIENT_CHECK: Duration = Duration::from_secs(1);
+
+/// Tells the client about changes in batches. Paths `policy` does not let clients
+/// access are left out, so that changes to `.git/` or `.env` are not given away.
+fn run_live_reload(
+    sender: EventSender,
+    changes: Receiver<PathBuf>,
+    root: &Path,
+    policy: &PathPolicy,
+    debounce: Duration,
+) {
+    loop {
+        // Without changes to send, nothing else would notice the client is gone
+        let first = match changes.recv_timeout(RELOAD_CLIENT_CHECK) {
+            Ok(path) => path,
+            Err(RecvTimeoutError::Timeout) if sender.is_closed() => break,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let mut changed = vec![first];
+        while let Ok(path) = changes.recv_timeout(debounce) {
+            if !changed.contains(&path) {
+                changed.push(path);
+            }
+        }
+        changed.retain(|path| utils::is_safe_path(path, root, policy));
+        if changed.is_empty() {
+            continue;
+        }
+
+        let paths: Vec<String> = changed
+            .iter()
+            .map(|path| path.strip_prefix(root).unwrap_or(path).to_string_lossy().into_owned())
+            .collect();
+        if sender.send(Event::new(paths.join("\n")).with_event("reload")).is_err() {
+            println!("Live reload client disconnected");
+            break;
+        }
+    }
+}
+
+fn handle_live_reload(headers: &[String]) -> Box<dyn HttpResponse> {
+    if !Watch::from_env().live_reload {
+        return Box::new(NotFoundResponse);
+    }
+
+    // Subscribe right away, so that nothing changing while the stream starts is missed
+    let changes = watch::subscribe();
+    let root = app_directory();
+    let policy = PathPolicy::from_env();
+    Box::new(sse::stream(headers, move |_, sender| {
+        run_live_reload(sender, changes, &root, &policy, RELOAD_DEBOUNCE)
+    }))
+}
+
+fn handle_cache_stats() -> Box<dyn HttpResponse> {
+    if CacheLimits::from_env().max_size == 0 {
+        return Box::new(NotFoundResponse);
+    }
+
+    let stats = cache::global().stats();
+    Box::new(OKResponse::new(stats.to_json()).with_content_type("application/json"))
+}
+
+fn handle_user_agent(headers: &[String]) -> Box<dyn HttpResponse> {
     let user_agent = headers
         .iter()
         .find(|header| header.starts_with("User-Agent: "));
@@ -33,26 +191,324 @@ fn handle_user_agent(headers: &Vec<String>) -> Box<dyn HttpResponse> {
     }
 }
 
-fn handle_read_file(file_path: &PathBuf) -> Box<dyn HttpResponse> {
-    let content = fs::read_to_string(file_path);
+fn sidecar_path(file_path: &Path, extension: &str) -> PathBuf {
+    let mut sidecar = file_path.as_os_str().to_owned();
+    sidecar.push(".");
+    sidecar.push(extension);
+    PathBuf::from(sidecar)
+}
 
-    match content {
-        Ok(text) => Box::new(
-            OKResponse::new(text).with_content_type("application/octet-stream")
-        ),
-        Err(_e) => {
-            println!("File not found: {:?}", file_path);
-            Box::new(NotFoundResponse)
+/// Looks for a precompressed sibling of `file_path` (e.g. `app.js.br`) that the
+/// client accepts and that is at least as new as the original file.
+fn find_precompressed(
+    file_path: &Path,
+    directory: &Path,
+    headers: &[String],
+) -> Option<(Encoding, PathBuf)> {
+    let modified = fs::metadata(file_path).ok()?.modified().ok()?;
+    let policy = PathPolicy::from_env();
+
+    let sidecars: Vec<(Encoding, PathBuf)> = SUPPORTED_ENCODINGS
+        .iter()
+        .filter_map(|&encoding| {
+            let sidecar = sidecar_path(file_path, encoding.file_extension()?);
+            let sidecar_modified = fs::metadata(&sidecar).ok()?.modified().ok()?;
+            let is_fresh =
+                sidecar_modified >= modified && utils::is_safe_path(&sidecar, directory, &policy);
+            is_fresh.then_some((encoding, sidecar))
+        })
+        .collect();
+    if sidecars.is_empty() {
+        return None;
+    }
+
+    let available: Vec<Encoding> = sidecars.iter().map(|(encoding, _)| *encoding).collect();
+    let encoding = encoding::negotiate(utils::get_header(headers, "Accept-Encoding"), &available)?;
+    sidecars.into_iter().find(|(e, _)| *e == encoding)
+}
+
+fn handle_read_file(
+    file_path: &Path,
+    directory: &Path,
+    headers: &[String],
+    mime_types: &MimeTypes,
+    etag_mode: ETagMode,
+) -> Box<dyn HttpResponse> {
+    let range = utils::get_header(headers, "Range");
+
+    // Ranges refer to the file itself, so they are always served from the original
+    let precompressed = range
+        .is_none()
+        .then(|| find_precompressed(file_path, directory, headers))
+        .flatten();
+    let (path, encoding) = match precompressed {
+        Some((encoding, sidecar)) => (sidecar, Some(encoding)),
+        None => (file_path.to_path_buf(), None),
+    };
+
+    let file = File::open(&path)
+        .ok()
+        .filter(|file| file.metadata().is_ok_and(|metadata| metadata.is_file()));
+    let mut file = match file {
+        Some(file) => file,
+        None => {
+            println!("File not found: {:?}", path);
+            return Box::new(NotFoundResponse);
         }
+    };
+
+    // Sidecars are compressed already, so only originals are cached
+    let limits = CacheLimits::from_env();
+    let cached = match encoding {
+        None => cache::load(&path, &mut file, etag_mode, &limits),
+        Some(_) => Ok(None),
+    };
+    let loaded = cached.and_then(|cached| match &cached {
+        Some(cached) => Ok((cached.validators.clone(), cached.contents.len() as u64)),
+        None => Ok((Validators::from_file(&mut file, etag_mode)?, file.metadata()?.len())),
     }
+    .map(|(validators, length)| (cached, validators, length)));
+    let (cached, validators, length) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            println!("Error reading file {:?}: {}", path, e);
+            return Box::new(InternalServerErrorResponse);
+        }
+    };
+    match conditional::evaluate("GET", headers, Some(&validators)) {
+        Precondition::NotModified => {
+            return Box::new(interface::NotModifiedResponse::new(validators.headers()))
+        }
+        Precondition::Failed => return Box::new(interface::PreconditionFailedResponse),
+        Precondition::Proceed => {}
+    }
+
+    // Sniffing would only see compressed bytes, so sidecars go by the extension alone
+    let prefix = match (&cached, encoding) {
+        (Some(cached), _) if mime_types.sniff => {
+            Some(cached.contents[..cached.contents.len().min(mime::SNIFF_LENGTH)].to_vec())
+        }
+        (None, None) if mime_types.sniff => read_prefix(&mut file, mime::SNIFF_LENGTH),
+        _ => None,
+    };
+    let content_type = mime::content_type(file_path, prefix.as_deref(), mime_types);
+
+    if let Some(range) = range.filter(|_| conditional::if_range_matches(headers, &validators)) {
+        match range::parse(range, length) {
+            RangeRequest::Full => {}
+            RangeRequest::Unsatisfiable => {
+                return Box::new(interface::RangeNotSatisfiableResponse { length })
+            }
+            RangeRequest::Partial(ranges) if ranges.len() == 1 && cached.is_none() => {
+                let mut partial = FileResponse::partial(file, ranges[0].start, ranges[0].end, length)
+                    .with_header("Content-Type", content_type);
+                for (name, value) in validators.headers() {
+                    partial = partial.with_header(name, value);
+                }
+                return Box::new(partial.with_header("Accept-Ranges", "bytes"));
+            }
+            RangeRequest::Partial(ranges) => {
+                let partial = match &cached {
+                    Some(cached) => PartialContentResponse::shared(Arc::clone(&cached.contents)),
+                    None => PartialContentResponse::from_file(file, length),
+                };
+                let mut partial = range::respond(&ranges, partial, length, &content_type);
+                for (name, value) in validators.headers() {
+                    partial = partial.with_header(name, value);
+                }
+                return Box::new(partial.with_header("Accept-Ranges", "bytes"));
+            }
+        }
+    }
+
+    // Anything that goes out as is, is sent straight from the file, with the `Vary`
+    // that `encode_response` would add.
+    let policy = CompressionPolicy::from_env();
+    let compressible = policy.allows_type(Some(&content_type));
+    let accepted = encoding::negotiate(
+        utils::get_header(headers, "Accept-Encoding"),
+        SUPPORTED_ENCODINGS,
+    );
+    if let Some(cached) = cached {
+        let wants_compression = compressible && length as usize >= policy.min_size;
+        let variant = match accepted {
+            Some(accepted) if wants_compression && accepted != Encoding::Identity => {
+                let levels = CompressionLevels::from_env();
+                match cache::compressed(&path, &cached, accepted, &levels, &limits) {
+                    Ok(body) => Some((accepted, body)),
+                    Err(e) => {
+                        println!("Error compressing file {:?}: {}", path, e);
+                        return Box::new(InternalServerErrorResponse);
+                    }
+                }
+            }
+            _ => None,
+        };
+        // Without a variant, `encode_response` settles `Vary` and `406` as usual
+        let (mut response, validators) = match variant {
+            Some((accepted, body)) => (
+                OKResponse::shared(body)
+                    .with_encoding(accepted)
+                    .with_vary("Accept-Encoding"),
+                validators.weakened(),
+            ),
+            None => (OKResponse::shared(cached.contents), validators),
+        };
+        response = response
+            .with_content_type(content_type)
+            .with_header("Accept-Ranges", "bytes");
+        for (name, value) in validators.headers() {
+            response = response.with_header(name, value);
+        }
+        return Box::new(response);
+    }
+    if encoding.is_some() || !compressible || accepted == Some(Encoding::Identity) {
+        let mut response = FileResponse::new(file, length).with_header("Content-Type", content_type);
+        response = match encoding {
+            Some(encoding) => response
+                .with_header("Content-Encoding", encoding.name())
+                .with_header("Vary", "Accept-Encoding"),
+            None if compressible => response
+                .with_header("Accept-Ranges", "bytes")
+                .with_header("Vary", "Accept-Encoding"),
+            None => response.with_header("Accept-Ranges", "bytes"),
+        };
+        for (name, value) in validators.headers() {
+            response = response.with_header(name, value);
+        }
+        return Box::new(response);
+    }
+
+    // Compressed as it is read from the file, once `encode_response` settles how
+    let mut response = OKResponse::from_file(file, length)
+        .with_content_type(content_type)
+        .with_header("Accept-Ranges", "bytes");
+    for (name, value) in validators.headers() {
+        response = response.with_header(name, value);
+    }
+    Box::new(response)
+}
+
+/// Reads up to `length` bytes from the start of `file` and rewinds it.
+fn read_prefix(file: &mut File, length: usize) -> Option<Vec<u8>> {
+    let mut prefix = Vec::with_capacity(length);
+    file.take(length as u64).read_to_end(&mut prefix).ok()?;
+    file.seek(SeekFrom::Start(0)).ok()?;
+    Some(prefix)
+}
+
+fn handle_list_directory(
+    directory: &Path,
+    url_path: &str,
+    query: &str,
+    headers: &[String],
+    config: &Autoindex,
+) -> Box<dyn HttpResponse> {
+    if !config.enabled {
+        println!("Directory listing disabled: {:?}", directory);
+        return Box::new(NotFoundResponse);
+    }
+
+    match autoindex::listing(directory, url_path, query, headers, config) {
+        Ok(response) => Box::new(response),
+        Err(e) => {
+            println!("Error listing directory {:?}: {}", directory, e);
+            Box::new(InternalServerErrorResponse)
+        }
+    }
+}
+
+/// Serializes changes to files, so that no other change slips in between checking
+/// a request's preconditions and applying it.
+fn lock_writes() -> MutexGuard<'static, ()> {
+    static WRITE_LOCK: Mutex<()> = Mutex::new(());
+    WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Makes sure the directory `path` goes into exists, creating it and any missing
+/// ancestors if uploads may do so. `path` has passed `is_safe_path`, so whatever
+/// is created stays inside the served directory, or with symlinks followed
+/// `always`, inside a directory linked from it.
+fn ensure_parent(path: &Path, uploads: &Uploads) -> Result<(), Box<dyn HttpResponse>> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.is_dir() => parent,
+        _ => return Ok(()),
+    };
+    if !uploads.create_parents {
+        println!("Parent directory missing: {:?}", parent);
+        return Err(Box::new(interface::ConflictResponse));
+    }
+
+    println!("Creating directory: {:?}", parent);
+    fs::create_dir_all(parent).map_err(|e| {
+        println!("Error creating directory {:?}: {}", parent, e);
+        Box::new(interface::ConflictResponse) as Box<dyn HttpResponse>
+    })
+}
+
+/// Where writes to `path` go. `path` has passed `is_safe_path`, so if it is a
+/// symbolic link, its target is one the symlink policy allows, and writing to the
+/// target keeps the link in place.
+fn write_target(path: &Path) -> PathBuf {
+    utils::resolve(path).unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Validators of the file currently at `path`, or `None` if there is none.
+fn current_validators(path: &Path, etag_mode: ETagMode) -> Option<Validators> {
+    if !path.is_file() {
+        return None;
+    }
+    let mut file = File::open(path).ok()?;
+    Validators::from_file(&mut file, etag_mode).ok()
 }
 
-fn handle_write_file(file_path: &PathBuf, content: &String) -> Box<dyn HttpResponse> {
-    println!("Writing to file: {:?} contents: {:?}", file_path, content);
-    let write_response = fs::write(file_path, content);
+/// Creates or replaces a file. `If-None-Match: *` restricts the write to creating
+/// the file and `If-Match` to replacing the version the client last saw.
+fn handle_write_file(
+    file_path: &Path,
+    url_path: &str,
+    method: &str,
+    headers: &[String],
+    content: &[u8],
+    etag_mode: ETagMode,
+    uploads: &Uploads,
+) -> Box<dyn HttpResponse> {
+    let _lock = lock_writes();
+
+    let current = current_validators(file_path, etag_mode);
+    if conditional::evaluate(method, headers, current.as_ref()) == Precondition::Failed {
+        println!("Precondition failed for write to {:?}", file_path);
+        return Box::new(interface::PreconditionFailedResponse);
+    }
+
+    if let Err(response) = ensure_parent(file_path, uploads) {
+        return response;
+    }
+
+    println!(
+        "Writing to file: {:?} contents: {:?}",
+        file_path,
+        String::from_utf8_lossy(content)
+    );
+    let target = write_target(file_path);
+    let write_response = utils::write_atomic(&target, content, uploads.sync_directory);
+    cache::invalidate(file_path);
 
     match write_response {
-        Ok(_) => Box::new(interface::OKCreatedResponse),
+        Ok(_) => {
+            // The stored bytes are exactly the ones sent, so a strong tag is safe
+            let validators = fs::metadata(file_path)
+                .map(|metadata| Validators::new(content, &metadata, etag_mode).headers())
+                .unwrap_or_default();
+            if current.is_some() {
+                return Box::new(interface::NoContentResponse::new(validators));
+            }
+            let mut response = interface::OKCreatedResponse::new(utils::percent_encode(url_path));
+            for (name, value) in validators {
+                response = response.with_header(name, value);
+            }
+            Box::new(response)
+        }
         Err(_e) => {
             println!("Error writing to file: {:?}", file_path);
             Box::new(InternalServerErrorResponse)
@@ -60,33 +516,417 @@ fn handle_write_file(file_path: &PathBuf, content: &String) -> Box<dyn HttpRespo
     }
 }
 
-fn handle_file_route(file_path: &String, method: &str, request_body: &String) -> Box<dyn HttpResponse> {
-    let directory = std::env::var("APP_DIRECTORY").unwrap_or_else(|_| ".".to_string());
+fn handle_delete_file(
+    file_path: &Path,
+    method: &str,
+    headers: &[String],
+    etag_mode: ETagMode,
+) -> Box<dyn HttpResponse> {
+    let _lock = lock_writes();
+
+    let current = match current_validators(file_path, etag_mode) {
+        Some(current) => current,
+        None => return Box::new(NotFoundResponse),
+    };
+    if conditional::evaluate(method, headers, Some(&current)) == Precondition::Failed {
+        println!("Precondition failed for delete of {:?}", file_path);
+        return Box::new(interface::PreconditionFailedResponse);
+    }
+
+    println!("Deleting file: {:?}", file_path);
+    let remove_response = fs::remove_file(file_path);
+    cache::invalidate(file_path);
+    match remove_response {
+        Ok(_) => Box::new(interface::NoContentResponse::new(vec![])),
+        Err(e) => {
+            println!("Error deleting file {:?}: {}", file_path, e);
+            Box::new(InternalServerErrorResponse)
+        }
+    }
+}
+
+/// Appends `content` to an existing file, or with `?offset=N` overwrites the file
+/// from byte `N` on. The offset may be at most the current length of the file.
+fn handle_patch_file(
+    file_path: &Path,
+    query: &str,
+    method: &str,
+    headers: &[String],
+    content: &[u8],
+    etag_mode: ETagMode,
+    uploads: &Uploads,
+) -> Box<dyn HttpResponse> {
+    let offset = match utils::parse_query(query)
+        .into_iter()
+        .find(|(name, _)| name == "offset")
+    {
+        Some((_, offset)) => match offset.parse::<u64>() {
+            Ok(offset) => Some(offset),
+            Err(_) => return Box::new(interface::BadRequestResponse),
+        },
+        None => None,
+    };
+
+    let _lock = lock_writes();
+
+    let mut contents = match fs::read(file_path) {
+        Ok(contents) => contents,
+        Err(_) => return Box::new(NotFoundResponse),
+    };
+    let current = match fs::metadata(file_path) {
+        Ok(metadata) => Validators::new(&contents, &metadata, etag_mode),
+        Err(_) => return Box::new(NotFoundResponse),
+    };
+    if conditional::evaluate(method, headers, Some(&current)) == Precondition::Failed {
+        println!("Precondition failed for patch of {:?}", file_path);
+        return Box::new(interface::PreconditionFailedResponse);
+    }
+
+    let length = contents.len();
+    let offset = offset.map_or(length, |offset| offset.min(usize::MAX as u64) as usize);
+    if offset > length {
+        return Box::new(interface::RangeNotSatisfiableResponse { length: length as u64 });
+    }
+
+    println!(
+        "Patching file: {:?} at offset {} contents: {:?}",
+        file_path,
+        offset,
+        String::from_utf8_lossy(content)
+    );
+    // Patch a copy and swap it in, so readers never see a half-applied change
+    let end = (offset + content.len()).min(length);
+    contents.splice(offset..end, content.iter().copied());
+    let target = write_target(file_path);
+    let write_response = utils::write_atomic(&target, &contents, uploads.sync_directory);
+    cache::invalidate(file_path);
+
+    match write_response {
+        Ok(_) => {
+            let validators = current_validators(file_path, etag_mode)
+                .map(|validators| validators.headers())
+                .unwrap_or_default();
+            Box::new(interface::NoContentResponse::new(validators))
+        }
+        Err(e) => {
+            println!("Error patching file {:?}: {}", file_path, e);
+            Box::new(InternalServerErrorResponse)
+        }
+    }
+}
+
+/// Creates an empty directory, WebDAV `MKCOL` style.
+fn handle_make_directory(
+    path: &Path,
+    url_path: &str,
+    content: &[u8],
+    uploads: &Uploads,
+) -> Box<dyn HttpResponse> {
+    // A body would describe the directory's contents, which we do not support
+    if !content.is_empty() {
+        return Box::new(interface::BadRequestResponse);
+    }
+
+    let _lock = lock_writes();
+
+    if path.exists() {
+        return Box::new(interface::MethodNotAllowedResponse { allow: FILE_METHODS });
+    }
+    if let Err(response) = ensure_parent(path, uploads) {
+        return response;
+    }
+
+    println!("Creating directory: {:?}", path);
+    match fs::create_dir(path) {
+        Ok(_) => Box::new(interface::OKCreatedResponse::new(utils::percent_encode(url_path))),
+        Err(e) => {
+            println!("Error creating directory {:?}: {}", path, e);
+            Box::new(InternalServerErrorResponse)
+        }
+    }
+}
+
+fn is_form_upload(headers: &[String]) -> bool {
+    utils::get_header(headers, "Content-Type").is_some_and(|content_type| {
+        content_type
+            .trim_start()
+            .to_ascii_lowercase()
+            .starts_with("multipart/form-data")
+    })
+}
+
+/// Saves the files of a `multipart/form-data` upload into `directory` and
+/// answers with a JSON summary of the files and fields.
+fn handle_form_upload(
+    directory: &Path,
+    url_path: &str,
+    headers: &[String],
+    content: RequestBody,
+    uploads: &Uploads,
+) -> Box<dyn HttpResponse> {
+    let boundary = match utils::get_header(headers, "Content-Type").and_then(multipart::boundary) {
+        Some(boundary) => boundary,
+        None => return Box::new(interface::BadRequestResponse),
+    };
+    if !directory.is_dir() {
+        println!("Form upload target is not a directory: {:?}", directory);
+        return Box::new(interface::ConflictResponse);
+    }
 
-    let path = Path::new(&directory).join(file_path);
+    let _lock = lock_writes();
 
-    if !utils::is_safe_path(&path, &Path::new(&directory)) {
+    println!("Saving form upload to: {:?}", directory);
+    let limits = FormLimits::from_env();
+    match multipart::save_form(content, &boundary, directory, &limits, uploads.sync_directory) {
+        Ok(form) => {
+            for file in &form.files {
+                cache::invalidate(&directory.join(&file.file_name));
+            }
+            Box::new(OKResponse::new(form.to_json(url_path)).with_content_type("application/json"))
+        }
+        Err(MultipartError::Malformed(e)) => {
+            println!("Malformed form upload: {}", e);
+            Box::new(interface::BadRequestResponse)
+        }
+        Err(MultipartError::PartTooLarge(_))
+        | Err(MultipartError::FormTooLarge(_))
+        | Err(MultipartError::TooManyParts(_)) => {
+            Box::new(interface::PayloadTooLargeResponse)
+        }
+        // The client stopped sending in the middle of the form
+        Err(MultipartError::Io(e))
+            if matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock) =>
+        {
+            Box::new(interface::RequestTimeoutResponse)
+        }
+        Err(MultipartError::Io(e)) => {
+            println!("Error saving form upload to {:?}: {}", directory, e);
+            Box::new(InternalServerErrorResponse)
+        }
+    }
+}
+
+fn app_directory() -> PathBuf {
+    PathBuf::from(std::env::var("APP_DIRECTORY").unwrap_or_else(|_| ".".to_string()))
+}
+
+fn resolve_file_path(file_path: &str) -> Result<PathBuf, Box<dyn HttpResponse>> {
+    let directory = app_directory();
+
+    let path = directory.join(file_path);
+
+    if !utils::is_safe_path(&path, &directory, &PathPolicy::from_env()) {
         println!("Invalid path: {:?}", path);
-        return Box::new(interface::ForbiddenResponse);
+        return Err(Box::new(interface::ForbiddenResponse));
+    }
+
+    Ok(path)
+}
+
+/// The SPA entry point to serve for the missing file `file_path`, if it lies under
+/// the fallback's directory. Paths with an extension are left to 404, since they
+/// are most likely assets rather than client-side routes.
+fn spa_fallback_path(file_path: &str, site: &StaticSite, directory: &Path) -> Option<PathBuf> {
+    let fallback = site.spa_fallback.as_deref()?;
+    let mount = Path::new(fallback).parent().unwrap_or(Path::new(""));
+    let requested = Path::new(file_path);
+    if !requested.starts_with(mount) || requested.extension().is_some() {
+        return None;
     }
 
+    let fallback_path = directory.join(fallback);
+    let is_allowed = utils::is_safe_path(&fallback_path, directory, &PathPolicy::from_env());
+    (fallback_path.is_file() && is_allowed)
+        .then_some(fallback_path)
+}
+
+fn handle_get_file(
+    path: &Path,
+    file_path: &str,
+    query: &str,
+    headers: &[String],
+    site: &StaticSite,
+) -> Box<dyn HttpResponse> {
+    let directory = app_directory();
+    let mime_types = MimeTypes::from_env();
+    let etag_mode = ETagMode::from_env();
+
+    if path.is_dir() {
+        // Relative links in the directory's pages only resolve with the slash
+        if !file_path.is_empty() && !file_path.ends_with('/') {
+            let mut location = format!("/files/{}/", utils::percent_encode(file_path));
+            if !query.is_empty() {
+                location = format!("{}?{}", location, query);
+            }
+            return Box::new(interface::MovedPermanentlyResponse { location });
+        }
+
+        let policy = PathPolicy::from_env();
+        for index in &site.index_files {
+            let index_path = path.join(index);
+            if index_path.is_file() && utils::is_safe_path(&index_path, &directory, &policy) {
+                return handle_read_file(&index_path, &directory, headers, &mime_types, etag_mode);
+            }
+        }
+
+        // Listing dotfiles that cannot be fetched would only give their names away
+        let mut autoindex = Autoindex::from_env();
+        autoindex.show_hidden &= policy.allow_hidden;
+        let url_path = format!("/files/{}", file_path);
+        return handle_list_directory(path, &url_path, query, headers, &autoindex);
+    }
+
+    if !path.exists() {
+        if let Some(fallback) = spa_fallback_path(file_path, site, &directory) {
+            return handle_read_file(&fallback, &directory, headers, &mime_types, etag_mode);
+        }
+    }
+
+    handle_read_file(path, &directory, headers, &mime_types, etag_mode)
+}
+
+fn handle_file_route(
+    target: &str,
+    method: &str,
+    headers: &[String],
+    request_body: RequestBody,
+) -> Box<dyn HttpResponse> {
+    let (file_path, query) = utils::split_query(target);
+    let file_path = match utils::percent_decode(file_path) {
+        Some(file_path) => file_path,
+        None => return Box::new(interface::BadRequestResponse),
+    };
+    let path = match resolve_file_path(&file_path) {
+        Ok(path) => path,
+        Err(response) => return response,
+    };
+
     println!("file path: {:?}", path);
 
+    let method = method.to_uppercase();
+    match method.as_str() {
+        // `route_request` drops the body of HEAD responses
+        "GET" | "HEAD" => handle_get_file(&path, &file_path, query, headers, &StaticSite::from_env()),
+        "POST" if is_form_upload(headers) => {
+            let url_path = match file_path.trim_end_matches('/') {
+                "" => "/files/".to_string(),
+                directory => format!("/files/{}/", directory),
+            };
+            handle_form_upload(&path, &url_path, headers, request_body, &Uploads::from_env())
+        }
+        "POST" | "PUT" | "PATCH" | "DELETE" | "MKCOL" if path.is_dir() => {
+            Box::new(interface::MethodNotAllowedResponse { allow: DIRECTORY_METHODS })
+        }
+        "POST" | "PUT" => {
+            let url_path = format!("/files/{}", file_path);
+            handle_write_file(
+                &path,
+                &url_path,
+                &method,
+                headers,
+                request_body.as_slice(),
+                ETagMode::from_env(),
+                &Uploads::from_env(),
+            )
+        }
+        "PATCH" => handle_patch_file(
+            &path,
+            query,
+            &method,
+            headers,
+            request_body.as_slice(),
+            ETagMode::from_env(),
+            &Uploads::from_env(),
+        ),
+        "DELETE" => handle_delete_file(&path, &method, headers, ETagMode::from_env()),
+        "MKCOL" => {
+            let url_path = format!("/files/{}/", file_path.trim_end_matches('/'));
+            handle_make_directory(&path, &url_path, request_body.as_slice(), &Uploads::from_env())
+        }
+        _ => Box::new(interface::MethodNotAllowedResponse { allow: FILE_METHODS }),
+    }
+}
+
+/// Applies the content coding negotiated from `Accept-Encoding` to an OK response,
+/// subject to the compression policy.
+fn encode_response(
+    response: &OKResponse,
+    headers: &[String],
+    policy: &CompressionPolicy,
+    levels: &CompressionLevels,
+) -> Box<dyn HttpResponse> {
+    // Leave alone bodies that are already encoded or would not shrink
+    if response.content_encoding().is_some() || !policy.allows_type(response.content_type()) {
+        return Box::new(response.clone());
+    }
+
+    let response = response.clone().with_vary("Accept-Encoding");
+    let encoding = encoding::negotiate(
+        utils::get_header(headers, "Accept-Encoding"),
+        SUPPORTED_ENCODINGS,
+    );
+
+    match encoding {
+        None => Box::new(interface::NotAcceptableResponse),
+        Some(_) if response.body_len() < policy.min_size => Box::new(response),
+        Some(encoding) => Box::new(response.encode(encoding, levels)),
+    }
+}
+
+/// Decides, from the request line alone, whether a request that announced
+/// `Expect: 100-continue` should be refused before the client sends its body.
+pub fn check_expectation(request_line: &str) -> Option<Box<dyn HttpResponse>> {
+    let mut components = request_line.split_whitespace();
+    let method = components.next()?;
+    let target = components.next()?.strip_prefix("/files/")?;
+    let file_path = match utils::percent_decode(utils::split_query(target).0) {
+        Some(file_path) => file_path,
+        None => return Some(Box::new(interface::BadRequestResponse)),
+    };
+
     match method.to_uppercase().as_str() {
-        "GET" => handle_read_file(&path),
-        "POST" => handle_write_file(&path, request_body),
-        _ => Box::new(interface::MethodNotAllowedResponse),
+        "GET" | "HEAD" | "POST" | "PUT" | "PATCH" | "DELETE" | "MKCOL" => {
+            resolve_file_path(&file_path).err()
+        }
+        _ => Some(Box::new(interface::MethodNotAllowedResponse { allow: FILE_METHODS })),
     }
 }
 
+/// The most bytes of body a request may have if the handler reads it straight
+/// from the connection, or `None` if the server should read it in full first.
+/// Form uploads are read like that, as they are saved while they arrive, and are
+/// bounded by the form limits instead of the server's body limit.
+pub fn streamed_body_limit(request_line: &str, headers: &[String]) -> Option<usize> {
+    let mut components = request_line.split_whitespace();
+    let method = components.next()?;
+    let target = components.next()?;
+    let streamed = method.eq_ignore_ascii_case("POST")
+        && target.starts_with("/files/")
+        && is_form_upload(headers);
+    streamed.then(|| FormLimits::from_env().max_form_size)
+}
+
+/// Routes a request and renders the response straight to bytes.
+#[cfg(test)]
 pub fn handle_http_request(
     request_line: &String,
     headers: &Vec<String>,
-    request_body: &String,
+    request_body: &[u8],
 ) -> Result<Vec<u8>, Box<dyn Error>> {
+    let response = route_request(request_line, headers, RequestBody::Bytes(request_body))?;
+    Ok(response.response())
+}
+
+/// Routes a request to its handler. Unlike `handle_http_request`, this keeps the
+/// response object around so the server can act on upgrades and streaming bodies.
+pub fn route_request(
+    request_line: &String,
+    headers: &Vec<String>,
+    request_body: RequestBody,
+) -> Result<Box<dyn HttpResponse>, Box<dyn Error>> {
     println!(
         "received request: {:?}, headers: {:?}, request body: {:?}",
-        request_line, headers, request_body
+        request_line, headers, String::from_utf8_lossy(request_body.as_slice())
     );
 
     let request_components: Vec<&str> = request_line.split_whitespace().collect();
@@ -96,13 +936,6 @@ pub fn handle_http_request(
         return Err("Invalid request line".into());
     }
 
-    let is_valid_encoding = headers
-        .iter()
-        .find(|header| header.starts_with("Accept-Encoding: "))
-        .and_then(|header| header.split(": ").nth(1))
-        .and_then(|encoding| encoding.split(", ").map(|e| e.trim()).find(|&e| e == "gzip"))
-        .is_some();
-
     let method = request_components[0];
     let route = request_components[1];
 
@@ -112,29 +945,45 @@ pub fn handle_http_request(
         "/" => handle_root(),
         r if r.starts_with("/echo/") => handle_echo(&r[6..].to_string()),
         r if r.starts_with("/files/") => {
-            handle_file_route(&r[7..].to_string(), method, &request_body)
+            handle_file_route(&r[7..], method, headers, request_body)
         }
-        "/user-agent" => handle_user_agent(&headers),
+        "/user-agent" => handle_user_agent(headers),
+        "/events/ticker" => handle_event_ticker(headers),
+        "/events/reload" => handle_live_reload(headers),
+        "/stats/cache" => handle_cache_stats(),
+        "/ws/echo" => websocket::upgrade(method, headers, |socket: &mut WebSocket| {
+            handle_ws_echo(socket)
+        }),
         _ => handle_default(),
     };
 
-    let response = if is_valid_encoding {
-        if let Some(ok_response) = response.as_any().downcast_ref::<OKResponse>() {
-            Box::new(ok_response.clone().compress()) as Box<dyn HttpResponse>
-        } else {
-            response
-        }
+    let response = if let Some(ok_response) = response.as_any().downcast_ref::<OKResponse>() {
+        // Before HTTP/1.1 there is no chunked transfer coding to stream a compressed body in
+        let ok_response = match request_components[2] {
+            "HTTP/1.1" => ok_response.clone(),
+            _ => ok_response.clone().without_chunks(),
+        };
+        encode_response(
+            &ok_response,
+            headers,
+            &CompressionPolicy::from_env(),
+            &CompressionLevels::from_env(),
+        )
     } else {
         response
     };
 
-    Ok(response.response())
+    if method.eq_ignore_ascii_case("HEAD") {
+        return Ok(Box::new(interface::HeadResponse::new(response)));
+    }
+    Ok(response)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::utils;
+    use std::time::SystemTime;
     use std::{env, vec};
 
     // Helper to get just the headers part as a string
@@ -166,14 +1015,14 @@ mod tests {
         route: &str,
         headers: Option<Vec<&str>>,
         body: Option<&str>,
-    ) -> (String, Vec<String>, String) {
+    ) -> (String, Vec<String>, Vec<u8>) {
         let request_line = format!("{} {} HTTP/1.1", method, route);
-        let headers = headers.unwrap_or_else(|| vec![]);
-        let body = body.unwrap_or_else(|| "");
+        let headers = headers.unwrap_or_default();
+        let body = body.unwrap_or("");
         (
             request_line,
             headers.iter().map(|h| h.to_string()).collect(),
-            body.to_string(),
+            body.as_bytes().to_vec(),
         )
     }
 
@@ -232,6 +1081,7 @@ mod tests {
 
     #[test]
     fn handle_http_request_get_file_route_valid() {
+        let _env = utils::env_lock();
         env::set_var(
             "APP_DIRECTORY",
             utils::get_project_source().unwrap_or_else(|| ".".to_string()),
@@ -241,11 +1091,16 @@ mod tests {
         assert_eq!(get_status(&response), "200");
         assert_eq!(get_body(&response), "Hello, World!");
         assert_eq!(get_content_length(&response), 13);
-        assert_eq!(get_header_value(&response, "Content-Type").unwrap(), "application/octet-stream");
+        assert_eq!(get_header_value(&response, "Content-Type").unwrap(), "text/plain; charset=utf-8");
     }
 
     #[test]
     fn handle_http_request_get_file_route_invalid() {
+        let _env = utils::env_lock();
+        env::set_var(
+            "APP_DIRECTORY",
+            utils::get_project_root().unwrap_or_else(|| ".".to_string()),
+        );
         let (request, headers, body) = get_inputs("GET", "/files/random.txt", None, None);
         let response = handle_http_request(&request, &headers, &body).unwrap();
         assert_eq!(get_status(&response), "404");
@@ -253,6 +1108,7 @@ mod tests {
 
     #[test]
     fn handle_http_request_invalid_file_path() {
+        let _env = utils::env_lock();
         env::set_var(
             "APP_DIRECTORY",
             utils::get_project_source().unwrap_or_else(|| ".".to_string()),
@@ -264,6 +1120,7 @@ mod tests {
 
     #[test]
     fn handle_http_request_post_file_route() {
+        let _env = utils::env_lock();
         env::set_var(
             "APP_DIRECTORY",
             utils::get_project_source().unwrap_or_else(|| ".".to_string()),
@@ -291,7 +1148,7 @@ mod tests {
     fn handle_gzip_encoding() {
         let (request, headers, body) = get_inputs(
             "GET",
-            "/",
+            "/echo/abc",
             Some(vec!["Accept-Encoding: gzip"]),
             None
         );
@@ -305,7 +1162,7 @@ mod tests {
     fn handle_multiple_encodings() {
         let (request, headers, body) = get_inputs(
             "GET",
-            "/",
+            "/echo/abc",
             Some(vec!["Accept-Encoding: deflate, gzip, random"]),
             None
         );
@@ -320,7 +1177,7 @@ mod tests {
         let (request, headers, body) = get_inputs(
             "GET",
             "/",
-            Some(vec!["Accept-Encoding: deflate"]),
+            Some(vec!["Accept-Encoding: compress"]),
             None
         );
         let response = handle_http_request(&request, &headers, &body).unwrap();
@@ -334,7 +1191,7 @@ mod tests {
         let (request, headers, body) = get_inputs(
             "GET",
             "/",
-            Some(vec!["Accept-Encoding: deflate, random"]),
+            Some(vec!["Accept-Encoding: compress, random"]),
             None
         );
         let response = handle_http_request(&request, &headers, &body).unwrap();
@@ -343,6 +1200,858 @@ mod tests {
         assert!(get_header_value(&response, "Content-Encoding").is_none());
     }
 
+    #[test]
+    fn handle_ws_echo_upgrade() {
+        let (request, headers, body) = get_inputs(
+            "GET",
+            "/ws/echo",
+            Some(vec![
+                "Upgrade: websocket",
+                "Connection: Upgrade",
+                "Sec-WebSocket-Version: 13",
+                "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==",
+            ]),
+            None,
+        );
+        let response = handle_http_request(&request, &headers, &body).unwrap();
+        assert_eq!(get_status(&response), "101");
+        assert_eq!(
+            get_header_value(&response, "Sec-WebSocket-Accept").unwrap(),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn handle_ws_echo_requires_upgrade() {
+        let (request, headers, body) = get_inputs("GET", "/ws/echo", None, None);
+        let response = handle_http_request(&request, &headers, &body).unwrap();
+        assert_eq!(get_status(&response), "426");
+    }
+
+    #[test]
+    fn handle_ws_echo_round_trip() {
+        // Masked "Hi" text frame followed by a masked close frame
+        let input = vec![
+            0x81, 0x82, 0x01, 0x02, 0x03, 0x04, b'H' ^ 0x01, b'i' ^ 0x02,
+            0x88, 0x80, 0x01, 0x02, 0x03, 0x04,
+        ];
+        let mut reader = std::io::Cursor::new(input);
+        let mut output = Vec::new();
+        let mut socket = WebSocket::new(&mut reader, &mut output);
+
+        handle_ws_echo(&mut socket).unwrap();
+        assert_eq!(output, vec![0x81, 0x02, b'H', b'i', 0x88, 0x02, 0x03, 0xE8]);
+    }
+
+    // Accepts writes until `limit` bytes have been written, then behaves like a closed socket
+    struct ClosingWriter {
+        data: Vec<u8>,
+        limit: usize,
+    }
+
+    impl std::io::Write for ClosingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.data.len() >= self.limit {
+                return Err(std::io::ErrorKind::BrokenPipe.into());
+            }
+            self.data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn handle_event_ticker_resumes_after_last_event_id() {
+        let headers = vec!["Last-Event-ID: 4".to_string()];
+        let response = sse::stream(&headers, |last_event_id, sender| {
+            run_ticker(last_event_id, sender, Duration::from_millis(1))
+        });
+
+        let mut writer = ClosingWriter { data: Vec::new(), limit: 200 };
+        assert!(response.write_to(&mut writer).is_err());
+
+        let output = String::from_utf8(writer.data).unwrap();
+        assert!(output.contains("Content-Type: text/event-stream\r\n"));
+        assert!(output.contains("event: tick\nid: 5\nretry: 1000\ndata: 5\n\n"));
+        assert!(output.contains("event: tick\nid: 6\ndata: 6\n\n"));
+        assert!(!output.contains("id: 4\n"));
+    }
+
+    #[test]
+    fn handle_live_reload_batches_changes() {
+        let root = utils::temp_dir("live-reload");
+        let (changes, receiver) = std::sync::mpsc::channel();
+        changes.send(root.join("index.html")).unwrap();
+        changes.send(root.join("index.html")).unwrap();
+        changes.send(root.join(".env")).unwrap();
+        changes.send(root.join("css/site.css")).unwrap();
+        let later = changes.clone();
+        let hidden = root.join(".git/index");
+        let script = root.join("app.js");
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            later.send(hidden).unwrap();
+            thread::sleep(Duration::from_millis(200));
+            later.send(script).unwrap();
+        });
+        drop(changes);
+
+        let watched = root.clone();
+        let response = sse::stream(&[], move |_, sender| {
+            let policy = PathPolicy::default();
+            run_live_reload(sender, receiver, &watched, &policy, Duration::from_millis(50))
+        });
+        let mut output = Vec::new();
+        response.write_to(&mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("event: reload\ndata: index.html\ndata: css/site.css\n\n"));
+        assert!(output.ends_with("event: reload\ndata: app.js\n\n"));
+        assert_eq!(output.matches("event: reload").count(), 2);
+        assert!(!output.contains(".git") && !output.contains(".env"));
+
+        // Once the client is gone the thread ends, and unsubscribes, without
+        // waiting for another change
+        let (_changes, receiver) = std::sync::mpsc::channel();
+        let (finished, ended) = std::sync::mpsc::channel();
+        let watched = root.clone();
+        let response = sse::stream(&[], move |_, sender| {
+            let policy = PathPolicy::default();
+            run_live_reload(sender, receiver, &watched, &policy, Duration::from_millis(50));
+            finished.send(()).unwrap();
+        })
+        .with_keep_alive(Duration::from_millis(20));
+        let mut client = ClosingWriter {
+            data: Vec::new(),
+            limit: 1,
+        };
+        assert!(response.write_to(&mut client).is_err());
+        assert!(ended.recv_timeout(RELOAD_CLIENT_CHECK * 3).is_ok());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn handle_live_reload_requires_opt_in() {
+        let _env = utils::env_lock();
+        env::remove_var("APP_LIVE_RELOAD");
+        let (request, headers, body) = get_inputs("GET", "/events/reload", None, None);
+        let response = handle_http_request(&request, &headers, &body).unwrap();
+        assert_eq!(get_status(&response), "404");
+    }
+
+    #[test]
+    fn check_expectation_rejects_unsafe_upload() {
+        let _env = utils::env_lock();
+        env::set_var(
+            "APP_DIRECTORY",
+            utils::get_project_source().unwrap_or_else(|| ".".to_string()),
+        );
+        let response = check_expectation("POST /files/../secret.txt HTTP/1.1").unwrap();
+        assert_eq!(get_status(&response.response()), "403");
+
+        let response = check_expectation("TRACE /files/abc.txt HTTP/1.1").unwrap().response();
+        assert_eq!(get_status(&response), "405");
+        assert_eq!(
+            get_header_value(&response, "Allow").unwrap(),
+            "GET, HEAD, POST, PUT, PATCH, DELETE, MKCOL"
+        );
+
+        assert!(check_expectation("POST /files/abc.txt HTTP/1.1").is_none());
+        assert!(check_expectation("POST /echo/abc HTTP/1.1").is_none());
+    }
+
+    #[test]
+    fn handle_encoding_qvalues() {
+        let (request, headers, body) = get_inputs(
+            "GET",
+            "/echo/abc",
+            Some(vec!["accept-encoding: compress,GZIP;q=0.5"]),
+            None
+        );
+        let response = handle_http_request(&request, &headers, &body).unwrap();
+        assert_eq!(get_header_value(&response, "Content-Encoding").unwrap(), "gzip");
+
+        let (request, headers, body) = get_inputs(
+            "GET",
+            "/echo/abc",
+            Some(vec!["Accept-Encoding: gzip;q=0"]),
+            None
+        );
+        let response = handle_http_request(&request, &headers, &body).unwrap();
+        assert_eq!(get_status(&response), "200");
+        assert_eq!(get_body(&response), "abc");
+        assert!(get_header_value(&response, "Content-Encoding").is_none());
+    }
+
+    #[test]
+    fn handle_encoding_not_acceptable() {
+        let (request, headers, body) = get_inputs(
+            "GET",
+            "/echo/abc",
+            Some(vec!["Accept-Encoding: compress, identity;q=0"]),
+            None
+        );
+        let response = handle_http_request(&request, &headers, &body).unwrap();
+        assert_eq!(get_status(&response), "406");
+
+        // Error responses are not subject to negotiation
+        let (request, headers, body) = get_inputs(
+            "GET",
+            "/missing",
+            Some(vec!["Accept-Encoding: identity;q=0"]),
+            None
+        );
+        let response = handle_http_request(&request, &headers, &body).unwrap();
+        assert_eq!(get_status(&response), "404");
+    }
+
+    #[test]
+    fn encode_response_follows_policy() {
+        let headers = vec!["Accept-Encoding: gzip".to_string()];
+        let levels = CompressionLevels::default();
+        let policy = CompressionPolicy {
+            min_size: 10,
+            ..CompressionPolicy::default()
+        };
+        let encode = |response: OKResponse| {
+            encode_response(&response, &headers, &policy, &levels).response()
+        };
+
+        let response = encode(OKResponse::new("a".repeat(10)));
+        assert_eq!(get_header_value(&response, "Content-Encoding").unwrap(), "gzip");
+        assert_eq!(get_header_value(&response, "Vary").unwrap(), "Accept-Encoding");
+
+        // Too small to be worth it, but still negotiated
+        let response = encode(OKResponse::new("a".repeat(9)));
+        assert!(get_header_value(&response, "Content-Encoding").is_none());
+        assert_eq!(get_header_value(&response, "Vary").unwrap(), "Accept-Encoding");
+
+        let response = encode(OKResponse::new("a".repeat(10)).with_content_type("image/png"));
+        assert!(get_header_value(&response, "Content-Encoding").is_none());
+        assert!(get_header_value(&response, "Vary").is_none());
+
+        let precompressed = OKResponse::new("a".repeat(10)).with_encoding(Encoding::Gzip);
+        let response = encode(precompressed);
+        assert_eq!(get_body(&response), "a".repeat(10));
+    }
+
+    #[test]
+    fn handle_read_file_serves_precompressed_sidecar() {
+        let _env = utils::env_lock();
+        let directory = utils::temp_dir("precompressed");
+        let file_path = directory.join("app.js");
+        fs::write(&file_path, "console.log('hi')").unwrap();
+        fs::write(directory.join("app.js.gz"), "gzipped bytes").unwrap();
+
+        let mime_types = MimeTypes::default();
+        let headers = vec!["Accept-Encoding: gzip".to_string()];
+        let response =
+            handle_read_file(&file_path, &directory, &headers, &mime_types, ETagMode::Metadata).response();
+        assert_eq!(get_body(&response), "gzipped bytes");
+        assert_eq!(
+            get_header_value(&response, "Content-Type").unwrap(),
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(get_header_value(&response, "Content-Encoding").unwrap(), "gzip");
+        assert_eq!(get_header_value(&response, "Vary").unwrap(), "Accept-Encoding");
+
+        // Clients that do not accept the sidecar's encoding get the original
+        let headers = vec!["Accept-Encoding: identity".to_string()];
+        let response =
+            handle_read_file(&file_path, &directory, &headers, &mime_types, ETagMode::Metadata).response();
+        assert_eq!(get_body(&response), "console.log('hi')");
+
+        // A sidecar older than the original is stale and ignored
+        let stale = SystemTime::now() - Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(directory.join("app.js.gz"))
+            .unwrap()
+            .set_modified(stale)
+            .unwrap();
+        let headers = vec!["Accept-Encoding: gzip".to_string()];
+        let response =
+            handle_read_file(&file_path, &directory, &headers, &mime_types, ETagMode::Metadata).response();
+        assert_eq!(get_body(&response), "console.log('hi')");
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn handle_empty_body_is_not_compressed() {
+        let (request, headers, body) = get_inputs(
+            "GET",
+            "/",
+            Some(vec!["Accept-Encoding: gzip"]),
+            None
+        );
+        let response = handle_http_request(&request, &headers, &body).unwrap();
+        assert_eq!(get_status(&response), "200");
+        assert_eq!(get_content_length(&response), 0);
+        assert!(get_header_value(&response, "Content-Encoding").is_none());
+        assert_eq!(get_header_value(&response, "Vary").unwrap(), "Accept-Encoding");
+    }
+
+    #[test]
+    fn handle_http_request_lists_directory() {
+        let _env = utils::env_lock();
+        let directory = utils::temp_dir("listing");
+        fs::create_dir(directory.join("sub dir")).unwrap();
+        fs::write(directory.join("sub dir").join("a.txt"), "a").unwrap();
+        env::set_var("APP_DIRECTORY", &directory);
+
+        let (request, headers, body) = get_inputs(
+            "GET",
+            "/files/sub%20dir/?sort=size",
+            Some(vec!["Accept: application/json"]),
+            None
+        );
+        env::remove_var("APP_AUTOINDEX");
+        let response = handle_http_request(&request, &headers, &body).unwrap();
+        assert_eq!(get_status(&response), "404");
+
+        env::set_var("APP_AUTOINDEX", "true");
+        let response = handle_http_request(&request, &headers, &body).unwrap();
+        env::remove_var("APP_AUTOINDEX");
+        assert_eq!(get_status(&response), "200");
+        assert_eq!(get_header_value(&response, "Content-Type").unwrap(), "application/json");
+        assert!(get_body(&response).starts_with("{\"path\":\"/files/sub dir/\",\"entries\":[{\"name\":\"a.txt\""));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn handle_http_request_denies_hidden_files() {
+        let _env = utils::env_lock();
+        let directory = utils::temp_dir("hidden-files");
+        fs::create_dir(directory.join(".git")).unwrap();
+        fs::write(directory.join(".git").join("config"), "[core]").unwrap();
+        fs::write(directory.join(".env"), "SECRET=1").unwrap();
+        fs::write(directory.join("index.txt"), "hi").unwrap();
+        env::set_var("APP_DIRECTORY", &directory);
+        env::set_var("APP_AUTOINDEX", "true");
+        env::set_var("APP_AUTOINDEX_HIDDEN", "true");
+        let request = |method: &str, target: &str, body: Option<&str>| {
+            let (request, headers, body) = get_inputs(method, target, None, body);
+            handle_http_request(&request, &headers, &body).unwrap()
+        };
+
+        assert_eq!(get_status(&request("GET", "/files/.env", None)), "403");
+        assert_eq!(get_status(&request("GET", "/files/.git/config", None)), "403");
+        assert_eq!(get_status(&request("PUT", "/files/.htaccess", Some("x"))), "403");
+        assert!(!directory.join(".htaccess").exists());
+        let listing = request("GET", "/files/", None);
+        assert!(get_body(&listing).contains("index.txt"));
+        assert!(!get_body(&listing).contains(".env"));
+
+        env::set_var("APP_ALLOW_HIDDEN", "true");
+        assert_eq!(get_body(&request("GET", "/files/.env", None)), "SECRET=1");
+        assert!(get_body(&request("GET", "/files/", None)).contains(".env"));
+
+        env::remove_var("APP_ALLOW_HIDDEN");
+        env::remove_var("APP_AUTOINDEX");
+        env::remove_var("APP_AUTOINDEX_HIDDEN");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn handle_http_request_serves_index_files() {
+        let _env = utils::env_lock();
+        let directory = utils::temp_dir("index-files");
+        fs::create_dir(directory.join("docs")).unwrap();
+        fs::write(directory.join("docs").join("index.html"), "<h1>docs</h1>").unwrap();
+        fs::write(directory.join("docs").join("home.htm"), "home").unwrap();
+        env::set_var("APP_DIRECTORY", &directory);
+
+        let (request, headers, body) = get_inputs("GET", "/files/docs/", None, None);
+        let response = handle_http_request(&request, &headers, &body).unwrap();
+        assert_eq!(get_status(&response), "200");
+        assert_eq!(get_body(&response), "<h1>docs</h1>");
+        assert_eq!(get_header_value(&response, "Content-Type").unwrap(), "text/html; charset=utf-8");
+
+        env::set_var("APP_INDEX_FILES", "home.htm,index.html");
+        let response = handle_http_request(&request, &headers, &body).unwrap();
+        env::remove_var("APP_INDEX_FILES");
+        assert_eq!(get_body(&response), "home");
+
+        let (request, headers, body) = get_inputs("GET", "/files/docs?x=1", None, None);
+        let response = handle_http_request(&request, &headers, &body).unwrap();
+        assert_eq!(get_status(&response), "301");
+        assert_eq!(get_header_value(&response, "Location").unwrap(), "/files/docs/?x=1");
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn handle_http_request_spa_fallback() {
+        let _env = utils::env_lock();
+        let directory = utils::temp_dir("spa");
+        fs::create_dir(directory.join("app")).unwrap();
+        fs::write(directory.join("app").join("index.html"), "spa").unwrap();
+        env::set_var("APP_DIRECTORY", &directory);
+
+        let get = |route: &str| {
+            let (request, headers, body) = get_inputs("GET", route, None, None);
+            handle_http_request(&request, &headers, &body).unwrap()
+        };
+
+        assert_eq!(get_status(&get("/files/app/users/42")), "404");
+
+        env::set_var("APP_SPA_FALLBACK", "app/index.html");
+        let response = get("/files/app/users/42");
+        let missing_asset = get("/files/app/missing.js");
+        let outside_mount = get("/files/other/route");
+        env::remove_var("APP_SPA_FALLBACK");
+
+        assert_eq!(get_status(&response), "200");
+        assert_eq!(get_body(&response), "spa");
+        assert_eq!(get_status(&missing_asset), "404");
+        assert_eq!(get_status(&outside_mount), "404");
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn handle_read_file_serves_ranges() {
+        let _env = utils::env_lock();
+        let directory = utils::temp_dir("ranges");
+        let file_path = directory.join("video.mp4");
+        fs::write(&file_path, "0123456789").unwrap();
+        fs::write(directory.join("video.mp4.gz"), "gzipped").unwrap();
+        let mime_types = MimeTypes::default();
+        let read = |headers: Vec<&str>| {
+            let headers: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+            handle_read_file(&file_path, &directory, &headers, &mime_types, ETagMode::Metadata)
+                .response()
+        };
+
+        let response = read(vec![]);
+        assert_eq!(get_header_value(&response, "Accept-Ranges").unwrap(), "bytes");
+        let last_modified = get_header_value(&response, "Last-Modified").unwrap();
+
+        // Ranges skip the precompressed sidecar
+        let response = read(vec!["Range: bytes=-4", "Accept-Encoding: gzip"]);
+        assert_eq!(get_status(&response), "206");
+        assert_eq!(get_body(&response), "6789");
+        assert_eq!(get_header_value(&response, "Content-Range").unwrap(), "bytes 6-9/10");
+        assert_eq!(get_header_value(&response, "Content-Type").unwrap(), "video/mp4");
+
+        let response = read(vec!["Range: bytes=20-"]);
+        assert_eq!(get_status(&response), "416");
+
+        // Several ranges are read from the file one by one, overlapping ones merged
+        let response = read(vec!["Range: bytes=8-,0-1,1-2"]);
+        assert_eq!(get_status(&response), "206");
+        let body = std::str::from_utf8(&response).unwrap();
+        assert!(body.contains("Content-Range: bytes 0-2/10\r\n\r\n012\r\n"));
+        assert!(body.contains("Content-Range: bytes 8-9/10\r\n\r\n89\r\n"));
+        assert_eq!(body.matches("Content-Range").count(), 2);
+
+        let if_range = format!("If-Range: {}", last_modified);
+        let response = read(vec!["Range: bytes=0-1", &if_range]);
+        assert_eq!(get_body(&response), "01");
+
+        // A changed file invalidates the range, so the whole file is sent
+        let response = read(vec!["Range: bytes=0-1", "If-Range: Thu, 01 Jan 1970 00:00:00 GMT"]);
+        assert_eq!(get_status(&response), "200");
+        assert_eq!(get_body(&response), "0123456789");
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn handle_read_file_streams_uncompressed_files() {
+        let _env = utils::env_lock();
+        let directory = utils::temp_dir("file-response");
+        fs::write(directory.join("logo.png"), b"\x89PNG\r\n\x1a\n0123456789").unwrap();
+        fs::write(directory.join("notes.txt"), "some notes").unwrap();
+        let mime_types = MimeTypes::default();
+        let read = |name: &str, headers: Vec<&str>| {
+            let headers: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+            let path = directory.join(name);
+            handle_read_file(&path, &directory, &headers, &mime_types, ETagMode::Metadata)
+        };
+        let is_file = |response: &dyn HttpResponse| response.as_any().is::<FileResponse>();
+
+        let response = read("logo.png", vec!["Accept-Encoding: gzip"]);
+        assert!(is_file(response.as_ref()));
+        let response = response.response();
+        assert_eq!(get_content_length(&response), 18);
+        assert!(response.ends_with(b"\x1a\n0123456789"));
+        assert_eq!(get_header_value(&response, "Vary"), None);
+
+        let response = read("logo.png", vec!["Range: bytes=8-9"]);
+        assert!(is_file(response.as_ref()));
+        let response = response.response();
+        assert_eq!(get_status(&response), "206");
+        assert_eq!(get_header_value(&response, "Content-Range").unwrap(), "bytes 8-9/18");
+        assert_eq!(get_body(&response), "01");
+
+        // Text is compressed from the file when the client takes a compressed copy
+        let response = read("notes.txt", vec!["Accept-Encoding: gzip"]);
+        assert!(response.as_any().is::<OKResponse>());
+        let response = read("notes.txt", vec![]);
+        assert!(is_file(response.as_ref()));
+        let head = interface::HeadResponse::new(response).response();
+        assert_eq!(get_content_length(&head), 10);
+        assert_eq!(get_header_value(&head, "Vary").unwrap(), "Accept-Encoding");
+        assert!(head.ends_with(b"\r\n\r\n"));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn handle_http_request_frames_compressed_files_by_version() {
+        let _env = utils::env_lock();
+        let directory = utils::temp_dir("compressed-framing");
+        env::set_var("APP_DIRECTORY", &directory);
+        let text = "compress me ".repeat(100);
+        fs::write(directory.join("notes.txt"), &text).unwrap();
+        let request = |method: &str, version: &str| {
+            let request_line = format!("{} /files/notes.txt {}", method, version);
+            let headers = vec!["Accept-Encoding: gzip".to_string()];
+            handle_http_request(&request_line, &headers, &[]).unwrap()
+        };
+
+        let response = request("GET", "HTTP/1.1");
+        assert_eq!(get_header_value(&response, "Transfer-Encoding").unwrap(), "chunked");
+
+        // HTTP/1.0 clients do not know chunks, so the length comes first
+        let response = request("GET", "HTTP/1.0");
+        assert!(get_header_value(&response, "Transfer-Encoding").is_none());
+        assert_eq!(get_header_value(&response, "Content-Encoding").unwrap(), "gzip");
+        let body = &response[response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4..];
+        assert_eq!(get_content_length(&response), body.len());
+        let mut decoded = String::new();
+        Encoding::Gzip.decoder(body).unwrap().read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, text);
+
+        let response = request("HEAD", "HTTP/1.1");
+        assert_eq!(get_header_value(&response, "Content-Encoding").unwrap(), "gzip");
+        assert!(response.ends_with(b"\r\n\r\n"));
+
+        env::remove_var("APP_DIRECTORY");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn handle_http_request_serves_cached_files() {
+        let _env = utils::env_lock();
+        let directory = utils::temp_dir("file-cache");
+        env::set_var("APP_DIRECTORY", &directory);
+        env::set_var("APP_CACHE_SIZE", "4096");
+        let contents = "cached ".repeat(100);
+        fs::write(directory.join("page.txt"), &contents).unwrap();
+        let request = |method: &str, headers: Vec<&str>, body: Option<&str>| {
+            let (request, headers, body) = get_inputs(method, "/files/page.txt", Some(headers), body);
+            handle_http_request(&request, &headers, &body).unwrap()
+        };
+        let before = cache::global().stats();
+
+        let response = request("GET", vec![], None);
+        assert_eq!(get_body(&response), contents);
+        let etag = get_header_value(&response, "ETag").unwrap();
+        let response = request("GET", vec!["Range: bytes=0-5"], None);
+        assert_eq!(get_body(&response), "cached");
+
+        let response = request("GET", vec!["Accept-Encoding: gzip"], None);
+        assert_eq!(get_header_value(&response, "Content-Encoding").unwrap(), "gzip");
+        assert_eq!(get_header_value(&response, "ETag").unwrap(), format!("W/{}", etag));
+        let body = &response[response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4..];
+        let mut decoded = String::new();
+        Encoding::Gzip.decoder(body).unwrap().read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, contents);
+
+        let after = cache::global().stats();
+        assert_eq!((after.hits - before.hits, after.misses - before.misses), (2, 1));
+
+        // Writes through the server drop the cached copy
+        let response = request("PATCH", vec![], Some("CACHED"));
+        assert_eq!(get_status(&response), "204");
+        let response = request("GET", vec![], None);
+        assert_eq!(get_body(&response), format!("{}CACHED", contents));
+
+        let (request, headers, body) = get_inputs("GET", "/stats/cache", None, None);
+        let response = handle_http_request(&request, &headers, &body).unwrap();
+        assert_eq!(get_header_value(&response, "Content-Type").unwrap(), "application/json");
+        assert!(get_body(&response).contains("\"hits\":"));
+
+        env::remove_var("APP_CACHE_SIZE");
+        let (request, headers, body) = get_inputs("GET", "/stats/cache", None, None);
+        let response = handle_http_request(&request, &headers, &body).unwrap();
+        assert_eq!(get_status(&response), "404");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn handle_read_file_evaluates_preconditions() {
+        let _env = utils::env_lock();
+        let directory = utils::temp_dir("conditional");
+        let file_path = directory.join("style.css");
+        fs::write(&file_path, "body {}").unwrap();
+        let mime_types = MimeTypes::default();
+        let read = |headers: Vec<String>| {
+            handle_read_file(&file_path, &directory, &headers, &mime_types, ETagMode::Content)
+                .response()
+        };
+
+        let response = read(vec![]);
+        let etag = get_header_value(&response, "ETag").unwrap();
+        let last_modified = get_header_value(&response, "Last-Modified").unwrap();
+        assert!(etag.starts_with('"'));
+
+        let response = read(vec![format!("If-None-Match: {}", etag)]);
+        assert_eq!(get_status(&response), "304");
+        assert_eq!(get_header_value(&response, "ETag").unwrap(), etag);
+        assert_eq!(get_body(&response), "");
+
+        let response = read(vec![format!("If-Modified-Since: {}", last_modified)]);
+        assert_eq!(get_status(&response), "304");
+
+        let response = read(vec!["If-Match: \"other\"".to_string()]);
+        assert_eq!(get_status(&response), "412");
+
+        let response = read(vec![
+            "If-Unmodified-Since: Thu, 01 Jan 1970 00:00:00 GMT".to_string(),
+        ]);
+        assert_eq!(get_status(&response), "412");
+
+        fs::write(&file_path, "body { margin: 0 }").unwrap();
+        let response = read(vec![format!("If-None-Match: {}", etag)]);
+        assert_eq!(get_status(&response), "200");
+        assert_ne!(get_header_value(&response, "ETag").unwrap(), etag);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn handle_write_file_honors_preconditions() {
+        let directory = utils::temp_dir("conditional-upload");
+        let file_path = directory.join("notes.txt");
+        let content = b"v1";
+        let write = |method: &str, headers: Vec<String>, content: &[u8]| {
+            handle_write_file(
+                &file_path,
+                "/files/my notes.txt",
+                method,
+                &headers,
+                content,
+                ETagMode::Metadata,
+                &Uploads::default(),
+            )
+            .response()
+        };
+
+        let create_only = || vec!["If-None-Match: *".to_string()];
+        let response = write("PUT", create_only(), content);
+        assert_eq!(get_status(&response), "201");
+        assert_eq!(
+            get_header_value(&response, "Location").unwrap(),
+            "/files/my%20notes.txt"
+        );
+        let etag = get_header_value(&response, "ETag").unwrap();
+
+        let response = write("PUT", create_only(), b"v2");
+        assert_eq!(get_status(&response), "412");
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "v1");
+
+        let response = write("POST", vec![format!("If-Match: {}", etag)], b"v2");
+        assert_eq!(get_status(&response), "204");
+        let new_etag = get_header_value(&response, "ETag").unwrap();
+        assert_ne!(new_etag, etag);
+
+        // A client still holding the first version loses the race
+        let response = write("PUT", vec![format!("If-Match: {}", etag)], b"v3");
+        assert_eq!(get_status(&response), "412");
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "v2");
+
+        let response = write("PUT", vec![], b"v3");
+        assert_eq!(get_status(&response), "204");
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "v3");
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn handle_http_request_file_methods() {
+        let _env = utils::env_lock();
+        let directory = utils::temp_dir("file-methods");
+        env::set_var("APP_DIRECTORY", &directory);
+        let request = |method: &str, target: &str, body: Option<&str>| {
+            let (request, headers, body) = get_inputs(method, target, None, body);
+            handle_http_request(&request, &headers, &body).unwrap()
+        };
+        let read = || fs::read_to_string(directory.join("log.txt")).unwrap();
+
+        assert_eq!(get_status(&request("PUT", "/files/log.txt", Some("hello"))), "201");
+        assert_eq!(get_status(&request("PUT", "/files/log.txt", Some("Hello"))), "204");
+        assert_eq!(read(), "Hello");
+
+        assert_eq!(get_status(&request("PATCH", "/files/log.txt", Some(" world"))), "204");
+        assert_eq!(read(), "Hello world");
+        let response = request("PATCH", "/files/log.txt?offset=6", Some("there"));
+        assert_eq!(get_status(&response), "204");
+        assert_eq!(read(), "Hello there");
+        let response = request("PATCH", "/files/log.txt?offset=12", Some("!"));
+        assert_eq!(get_status(&response), "416");
+        assert_eq!(get_status(&request("PATCH", "/files/missing.txt", Some("!"))), "404");
+
+        let response = request("HEAD", "/files/log.txt", None);
+        assert_eq!(get_status(&response), "200");
+        assert_eq!(get_content_length(&response), 11);
+        assert!(response.ends_with(b"\r\n\r\n"));
+
+        let response = request("DELETE", "/files/", None);
+        assert_eq!(get_status(&response), "405");
+        assert_eq!(get_header_value(&response, "Allow").unwrap(), "GET, HEAD");
+        let response = request("OPTIONS", "/files/log.txt", None);
+        assert_eq!(get_status(&response), "405");
+        assert_eq!(
+            get_header_value(&response, "Allow").unwrap(),
+            "GET, HEAD, POST, PUT, PATCH, DELETE, MKCOL"
+        );
+
+        assert_eq!(get_status(&request("DELETE", "/files/log.txt", None)), "204");
+        assert!(!directory.join("log.txt").exists());
+        assert_eq!(get_status(&request("DELETE", "/files/log.txt", None)), "404");
+        assert_eq!(get_status(&request("DELETE", "/files/../Cargo.toml", None)), "403");
+
+        // Writes through a link update its target and keep the link
+        #[cfg(unix)]
+        {
+            fs::write(directory.join("current.txt"), "v1").unwrap();
+            std::os::unix::fs::symlink("current.txt", directory.join("latest.txt")).unwrap();
+            assert_eq!(get_status(&request("PUT", "/files/latest.txt", Some("v2"))), "204");
+            assert_eq!(fs::read_to_string(directory.join("current.txt")).unwrap(), "v2");
+            let link = fs::symlink_metadata(directory.join("latest.txt")).unwrap();
+            assert!(link.file_type().is_symlink());
+        }
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn handle_http_request_creates_directories() {
+        let _env = utils::env_lock();
+        let directory = utils::temp_dir("mkdir");
+        env::set_var("APP_DIRECTORY", &directory);
+        let request = |method: &str, target: &str, body: Option<&str>| {
+            let (request, headers, body) = get_inputs(method, target, None, body);
+            handle_http_request(&request, &headers, &body).unwrap()
+        };
+
+        env::remove_var("APP_CREATE_PARENTS");
+        assert_eq!(get_status(&request("POST", "/files/a/b/c.txt", Some("abc"))), "409");
+        assert_eq!(get_status(&request("MKCOL", "/files/a/b", None)), "409");
+        assert!(!directory.join("a").exists());
+
+        let response = request("MKCOL", "/files/a", None);
+        assert_eq!(get_status(&response), "201");
+        assert_eq!(get_header_value(&response, "Location").unwrap(), "/files/a/");
+        assert!(directory.join("a").is_dir());
+        assert_eq!(get_status(&request("MKCOL", "/files/a/", None)), "405");
+        assert_eq!(get_status(&request("MKCOL", "/files/x", Some("body"))), "400");
+
+        env::set_var("APP_CREATE_PARENTS", "true");
+        assert_eq!(get_status(&request("POST", "/files/a/b/c.txt", Some("abc"))), "201");
+        assert_eq!(fs::read_to_string(directory.join("a/b/c.txt")).unwrap(), "abc");
+        assert_eq!(get_status(&request("MKCOL", "/files/d/e%20f", None)), "201");
+        assert!(directory.join("d/e f").is_dir());
+
+        // Still confined to the served directory
+        let response = request("POST", "/files/a/../../escape/c.txt", Some("abc"));
+        assert_eq!(get_status(&response), "403");
+        assert!(!directory.parent().unwrap().join("escape").exists());
+        // A file is in the way
+        assert_eq!(get_status(&request("POST", "/files/a/b/c.txt/d.txt", Some("x"))), "409");
+
+        env::remove_var("APP_CREATE_PARENTS");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn handle_http_request_form_upload() {
+        let _env = utils::env_lock();
+        let directory = utils::temp_dir("form-upload");
+        fs::create_dir(directory.join("photos")).unwrap();
+        env::set_var("APP_DIRECTORY", &directory);
+
+        let content_type = "Content-Type: multipart/form-data; boundary=XyZ";
+        let body = b"--XyZ\r\nContent-Disposition: form-data; name=\"caption\"\r\n\r\nBeach\r\n\
+                    --XyZ\r\nContent-Disposition: form-data; name=\"photo\"; filename=\"beach.jpg\"\r\n\
+                    Content-Type: image/jpeg\r\n\r\n\xff\xd8\xff\r\n--XyZ--\r\n";
+        let upload = |target: &str, headers: Vec<&str>| {
+            let (request, headers, _) = get_inputs("POST", target, Some(headers), None);
+            handle_http_request(&request, &headers, body).unwrap()
+        };
+
+        let response = upload("/files/photos", vec![content_type]);
+        assert_eq!(get_status(&response), "200");
+        assert_eq!(
+            get_body(&response),
+            "{\"files\":[{\"field\":\"photo\",\"name\":\"beach.jpg\",\
+             \"path\":\"/files/photos/beach.jpg\",\"size\":3}],\
+             \"fields\":[{\"name\":\"caption\",\"value\":\"Beach\"}]}"
+        );
+        assert_eq!(
+            fs::read(directory.join("photos/beach.jpg")).unwrap(),
+            b"\xff\xd8\xff"
+        );
+
+        // Files can only be saved into a directory
+        let response = upload("/files/photos/beach.jpg", vec![content_type]);
+        assert_eq!(get_status(&response), "409");
+        let response = upload("/files/photos", vec!["Content-Type: multipart/form-data"]);
+        assert_eq!(get_status(&response), "400");
+
+        env::set_var("APP_MAX_PART_SIZE", "4");
+        let response = upload("/files/", vec![content_type]);
+        assert_eq!(get_status(&response), "413");
+        assert!(!directory.join("beach.jpg").exists());
+        env::remove_var("APP_MAX_PART_SIZE");
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn handle_http_request_weakens_etag_when_compressing() {
+        let _env = utils::env_lock();
+        let directory = utils::temp_dir("weak-etag");
+        fs::write(directory.join("app.js"), "console.log('hi')").unwrap();
+        env::set_var("APP_DIRECTORY", &directory);
+
+        let (request, headers, body) = get_inputs("GET", "/files/app.js", None, None);
+        let response = handle_http_request(&request, &headers, &body).unwrap();
+        let etag = get_header_value(&response, "ETag").unwrap();
+
+        let (request, headers, body) = get_inputs(
+            "GET",
+            "/files/app.js",
+            Some(vec!["Accept-Encoding: gzip"]),
+            None
+        );
+        let response = handle_http_request(&request, &headers, &body).unwrap();
+        let weak_etag = get_header_value(&response, "ETag").unwrap();
+        assert_eq!(weak_etag, format!("W/{}", etag));
+
+        // The weak tag still revalidates the compressed response
+        let if_none_match = format!("If-None-Match: {}", weak_etag);
+        let (request, headers, body) = get_inputs(
+            "GET",
+            "/files/app.js",
+            Some(vec!["Accept-Encoding: gzip", &if_none_match]),
+            None
+        );
+        let response = handle_http_request(&request, &headers, &body).unwrap();
+        assert_eq!(get_status(&response), "304");
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
     #[test]
     fn handle_http_request_invalid_request() {
         let (_request, headers, body) = get_inputs("GET", "/", None, None);
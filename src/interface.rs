@@ -1,7 +1,13 @@
 use std::collections::HashMap;
 use std::any::Any;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::Arc;
 
-use crate::utils::gzip_compress;
+use crate::config::CompressionLevels;
+use crate::encoding::{Encoding, SUPPORTED_ENCODINGS};
+use crate::sendfile::{self, Socket};
 
 
 # [derive(Clone)]
@@ -31,42 +37,195 @@ impl HttpHeaders {
         self
     }
 
-    pub fn to_string(&self) -> String {
-        self.headers
+    pub fn with_header<B: Into<String>>(mut self, name: &str, value: B) -> Self {
+        self.headers.insert(name.to_string(), value.into());
+        self
+    }
+
+    /// Adds `field` to the `Vary` header, keeping any fields already listed.
+    pub fn with_vary(mut self, field: &str) -> Self {
+        let vary = match self.headers.get("Vary") {
+            Some(vary) if vary.split(',').any(|f| f.trim().eq_ignore_ascii_case(field)) => {
+                return self;
+            }
+            Some(vary) => format!("{}, {}", vary, field),
+            None => field.to_string(),
+        };
+        self.headers.insert("Vary".to_string(), vary);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(|value| value.as_str())
+    }
+}
+
+impl fmt::Display for HttpHeaders {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let headers = self
+            .headers
             .iter()
             .map(|(k, v)| format!("{}: {}", k, v))
             .collect::<Vec<String>>()
-            .join("\r\n")
+            .join("\r\n");
+        write!(f, "{}", headers)
     }
 }
 
 
+/// Status line and headers of a bodiless response, which may have no headers at all.
+fn status_with_headers(status: &str, headers: &HttpHeaders) -> String {
+    if headers.headers.is_empty() {
+        format!("HTTP/1.1 {}\r\n\r\n", status)
+    } else {
+        format!("HTTP/1.1 {}\r\n{}\r\n\r\n", status, headers)
+    }
+}
+
 pub trait HttpResponse: Any {
     fn response(&self) -> Vec<u8>;
 
+    /// Writes the response to the connection. Streaming responses override this to keep
+    /// pushing body data after the headers; everything else is sent in one go.
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(&self.response())?;
+        writer.flush()
+    }
+
     fn as_any(&self) -> &dyn Any;
 }
 
+/// Size of the pieces a streamed body is encoded and sent in.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Frames everything written through it as `Transfer-Encoding: chunked` data.
+/// The terminating zero-length chunk is left to the caller.
+struct ChunkedWriter<W: Write>(W);
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.0, "{:X}\r\n", buf.len())?;
+        self.0.write_all(buf)?;
+        self.0.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// What an `OKResponse` sends: bytes in memory, shared so that cached files and
+/// clones go out without copying them, or a file read as the body is written out.
+#[derive(Clone)]
+enum Body {
+    Bytes(Arc<Vec<u8>>),
+    File(Arc<File>, u64),
+}
+
+impl Body {
+    fn len(&self) -> u64 {
+        match self {
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::File(_, length) => *length,
+        }
+    }
+
+    fn copy_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        match self {
+            Body::Bytes(bytes) => writer.write_all(bytes),
+            Body::File(file, length) => sendfile::copy(file, 0, *length, writer),
+        }
+    }
+
+    /// Copies `length` bytes from `offset` on, failing if the body ends before that.
+    fn copy_range_to(&self, offset: u64, length: u64, writer: &mut dyn Write) -> io::Result<()> {
+        match self {
+            Body::Bytes(bytes) => {
+                let range = usize::try_from(offset)
+                    .ok()
+                    .zip(usize::try_from(offset + length).ok())
+                    .and_then(|(start, end)| bytes.get(start..end));
+                match range {
+                    Some(range) => writer.write_all(range),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "body is shorter than the range",
+                    )),
+                }
+            }
+            Body::File(file, _) => sendfile::copy(file, offset, length, writer),
+        }
+    }
+}
+
 # [derive(Clone)]
 pub struct OKResponse {
     headers: HttpHeaders,
-    body: Vec<u8>,
+    body: Body,
+    // Content coding applied while the body is written out
+    encoding: Option<(Encoding, CompressionLevels)>,
+    // Whether an encoded body may be sent as chunks while it is compressed
+    chunked: bool,
+}
+
+impl OKResponse {
+    /// Streams the body through the encoder, sending the output as chunks so the
+    /// compressed body never has to be held in memory. Without chunks, it is
+    /// compressed in full first to learn its length.
+    fn write_encoded(
+        &self,
+        writer: &mut dyn Write,
+        encoding: Encoding,
+        levels: &CompressionLevels,
+    ) -> io::Result<()> {
+        if !self.chunked {
+            let mut body = Vec::new();
+            let mut encoder = encoding.encoder(&mut body, levels)?;
+            self.body.copy_to(&mut encoder)?;
+            encoder.finish()?;
+            let headers = self.headers.clone().with_content_length(body.len().to_string());
+            write!(writer, "HTTP/1.1 200 OK\r\n{}\r\n\r\n", headers)?;
+            writer.write_all(&body)?;
+            return writer.flush();
+        }
+
+        writer.write_all(self.head().as_bytes())?;
+        let mut chunked = BufWriter::with_capacity(CHUNK_SIZE, ChunkedWriter(&mut *writer));
+        let mut encoder = encoding.encoder(&mut chunked, levels)?;
+        self.body.copy_to(&mut encoder)?;
+        encoder.finish()?;
+        chunked.flush()?;
+        drop(chunked);
+
+        writer.write_all(b"0\r\n\r\n")?;
+        writer.flush()
+    }
 }
 
 impl HttpResponse for OKResponse {
     fn response(&self) -> Vec<u8> {
-        let content_length = self.body.len();
-        let self_headers = self.headers.clone();
-        let headers = self_headers.with_content_length(content_length.to_string());
-        let mut response = format!(
-            "HTTP/1.1 200 OK\r\n{}\r\n\r\n",
-            headers.to_string(),
-        ).into_bytes();
-
-        response.extend(&self.body);
+        let mut response = Vec::new();
+        if let Err(e) = self.write_to(&mut response) {
+            println!("Error writing response: {}", e);
+        }
         response
     }
 
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        match &self.encoding {
+            Some((encoding, levels)) => self.write_encoded(writer, *encoding, levels),
+            None => {
+                writer.write_all(self.head().as_bytes())?;
+                self.body.copy_to(writer)?;
+                writer.flush()
+            }
+        }
+    }
+
     fn as_any(&self) -> &dyn Any {
         self
     }
@@ -74,15 +233,57 @@ impl HttpResponse for OKResponse {
 
 impl OKResponse {
     pub fn new<B: Into<String>>(body: B) -> Self {
+        Self::from_bytes(body.into().into_bytes())
+    }
+
+    pub fn from_bytes(body: Vec<u8>) -> Self {
+        Self::shared(Arc::new(body))
+    }
+
+    /// Serves `body` without copying it, e.g. straight from the file cache.
+    pub fn shared(body: Arc<Vec<u8>>) -> Self {
+        Self::with_body(Body::Bytes(body))
+    }
+
+    /// Serves `file`, which is `length` bytes long, reading it only as the body is
+    /// written out, so that compressing it does not need it in memory.
+    pub fn from_file(file: File, length: u64) -> Self {
+        Self::with_body(Body::File(Arc::new(file), length))
+    }
+
+    fn with_body(body: Body) -> Self {
         Self {
             headers: HttpHeaders::new().with_content_type("text/plain"),
-            body: body.into().into_bytes(),
+            body,
+            encoding: None,
+            chunked: true,
+        }
+    }
+
+    /// Marks the body as already encoded, e.g. when serving a precompressed file.
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.headers = self.headers.with_encoding(encoding.name());
+        self
+    }
+
+    /// Compresses the body with `encoding` as it is written out.
+    pub fn encode(mut self, encoding: Encoding, levels: &CompressionLevels) -> Self {
+        if encoding != Encoding::Identity {
+            self.headers = self.headers.with_encoding(encoding.name());
+            self.encoding = Some((encoding, levels.clone()));
+            // The compressed bytes differ from the ones a strong ETag vouches for
+            let strong_etag = self.headers.get("ETag").filter(|etag| !etag.starts_with("W/"));
+            if let Some(weak) = strong_etag.map(|etag| format!("W/{}", etag)) {
+                self.headers = self.headers.with_header("ETag", weak);
+            }
         }
+        self
     }
 
-    pub fn compress(mut self) -> Self {
-        self.headers = self.headers.with_encoding("gzip");
-        self.body = gzip_compress(&self.body);
+    /// For clients without chunked transfer coding, i.e. anything before HTTP/1.1:
+    /// an encoded body is then sent with `Content-Length`.
+    pub fn without_chunks(mut self) -> Self {
+        self.chunked = false;
         self
     }
 
@@ -90,13 +291,243 @@ impl OKResponse {
         self.headers = self.headers.with_content_type(content_type);
         self
     }
+
+    pub fn with_vary(mut self, field: &str) -> Self {
+        self.headers = self.headers.with_vary(field);
+        self
+    }
+
+    pub fn with_header<B: Into<String>>(mut self, name: &str, value: B) -> Self {
+        self.headers = self.headers.with_header(name, value);
+        self
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get("Content-Type")
+    }
+
+    pub fn content_encoding(&self) -> Option<&str> {
+        self.headers.get("Content-Encoding")
+    }
+
+    pub fn body_len(&self) -> usize {
+        self.body.len() as usize
+    }
+
+    /// Status line and headers. The length of a body encoded on the fly is only
+    /// known once it has been compressed, so it is left out.
+    pub fn head(&self) -> String {
+        let headers = match (&self.encoding, self.chunked) {
+            (None, _) => self.headers.clone().with_content_length(self.body.len().to_string()),
+            (Some(_), true) => self.headers.clone().with_header("Transfer-Encoding", "chunked"),
+            (Some(_), false) => self.headers.clone(),
+        };
+        format!("HTTP/1.1 200 OK\r\n{}\r\n\r\n", headers)
+    }
+}
+
+/// One range of a `206` body, sent after `head`: nothing for a lone range, or the
+/// delimiter and headers of its part in `multipart/byteranges`.
+struct Part {
+    head: String,
+    offset: u64,
+    length: u64,
+}
+
+/// `206 Partial Content`, carrying one range of a file or a
+/// `multipart/byteranges` body. The ranges are read only as they are sent, so a
+/// response costs no more memory than its part headers.
+pub struct PartialContentResponse {
+    headers: HttpHeaders,
+    body: Body,
+    parts: Vec<Part>,
+    trailer: String,
+}
+
+impl HttpResponse for PartialContentResponse {
+    fn response(&self) -> Vec<u8> {
+        let mut response = Vec::new();
+        if let Err(e) = self.write_to(&mut response) {
+            println!("Error reading file for response: {}", e);
+        }
+        response
+    }
+
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(self.head().as_bytes())?;
+        for part in &self.parts {
+            writer.write_all(part.head.as_bytes())?;
+            self.body.copy_range_to(part.offset, part.length, writer)?;
+        }
+        writer.write_all(self.trailer.as_bytes())?;
+        writer.flush()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl PartialContentResponse {
+    /// Ranges of `body`, which is already in memory, e.g. in the file cache.
+    pub fn shared(body: Arc<Vec<u8>>) -> Self {
+        Self::with_body(Body::Bytes(body))
+    }
+
+    /// Ranges of `file`, which is `length` bytes long.
+    pub fn from_file(file: File, length: u64) -> Self {
+        Self::with_body(Body::File(Arc::new(file), length))
+    }
+
+    fn with_body(body: Body) -> Self {
+        Self {
+            headers: HttpHeaders::new(),
+            body,
+            parts: Vec::new(),
+            trailer: String::new(),
+        }
+    }
+
+    /// Sends `length` bytes of the body from `offset` on, preceded by `head`.
+    pub fn with_part(mut self, head: String, offset: u64, length: u64) -> Self {
+        self.parts.push(Part {
+            head,
+            offset,
+            length,
+        });
+        self
+    }
+
+    /// Sent after the last part, e.g. the closing multipart delimiter.
+    pub fn with_trailer(mut self, trailer: String) -> Self {
+        self.trailer = trailer;
+        self
+    }
+
+    pub fn with_header<B: Into<String>>(mut self, name: &str, value: B) -> Self {
+        self.headers = self.headers.with_header(name, value);
+        self
+    }
+
+    /// Status line and headers.
+    pub fn head(&self) -> String {
+        let length = self
+            .parts
+            .iter()
+            .map(|part| part.head.len() as u64 + part.length)
+            .sum::<u64>()
+            + self.trailer.len() as u64;
+        let headers = self.headers.clone().with_content_length(length.to_string());
+        format!("HTTP/1.1 206 Partial Content\r\n{}\r\n\r\n", headers)
+    }
+
+    /// Like `write_to`, but ranges of a file go out with `sendfile`.
+    pub fn send_to(&self, socket: &mut dyn Socket) -> io::Result<()> {
+        socket.write_all(self.head().as_bytes())?;
+        for part in &self.parts {
+            socket.write_all(part.head.as_bytes())?;
+            match &self.body {
+                Body::File(file, _) => sendfile::send_file(file, part.offset, part.length, socket)?,
+                body => body.copy_range_to(part.offset, part.length, socket)?,
+            }
+        }
+        socket.write_all(self.trailer.as_bytes())?;
+        socket.flush()
+    }
+}
+
+/// A file, or one range of it, sent as is. The server hands these to `send_to`,
+/// which lets the kernel copy the file to the socket instead of reading it into
+/// memory first.
+pub struct FileResponse {
+    status: &'static str,
+    headers: HttpHeaders,
+    file: File,
+    offset: u64,
+    length: u64,
+}
+
+impl HttpResponse for FileResponse {
+    fn response(&self) -> Vec<u8> {
+        let mut response = self.head().into_bytes();
+        if let Err(e) = sendfile::copy(&self.file, self.offset, self.length, &mut response) {
+            println!("Error reading file for response: {}", e);
+        }
+        response
+    }
+
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(self.head().as_bytes())?;
+        sendfile::copy(&self.file, self.offset, self.length, writer)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
-pub struct OKCreatedResponse;
+impl FileResponse {
+    /// The whole of `file`, which is `length` bytes long.
+    pub fn new(file: File, length: u64) -> Self {
+        Self {
+            status: "200 OK",
+            headers: HttpHeaders::new(),
+            file,
+            offset: 0,
+            length,
+        }
+    }
+
+    /// Bytes `start` to `end` inclusive of a file of `total` bytes, as a `206`.
+    pub fn partial(file: File, start: u64, end: u64, total: u64) -> Self {
+        Self {
+            status: "206 Partial Content",
+            headers: HttpHeaders::new()
+                .with_header("Content-Range", format!("bytes {}-{}/{}", start, end, total)),
+            file,
+            offset: start,
+            length: end - start + 1,
+        }
+    }
+
+    pub fn with_header<B: Into<String>>(mut self, name: &str, value: B) -> Self {
+        self.headers = self.headers.with_header(name, value);
+        self
+    }
+
+    /// Status line and headers.
+    pub fn head(&self) -> String {
+        let headers = self.headers.clone().with_content_length(self.length.to_string());
+        format!("HTTP/1.1 {}\r\n{}\r\n\r\n", self.status, headers)
+    }
+
+    pub fn send_to(&self, socket: &mut dyn Socket) -> io::Result<()> {
+        socket.write_all(self.head().as_bytes())?;
+        sendfile::send_file(&self.file, self.offset, self.length, socket)
+    }
+}
+
+pub struct ContinueResponse;
+
+impl HttpResponse for ContinueResponse {
+    fn response(&self) -> Vec<u8> {
+        "HTTP/1.1 100 Continue\r\n\r\n".to_string().into_bytes()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// `201 Created`, pointing at the new resource with `Location`.
+pub struct OKCreatedResponse {
+    headers: HttpHeaders,
+}
 
 impl HttpResponse for OKCreatedResponse {
     fn response(&self) -> Vec<u8> {
-        "HTTP/1.1 201 Created\r\n\r\n".to_string().into_bytes()
+        let headers = self.headers.clone().with_content_length("0");
+        format!("HTTP/1.1 201 Created\r\n{}\r\n\r\n", headers).into_bytes()
     }
 
     fn as_any(&self) -> &dyn Any {
@@ -104,11 +535,132 @@ impl HttpResponse for OKCreatedResponse {
     }
 }
 
+impl OKCreatedResponse {
+    pub fn new<B: Into<String>>(location: B) -> Self {
+        Self {
+            headers: HttpHeaders::new().with_header("Location", location),
+        }
+    }
+
+    pub fn with_header<B: Into<String>>(mut self, name: &str, value: B) -> Self {
+        self.headers = self.headers.with_header(name, value);
+        self
+    }
+}
+
+/// `204 No Content`, e.g. after replacing a file.
+pub struct NoContentResponse {
+    headers: HttpHeaders,
+}
+
+impl HttpResponse for NoContentResponse {
+    fn response(&self) -> Vec<u8> {
+        status_with_headers("204 No Content", &self.headers).into_bytes()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl NoContentResponse {
+    pub fn new(headers: Vec<(&str, String)>) -> Self {
+        let mut response = Self {
+            headers: HttpHeaders::new(),
+        };
+        for (name, value) in headers {
+            response.headers = response.headers.with_header(name, value);
+        }
+        response
+    }
+}
+
+/// Sends the client to the canonical URL of a resource, e.g. a directory
+/// requested without its trailing slash.
+pub struct MovedPermanentlyResponse {
+    pub location: String,
+}
+
+impl HttpResponse for MovedPermanentlyResponse {
+    fn response(&self) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 301 Moved Permanently\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n",
+            self.location
+        )
+        .into_bytes()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// `304 Not Modified`, repeating the validators the client should keep using.
+pub struct NotModifiedResponse {
+    headers: HttpHeaders,
+}
+
+impl HttpResponse for NotModifiedResponse {
+    fn response(&self) -> Vec<u8> {
+        status_with_headers("304 Not Modified", &self.headers).into_bytes()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl NotModifiedResponse {
+    pub fn new(validators: Vec<(&str, String)>) -> Self {
+        let mut headers = HttpHeaders::new();
+        for (name, value) in validators {
+            headers = headers.with_header(name, value);
+        }
+        Self { headers }
+    }
+}
+
+/// The answer to a `HEAD` request: the response a `GET` would have produced, with
+/// its headers intact but without the body.
+pub struct HeadResponse {
+    inner: Box<dyn HttpResponse>,
+}
+
+impl HttpResponse for HeadResponse {
+    fn response(&self) -> Vec<u8> {
+        // No need to read a file, or compress a body, only to drop it
+        if let Some(file) = self.inner.as_any().downcast_ref::<FileResponse>() {
+            return file.head().into_bytes();
+        }
+        if let Some(ok) = self.inner.as_any().downcast_ref::<OKResponse>() {
+            return ok.head().into_bytes();
+        }
+        if let Some(partial) = self.inner.as_any().downcast_ref::<PartialContentResponse>() {
+            return partial.head().into_bytes();
+        }
+        let mut response = self.inner.response();
+        if let Some(end) = response.windows(4).position(|window| window == b"\r\n\r\n") {
+            response.truncate(end + 4);
+        }
+        response
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl HeadResponse {
+    pub fn new(inner: Box<dyn HttpResponse>) -> Self {
+        Self { inner }
+    }
+}
+
 pub struct NotFoundResponse;
 
 impl HttpResponse for NotFoundResponse {
     fn response(&self) -> Vec<u8> {
-        "HTTP/1.1 404 Not Found\r\n\r\n".to_string().into_bytes()
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string().into_bytes()
     }
 
     fn as_any(&self) -> &dyn Any {
@@ -120,7 +672,7 @@ pub struct ForbiddenResponse;
 
 impl HttpResponse for ForbiddenResponse {
     fn response(&self) -> Vec<u8> {
-        "HTTP/1.1 403 Forbidden\r\n\r\n".to_string().into_bytes()
+        "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n".to_string().into_bytes()
     }
 
     fn as_any(&self) -> &dyn Any {
@@ -128,11 +680,210 @@ impl HttpResponse for ForbiddenResponse {
     }
 }
 
-pub struct MethodNotAllowedResponse;
+/// Lists the methods the resource does support in `Allow`.
+pub struct MethodNotAllowedResponse {
+    pub allow: &'static str,
+}
 
 impl HttpResponse for MethodNotAllowedResponse {
     fn response(&self) -> Vec<u8> {
-        "HTTP/1.1 405 Method Not Allowed\r\n\r\n"
+        format!(
+            "HTTP/1.1 405 Method Not Allowed\r\nAllow: {}\r\nContent-Length: 0\r\n\r\n",
+            self.allow
+        )
+        .into_bytes()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct BadRequestResponse;
+
+impl HttpResponse for BadRequestResponse {
+    fn response(&self) -> Vec<u8> {
+        "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n".to_string().into_bytes()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct NotAcceptableResponse;
+
+impl HttpResponse for NotAcceptableResponse {
+    fn response(&self) -> Vec<u8> {
+        "HTTP/1.1 406 Not Acceptable\r\nContent-Length: 0\r\n\r\n"
+            .to_string()
+            .into_bytes()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct RequestTimeoutResponse;
+
+impl HttpResponse for RequestTimeoutResponse {
+    fn response(&self) -> Vec<u8> {
+        "HTTP/1.1 408 Request Timeout\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            .to_string()
+            .into_bytes()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct PayloadTooLargeResponse;
+
+impl HttpResponse for PayloadTooLargeResponse {
+    fn response(&self) -> Vec<u8> {
+        "HTTP/1.1 413 Payload Too Large\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            .to_string()
+            .into_bytes()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Sent for request bodies in a transfer coding other than `chunked`.
+pub struct NotImplementedResponse;
+
+impl HttpResponse for NotImplementedResponse {
+    fn response(&self) -> Vec<u8> {
+        "HTTP/1.1 501 Not Implemented\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            .to_string()
+            .into_bytes()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Sent for request bodies in a `Content-Encoding` we cannot decode. Lists the
+/// codings we do accept, as RFC 9110 suggests.
+pub struct UnsupportedMediaTypeResponse;
+
+impl HttpResponse for UnsupportedMediaTypeResponse {
+    fn response(&self) -> Vec<u8> {
+        let accepted: Vec<&str> = SUPPORTED_ENCODINGS
+            .iter()
+            .filter(|encoding| **encoding != Encoding::Identity)
+            .map(|encoding| encoding.name())
+            .collect();
+        format!(
+            "HTTP/1.1 415 Unsupported Media Type\r\nContent-Length: 0\r\nAccept-Encoding: {}\r\nConnection: close\r\n\r\n",
+            accepted.join(", ")
+        )
+        .into_bytes()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct UriTooLongResponse;
+
+impl HttpResponse for UriTooLongResponse {
+    fn response(&self) -> Vec<u8> {
+        "HTTP/1.1 414 URI Too Long\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            .to_string()
+            .into_bytes()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct RequestHeaderFieldsTooLargeResponse;
+
+impl HttpResponse for RequestHeaderFieldsTooLargeResponse {
+    fn response(&self) -> Vec<u8> {
+        "HTTP/1.1 431 Request Header Fields Too Large\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            .to_string()
+            .into_bytes()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Sent when none of the requested ranges overlaps the file, which is `length`
+/// bytes long.
+pub struct RangeNotSatisfiableResponse {
+    pub length: u64,
+}
+
+impl HttpResponse for RangeNotSatisfiableResponse {
+    fn response(&self) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\n\r\n",
+            self.length
+        )
+        .into_bytes()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct PreconditionFailedResponse;
+
+impl HttpResponse for PreconditionFailedResponse {
+    fn response(&self) -> Vec<u8> {
+        "HTTP/1.1 412 Precondition Failed\r\nContent-Length: 0\r\n\r\n".to_string().into_bytes()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// The request conflicts with the state of the target, e.g. an upload into a
+/// directory that does not exist.
+pub struct ConflictResponse;
+
+impl HttpResponse for ConflictResponse {
+    fn response(&self) -> Vec<u8> {
+        "HTTP/1.1 409 Conflict\r\nContent-Length: 0\r\n\r\n".to_string().into_bytes()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct ExpectationFailedResponse;
+
+impl HttpResponse for ExpectationFailedResponse {
+    fn response(&self) -> Vec<u8> {
+        "HTTP/1.1 417 Expectation Failed\r\nContent-Length: 0\r\n\r\n"
+            .to_string()
+            .into_bytes()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct UpgradeRequiredResponse;
+
+impl HttpResponse for UpgradeRequiredResponse {
+    fn response(&self) -> Vec<u8> {
+        "HTTP/1.1 426 Upgrade Required\r\nContent-Length: 0\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\r\n"
             .to_string()
             .into_bytes()
     }
@@ -146,7 +897,7 @@ pub struct InternalServerErrorResponse;
 
 impl HttpResponse for InternalServerErrorResponse {
     fn response(&self) -> Vec<u8> {
-        "HTTP/1.1 500 Internal Server Error\r\n\r\n"
+        "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n"
             .to_string()
             .into_bytes()
     }
@@ -155,3 +906,114 @@ impl HttpResponse for InternalServerErrorResponse {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn split_response(response: &[u8]) -> (String, Vec<u8>) {
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(response[..end].to_vec()).unwrap();
+        (head, response[end + 4..].to_vec())
+    }
+
+    fn dechunk(mut body: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        loop {
+            let line_end = body.windows(2).position(|w| w == b"\r\n").unwrap();
+            let size_line = std::str::from_utf8(&body[..line_end]).unwrap();
+            let size = usize::from_str_radix(size_line, 16).unwrap();
+            body = &body[line_end + 2..];
+            if size == 0 {
+                assert_eq!(body, b"\r\n");
+                return data;
+            }
+            data.extend_from_slice(&body[..size]);
+            assert_eq!(&body[size..size + 2], b"\r\n");
+            body = &body[size + 2..];
+        }
+    }
+
+    #[test]
+    fn encoded_response_is_streamed_in_chunks() {
+        let text = "streaming compression ".repeat(5000);
+        let response = OKResponse::new(text.clone())
+            .encode(Encoding::Gzip, &CompressionLevels::default())
+            .response();
+
+        let (head, body) = split_response(&response);
+        assert!(head.contains("Transfer-Encoding: chunked"));
+        assert!(head.contains("Content-Encoding: gzip"));
+        assert!(!head.contains("Content-Length"));
+
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&dechunk(&body)[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+    }
+
+    #[test]
+    fn encoded_response_without_chunks_has_a_length() {
+        let text = "compressed in full ".repeat(1000);
+        let response = OKResponse::new(text.clone())
+            .encode(Encoding::Gzip, &CompressionLevels::default())
+            .without_chunks()
+            .response();
+
+        let (head, body) = split_response(&response);
+        assert!(!head.contains("Transfer-Encoding"));
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+    }
+
+    #[test]
+    fn encoded_file_is_read_as_it_is_sent() {
+        let path = crate::utils::temp_dir("encoded-file").join("page.html");
+        let text = "<p>streamed</p>".repeat(1000);
+        std::fs::write(&path, &text).unwrap();
+        let response = OKResponse::from_file(File::open(&path).unwrap(), text.len() as u64)
+            .encode(Encoding::Gzip, &CompressionLevels::default());
+
+        // HEAD gets the headers without the body being compressed
+        let head = HeadResponse::new(Box::new(response.clone())).response();
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(head.ends_with("\r\n\r\n"));
+        assert!(!head.contains("Content-Length"));
+
+        let (_, body) = split_response(&response.response());
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&dechunk(&body)[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn encoded_response_surfaces_write_errors() {
+        struct FailingWriter;
+
+        impl Write for FailingWriter {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let response = OKResponse::new("a".repeat(100_000))
+            .encode(Encoding::Gzip, &CompressionLevels::default());
+        let error = response.write_to(&mut FailingWriter).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
@@ -1,18 +1,201 @@
 use std::env;
 use std::net::TcpListener;
+use std::path::Path;
+use std::process;
 use std::thread;
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 
+mod autoindex;
+mod cache;
+mod conditional;
+mod config;
+mod encoding;
 mod handler;
 mod interface;
+mod mime;
+mod multipart;
+mod range;
+mod sendfile;
 mod server;
+mod sse;
 mod utils;
+mod watch;
+mod websocket;
+
+use config::{ETagMode, SymlinkPolicy};
 
 #[derive(Parser, Debug)]
 struct Args {
     #[clap(long)]
     directory: Option<String>,
+
+    /// Longest request line accepted (bytes)
+    #[clap(long)]
+    max_request_line: Option<usize>,
+
+    /// Most header lines accepted per request
+    #[clap(long)]
+    max_headers: Option<usize>,
+
+    /// Most header bytes accepted per request
+    #[clap(long)]
+    max_header_bytes: Option<usize>,
+
+    /// Largest request body accepted (bytes)
+    #[clap(long)]
+    max_body_size: Option<usize>,
+
+    /// Pass request bodies on without undoing their Content-Encoding
+    #[clap(long)]
+    no_decode_bodies: bool,
+
+    /// Largest request body accepted after decoding (bytes)
+    #[clap(long)]
+    max_decoded_body_size: Option<usize>,
+
+    /// Seconds allowed to receive a request line and headers
+    #[clap(long)]
+    header_timeout: Option<f64>,
+
+    /// Base seconds allowed to receive a request body
+    #[clap(long)]
+    body_timeout: Option<f64>,
+
+    /// Slowest accepted body upload rate (bytes per second)
+    #[clap(long)]
+    min_body_rate: Option<u64>,
+
+    /// Base seconds allowed to send a response
+    #[clap(long)]
+    write_timeout: Option<f64>,
+
+    /// Slowest accepted rate for a client to read a response (bytes per second)
+    #[clap(long)]
+    min_write_rate: Option<u64>,
+
+    /// Seconds an idle kept-alive connection stays open
+    #[clap(long)]
+    keep_alive_timeout: Option<f64>,
+
+    /// Seconds a WebSocket may go without a frame from the client
+    #[clap(long)]
+    websocket_idle_timeout: Option<f64>,
+
+    /// gzip compression level (0-9)
+    #[clap(long)]
+    gzip_level: Option<u32>,
+
+    /// deflate compression level (0-9)
+    #[clap(long)]
+    deflate_level: Option<u32>,
+
+    /// brotli compression level (0-11)
+    #[clap(long)]
+    brotli_level: Option<u32>,
+
+    /// zstd compression level (1-22)
+    #[clap(long)]
+    zstd_level: Option<i32>,
+
+    /// Smallest response body worth compressing (bytes)
+    #[clap(long)]
+    compress_min_size: Option<usize>,
+
+    /// Comma separated MIME types to compress, e.g. "text/*,application/json"
+    #[clap(long)]
+    compress_types: Option<String>,
+
+    /// Comma separated MIME types never to compress
+    #[clap(long)]
+    no_compress_types: Option<String>,
+
+    /// Comma separated extension=type pairs added to the MIME type table,
+    /// e.g. "md=text/plain,log=text/plain"
+    #[clap(long)]
+    mime_types: Option<String>,
+
+    /// Guess the type of files with an unknown extension from their contents
+    #[clap(long)]
+    sniff_mime_types: bool,
+
+    /// List the contents of directories requested under /files/
+    #[clap(long)]
+    autoindex: bool,
+
+    /// Include dotfiles in directory listings (needs --allow-hidden)
+    #[clap(long)]
+    autoindex_hidden: bool,
+
+    /// Comma separated file names served for directory requests (default "index.html")
+    #[clap(long)]
+    index_files: Option<String>,
+
+    /// How ETags of served files are generated
+    #[clap(long)]
+    etag: Option<ETagMode>,
+
+    /// File served for missing paths under its directory, for single-page apps,
+    /// e.g. "app/index.html"
+    #[clap(long)]
+    spa_fallback: Option<String>,
+
+    /// Sync the directory after each upload, making the rename that puts the new
+    /// file in place durable too
+    #[clap(long)]
+    fsync_directory: bool,
+
+    /// Create missing parent directories of uploaded files
+    #[clap(long)]
+    create_parents: bool,
+
+    /// Largest field or file in a multipart/form-data upload, in bytes
+    #[clap(long)]
+    max_part_size: Option<usize>,
+
+    /// Largest multipart/form-data upload across all its parts, in bytes
+    #[clap(long)]
+    max_form_size: Option<usize>,
+
+    /// Most fields and files in a multipart/form-data upload
+    #[clap(long)]
+    max_form_parts: Option<usize>,
+
+    /// Keep up to this many bytes of hot files and their compressed variants in
+    /// memory (0 disables the cache)
+    #[clap(long)]
+    cache_size: Option<usize>,
+
+    /// Largest file kept in the cache, in bytes (default: 1 MiB)
+    #[clap(long)]
+    cache_max_file_size: Option<usize>,
+
+    /// Watch the directory for changes and drop changed files from the cache
+    /// (Linux only)
+    #[clap(long)]
+    watch: bool,
+
+    /// Serve /events/reload, which tells browsers to reload pages when files
+    /// change (implies --watch)
+    #[clap(long)]
+    live_reload: bool,
+
+    /// Which symlinks under the directory are followed (default: within-root, only
+    /// those that stay inside the directory)
+    #[clap(long)]
+    follow_symlinks: Option<SymlinkPolicy>,
+
+    /// Serve dotfiles and paths through dot-directories, e.g. .env or .git/config
+    #[clap(long)]
+    allow_hidden: bool,
+}
+
+/// The name `value` is given on the command line, for passing it on as a setting.
+fn value_name<T: ValueEnum>(value: &T) -> String {
+    value
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default()
 }
 
 fn main() {
@@ -20,7 +203,135 @@ fn main() {
 
     // Set environment variables based on command-line args
     if let Some(directory) = args.directory {
-        env::set_var("APP_DIRECTORY", directory.to_string());
+        env::set_var("APP_DIRECTORY", &directory);
+    }
+    if let Some(limit) = args.max_request_line {
+        env::set_var("APP_MAX_REQUEST_LINE", limit.to_string());
+    }
+    if let Some(limit) = args.max_headers {
+        env::set_var("APP_MAX_HEADERS", limit.to_string());
+    }
+    if let Some(limit) = args.max_header_bytes {
+        env::set_var("APP_MAX_HEADER_BYTES", limit.to_string());
+    }
+    if let Some(limit) = args.max_body_size {
+        env::set_var("APP_MAX_BODY_SIZE", limit.to_string());
+    }
+    if args.no_decode_bodies {
+        env::set_var("APP_DECODE_BODIES", "false");
+    }
+    if let Some(limit) = args.max_decoded_body_size {
+        env::set_var("APP_MAX_DECODED_BODY_SIZE", limit.to_string());
+    }
+    if let Some(timeout) = args.header_timeout {
+        env::set_var("APP_HEADER_TIMEOUT", timeout.to_string());
+    }
+    if let Some(timeout) = args.body_timeout {
+        env::set_var("APP_BODY_TIMEOUT", timeout.to_string());
+    }
+    if let Some(rate) = args.min_body_rate {
+        env::set_var("APP_MIN_BODY_RATE", rate.to_string());
+    }
+    if let Some(timeout) = args.write_timeout {
+        env::set_var("APP_WRITE_TIMEOUT", timeout.to_string());
+    }
+    if let Some(rate) = args.min_write_rate {
+        env::set_var("APP_MIN_WRITE_RATE", rate.to_string());
+    }
+    if let Some(timeout) = args.keep_alive_timeout {
+        env::set_var("APP_KEEP_ALIVE_TIMEOUT", timeout.to_string());
+    }
+    if let Some(timeout) = args.websocket_idle_timeout {
+        env::set_var("APP_WEBSOCKET_IDLE_TIMEOUT", timeout.to_string());
+    }
+    if let Some(level) = args.gzip_level {
+        env::set_var("APP_GZIP_LEVEL", level.to_string());
+    }
+    if let Some(level) = args.deflate_level {
+        env::set_var("APP_DEFLATE_LEVEL", level.to_string());
+    }
+    if let Some(level) = args.brotli_level {
+        env::set_var("APP_BROTLI_LEVEL", level.to_string());
+    }
+    if let Some(level) = args.zstd_level {
+        env::set_var("APP_ZSTD_LEVEL", level.to_string());
+    }
+    if let Some(size) = args.compress_min_size {
+        env::set_var("APP_COMPRESS_MIN_SIZE", size.to_string());
+    }
+    if let Some(types) = args.compress_types {
+        env::set_var("APP_COMPRESS_TYPES", &types);
+    }
+    if let Some(types) = args.no_compress_types {
+        env::set_var("APP_NO_COMPRESS_TYPES", &types);
+    }
+    if let Some(types) = args.mime_types {
+        env::set_var("APP_MIME_TYPES", &types);
+    }
+    if args.sniff_mime_types {
+        env::set_var("APP_SNIFF_MIME_TYPES", "true");
+    }
+    if args.autoindex {
+        env::set_var("APP_AUTOINDEX", "true");
+    }
+    if args.autoindex_hidden {
+        env::set_var("APP_AUTOINDEX_HIDDEN", "true");
+    }
+    if let Some(files) = args.index_files {
+        env::set_var("APP_INDEX_FILES", &files);
+    }
+    if let Some(mode) = args.etag {
+        env::set_var("APP_ETAG", value_name(&mode));
+    }
+    if let Some(fallback) = args.spa_fallback {
+        env::set_var("APP_SPA_FALLBACK", &fallback);
+    }
+    if args.fsync_directory {
+        env::set_var("APP_FSYNC_DIRECTORY", "true");
+    }
+    if args.create_parents {
+        env::set_var("APP_CREATE_PARENTS", "true");
+    }
+    if let Some(limit) = args.max_part_size {
+        env::set_var("APP_MAX_PART_SIZE", limit.to_string());
+    }
+    if let Some(limit) = args.max_form_size {
+        env::set_var("APP_MAX_FORM_SIZE", limit.to_string());
+    }
+    if let Some(limit) = args.max_form_parts {
+        env::set_var("APP_MAX_FORM_PARTS", limit.to_string());
+    }
+    if let Some(size) = args.cache_size {
+        env::set_var("APP_CACHE_SIZE", size.to_string());
+    }
+    if let Some(size) = args.cache_max_file_size {
+        env::set_var("APP_CACHE_MAX_FILE_SIZE", size.to_string());
+    }
+    if args.watch {
+        env::set_var("APP_WATCH", "true");
+    }
+    if args.live_reload {
+        env::set_var("APP_LIVE_RELOAD", "true");
+    }
+    if let Some(policy) = args.follow_symlinks {
+        env::set_var("APP_FOLLOW_SYMLINKS", value_name(&policy));
+    }
+    if args.allow_hidden {
+        env::set_var("APP_ALLOW_HIDDEN", "true");
+    }
+
+    // Settings can also come straight from the environment, unchecked by clap
+    if let Err(e) = config::check_env() {
+        eprintln!("Invalid setting {}", e);
+        process::exit(2);
+    }
+
+    if config::Watch::from_env().enabled {
+        let directory = env::var("APP_DIRECTORY").unwrap_or_else(|_| ".".to_string());
+        match watch::start(Path::new(&directory)) {
+            Ok(()) => println!("Watching {:?} for changes", directory),
+            Err(e) => println!("Not watching {:?}: {}", directory, e),
+        }
     }
 
     let listener = TcpListener::bind("127.0.0.1:4221").unwrap();
@@ -0,0 +1,199 @@
+use std::path::Path;
+
+use crate::config::MimeTypes;
+
+/// Used when neither the extension nor the contents tell us anything.
+pub const DEFAULT_TYPE: &str = "application/octet-stream";
+
+/// Built-in extension table. Entries from `--mime-types` take precedence.
+pub const BUILTIN_TYPES: &[(&str, &str)] = &[
+    // Text
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("csv", "text/csv"),
+    ("xml", "application/xml"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    // Images
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    // Fonts
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    // Audio and video
+    ("mp3", "audio/mpeg"),
+    ("wav", "audio/wav"),
+    ("ogg", "audio/ogg"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    // Archives and other binaries
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("wasm", "application/wasm"),
+];
+
+/// Non `text/*` types whose contents are text, and so get a charset.
+const TEXT_APPLICATION_TYPES: &[&str] = &[
+    "application/json",
+    "application/xml",
+    "application/manifest+json",
+    "image/svg+xml",
+];
+
+/// How much of a file `sniff` gets to look at.
+pub const SNIFF_LENGTH: usize = 512;
+
+/// Guesses a type from the leading bytes of a file: well-known signatures first,
+/// then plain text if the data is valid UTF-8 without control characters.
+/// `contents` may stop in the middle of a character.
+pub fn sniff(contents: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"\0asm", "application/wasm"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+    ];
+    if let Some((_, mime)) = SIGNATURES
+        .iter()
+        .find(|(magic, _)| contents.starts_with(magic))
+    {
+        return Some(mime);
+    }
+    if contents.len() >= 12 && &contents[..4] == b"RIFF" && &contents[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+
+    let text = match std::str::from_utf8(contents) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&contents[..e.valid_up_to()]).ok()?
+        }
+        Err(_) => return None,
+    };
+    if text.chars().any(|c| c.is_control() && !c.is_whitespace()) {
+        return None;
+    }
+    let start: String = text.trim_start().chars().take(14).collect();
+    let start = start.to_ascii_lowercase();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        Some("text/html")
+    } else if start.starts_with("<?xml") {
+        Some("application/xml")
+    } else {
+        Some("text/plain")
+    }
+}
+
+/// Adds `charset=utf-8` to textual types that do not already name a charset.
+fn with_charset(mime: &str) -> String {
+    let is_text = mime.starts_with("text/") || TEXT_APPLICATION_TYPES.contains(&mime);
+    if is_text && !mime.contains(';') {
+        format!("{}; charset=utf-8", mime)
+    } else {
+        mime.to_string()
+    }
+}
+
+/// Picks the `Content-Type` for a served file from its extension, falling back to
+/// sniffing `contents` when that is enabled and `contents` are available.
+pub fn content_type(path: &Path, contents: Option<&[u8]>, types: &MimeTypes) -> String {
+    let by_extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(|extension| types.get(extension));
+    let sniffed = || {
+        contents
+            .filter(|_| types.sniff)
+            .and_then(sniff)
+            .map(|mime| mime.to_string())
+    };
+
+    match by_extension.map(|mime| mime.to_string()).or_else(sniffed) {
+        Some(mime) => with_charset(&mime),
+        None => DEFAULT_TYPE.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_type_from_extension() {
+        let types = MimeTypes::default();
+        let lookup = |name: &str| content_type(Path::new(name), None, &types);
+
+        assert_eq!(lookup("index.html"), "text/html; charset=utf-8");
+        assert_eq!(lookup("STYLE.CSS"), "text/css; charset=utf-8");
+        assert_eq!(lookup("data.json"), "application/json; charset=utf-8");
+        assert_eq!(lookup("logo.png"), "image/png");
+        assert_eq!(lookup("archive.tar.gz"), "application/gzip");
+        assert_eq!(lookup("README"), DEFAULT_TYPE);
+        assert_eq!(lookup("file.unknown"), DEFAULT_TYPE);
+    }
+
+    #[test]
+    fn content_type_uses_configured_types() {
+        let mut types = MimeTypes::default();
+        types.insert("js", "application/javascript");
+        types.insert("bin", "application/x-custom; charset=latin1");
+
+        let lookup = |name: &str| content_type(Path::new(name), None, &types);
+        assert_eq!(lookup("app.js"), "application/javascript");
+        assert_eq!(lookup("blob.bin"), "application/x-custom; charset=latin1");
+    }
+
+    #[test]
+    fn content_type_sniffs_when_enabled() {
+        let mut types = MimeTypes::default();
+        let lookup = |contents: &[u8], types: &MimeTypes| {
+            content_type(Path::new("upload"), Some(contents), types)
+        };
+
+        assert_eq!(lookup(b"<html></html>", &types), DEFAULT_TYPE);
+
+        types.sniff = true;
+        assert_eq!(lookup(b"<html></html>", &types), "text/html; charset=utf-8");
+        assert_eq!(lookup(b"\x89PNG\r\n\x1a\n....", &types), "image/png");
+        assert_eq!(
+            lookup(b"  <!DOCTYPE html><p>hi", &types),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            lookup("héllo\n".as_bytes(), &types),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(lookup(b"\x00\x01\x02binary", &types), DEFAULT_TYPE);
+        // A prefix may cut the last character short
+        assert_eq!(lookup(b"caf\xc3", &types), "text/plain; charset=utf-8");
+        assert_eq!(lookup(b"caf\xc3!", &types), DEFAULT_TYPE);
+
+        // The extension still wins over the contents
+        let path = Path::new("notes.txt");
+        assert_eq!(
+            content_type(path, Some(b"<html>"), &types),
+            "text/plain; charset=utf-8"
+        );
+    }
+}
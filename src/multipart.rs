@@ -0,0 +1,736 @@
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use thiserror::Error;
+
+use crate::config::FormLimits;
+use crate::utils::{self, AtomicFile};
+
+/// Size of the pieces the body is read in.
+const CHUNK_SIZE: usize = 8 * 1024;
+/// Most bytes of headers a single part may have.
+const MAX_PART_HEADER_BYTES: usize = 8 * 1024;
+
+#[derive(Debug, Error)]
+pub enum MultipartError {
+    #[error("malformed multipart body: {0}")]
+    Malformed(&'static str),
+    #[error("part larger than {0} bytes")]
+    PartTooLarge(usize),
+    #[error("form larger than {0} bytes")]
+    FormTooLarge(usize),
+    #[error("form with more than {0} parts")]
+    TooManyParts(usize),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Splits a header value such as `form-data; name="a; b"; filename=x` into its
+/// leading value and `name=value` parameters. Parameter names are lowercased and
+/// quoted values unquoted.
+fn parameters(header: &str) -> (String, Vec<(String, String)>) {
+    let mut pieces = Vec::new();
+    let mut piece = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in header.chars() {
+        match c {
+            _ if escaped => {
+                piece.push(c);
+                escaped = false;
+            }
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => pieces.push(std::mem::take(&mut piece)),
+            _ => piece.push(c),
+        }
+    }
+    pieces.push(piece);
+
+    let value = pieces.remove(0).trim().to_ascii_lowercase();
+    let parameters = pieces
+        .iter()
+        .filter_map(|piece| piece.split_once('='))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    (value, parameters)
+}
+
+/// The boundary of a `multipart/form-data` body, from its `Content-Type`.
+pub fn boundary(content_type: &str) -> Option<String> {
+    let (mime, parameters) = parameters(content_type);
+    if mime != "multipart/form-data" {
+        return None;
+    }
+    parameters
+        .into_iter()
+        .find(|(name, _)| name == "boundary")
+        .map(|(_, boundary)| boundary)
+        // RFC 2046 allows at most 70 characters
+        .filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+}
+
+/// The headers of one part that matter to us.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    /// Form field name.
+    pub name: String,
+    /// Present for file inputs. Empty if no file was chosen.
+    pub file_name: Option<String>,
+    pub content_type: Option<String>,
+    /// Bytes of the delimiter and headers before the body.
+    pub header_size: usize,
+}
+
+impl Part {
+    fn from_headers(headers: &[String]) -> Result<Self, MultipartError> {
+        let disposition = utils::get_header(headers, "Content-Disposition").ok_or(
+            MultipartError::Malformed("part without Content-Disposition"),
+        )?;
+        let (kind, parameters) = parameters(disposition);
+        if kind != "form-data" {
+            return Err(MultipartError::Malformed("part is not form-data"));
+        }
+        let parameter = |name: &str| {
+            parameters
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, value)| value.clone())
+        };
+        // RFC 5987 `filename*=UTF-8''...` wins over the plain form
+        let extended = parameter("filename*").and_then(|value| {
+            let (charset, encoded) = value.split_once("''")?;
+            charset
+                .eq_ignore_ascii_case("utf-8")
+                .then(|| utils::percent_decode(encoded))?
+        });
+
+        Ok(Part {
+            name: parameter("name").ok_or(MultipartError::Malformed("part without a name"))?,
+            file_name: extended.or_else(|| parameter("filename")),
+            content_type: utils::get_header(headers, "Content-Type").map(str::to_string),
+            header_size: 0,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Preamble,
+    /// Part headers have been read; the body comes next.
+    Body,
+    /// The body of the last part has been read up to its delimiter.
+    Delimiter,
+    Done,
+}
+
+/// Reads a `multipart/form-data` body part by part, never holding more than a
+/// chunk of it in memory: `next_part` returns the headers of the next part and
+/// `read_body` streams its contents.
+pub struct Parser<R> {
+    reader: R,
+    /// `CRLF--boundary`. The CRLF belongs to the delimiter, not the part before.
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    eof: bool,
+    state: State,
+}
+
+impl<R: Read> Parser<R> {
+    pub fn new(reader: R, boundary: &str) -> Self {
+        Self {
+            reader,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // Lets the first delimiter match even without a preamble before it
+            buffer: b"\r\n".to_vec(),
+            eof: false,
+            state: State::Preamble,
+        }
+    }
+
+    /// Reads another chunk into the buffer. Returns `false` at the end of the body.
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+        let start = self.buffer.len();
+        self.buffer.resize(start + CHUNK_SIZE, 0);
+        let read = loop {
+            match self.reader.read(&mut self.buffer[start..]) {
+                Ok(read) => break read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.buffer.truncate(start);
+                    return Err(e);
+                }
+            }
+        };
+        self.buffer.truncate(start + read);
+        self.eof = read == 0;
+        Ok(read > 0)
+    }
+
+    /// Passes everything up to the next delimiter to `sink` and consumes the
+    /// delimiter.
+    fn read_to_delimiter<F>(&mut self, mut sink: F) -> Result<(), MultipartError>
+    where
+        F: FnMut(&[u8]) -> Result<(), MultipartError>,
+    {
+        loop {
+            let found = self
+                .buffer
+                .windows(self.delimiter.len())
+                .position(|window| window == self.delimiter.as_slice());
+            if let Some(end) = found {
+                sink(&self.buffer[..end])?;
+                self.buffer.drain(..end + self.delimiter.len());
+                return Ok(());
+            }
+            // Hold back what could be the start of a delimiter split across chunks
+            let keep = self.delimiter.len() - 1;
+            if self.buffer.len() > keep {
+                let end = self.buffer.len() - keep;
+                sink(&self.buffer[..end])?;
+                self.buffer.drain(..end);
+            }
+            if !self.fill()? {
+                return Err(MultipartError::Malformed("missing closing boundary"));
+            }
+        }
+    }
+
+    /// Reads a line of at most `limit` bytes, without its CRLF. The last line may
+    /// end with the body instead.
+    fn read_line(&mut self, limit: usize) -> Result<Vec<u8>, MultipartError> {
+        loop {
+            if let Some(end) = self.buffer.windows(2).position(|window| window == b"\r\n") {
+                let line = self.buffer[..end].to_vec();
+                self.buffer.drain(..end + 2);
+                return Ok(line);
+            }
+            if self.buffer.len() > limit {
+                return Err(MultipartError::Malformed("line too long"));
+            }
+            if !self.fill()? {
+                return Ok(std::mem::take(&mut self.buffer));
+            }
+        }
+    }
+
+    /// Moves past the next delimiter and returns the headers of the part after
+    /// it, or `None` after the closing delimiter. Whatever is left of the previous
+    /// part's body is skipped.
+    pub fn next_part(&mut self) -> Result<Option<Part>, MultipartError> {
+        match self.state {
+            State::Preamble | State::Body => self.read_to_delimiter(|_| Ok(()))?,
+            State::Delimiter => {}
+            State::Done => return Ok(None),
+        }
+
+        // The delimiter is followed by `--` on the last one, then optional
+        // whitespace and a CRLF
+        let rest = self.read_line(MAX_PART_HEADER_BYTES)?;
+        if rest.starts_with(b"--") {
+            self.state = State::Done;
+            return Ok(None);
+        }
+        if !rest.iter().all(|b| *b == b' ' || *b == b'\t') {
+            return Err(MultipartError::Malformed("garbage after boundary"));
+        }
+
+        let mut headers = Vec::new();
+        let mut header_bytes = 0;
+        loop {
+            let line = self.read_line(MAX_PART_HEADER_BYTES - header_bytes)?;
+            if line.is_empty() {
+                break;
+            }
+            header_bytes += line.len() + 2;
+            if header_bytes > MAX_PART_HEADER_BYTES {
+                return Err(MultipartError::Malformed("part headers too large"));
+            }
+            headers.push(String::from_utf8_lossy(&line).into_owned());
+        }
+
+        self.state = State::Body;
+        let header_size = self.delimiter.len() + rest.len() + 2 + header_bytes + 2;
+        Part::from_headers(&headers).map(|part| {
+            Some(Part {
+                header_size,
+                ..part
+            })
+        })
+    }
+
+    /// Streams the body of the current part to `sink`.
+    pub fn read_body<F>(&mut self, sink: F) -> Result<(), MultipartError>
+    where
+        F: FnMut(&[u8]) -> Result<(), MultipartError>,
+    {
+        if self.state != State::Body {
+            return Ok(());
+        }
+        self.read_to_delimiter(sink)?;
+        self.state = State::Delimiter;
+        Ok(())
+    }
+}
+
+/// Turns a client supplied file name into one that is safe to create in the
+/// upload directory: only the last path component is kept, characters other
+/// than letters, digits, `.`, `-`, `_` and spaces are replaced, and leading dots
+/// are dropped so uploads cannot create hidden files.
+pub fn sanitize_file_name(name: &str) -> String {
+    // Some browsers send the full client side path
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '.' | '-' | '_' | ' ') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let name = name
+        .trim_start_matches(['.', ' '])
+        .trim_end_matches(['.', ' ']);
+
+    let mut end = name.len().min(255);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    match &name[..end] {
+        "" => "upload".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// A file part saved to disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SavedFile {
+    pub field: String,
+    pub file_name: String,
+    pub size: usize,
+}
+
+/// What `save_form` made of a form: the regular fields, in order, and the files.
+#[derive(Debug, Default)]
+pub struct Form {
+    pub fields: Vec<(String, String)>,
+    pub files: Vec<SavedFile>,
+}
+
+impl Form {
+    /// Summary of the upload for the client. `url_path` is where the files were
+    /// saved, ending in `/`.
+    pub fn to_json(&self, url_path: &str) -> String {
+        let files: Vec<String> = self
+            .files
+            .iter()
+            .map(|file| {
+                format!(
+                    "{{\"field\":{},\"name\":{},\"path\":{},\"size\":{}}}",
+                    utils::escape_json(&file.field),
+                    utils::escape_json(&file.file_name),
+                    utils::escape_json(&utils::percent_encode(&format!(
+                        "{}{}",
+                        url_path, file.file_name
+                    ))),
+                    file.size,
+                )
+            })
+            .collect();
+        let fields: Vec<String> = self
+            .fields
+            .iter()
+            .map(|(name, value)| {
+                format!(
+                    "{{\"name\":{},\"value\":{}}}",
+                    utils::escape_json(name),
+                    utils::escape_json(value)
+                )
+            })
+            .collect();
+        format!(
+            "{{\"files\":[{}],\"fields\":[{}]}}",
+            files.join(","),
+            fields.join(",")
+        )
+    }
+}
+
+struct Sizes<'a> {
+    limits: &'a FormLimits,
+    part: usize,
+    total: usize,
+}
+
+impl Sizes<'_> {
+    fn add(&mut self, length: usize) -> Result<(), MultipartError> {
+        self.part += length;
+        if self.part > self.limits.max_part_size {
+            return Err(MultipartError::PartTooLarge(self.limits.max_part_size));
+        }
+        self.add_overhead(length)
+    }
+
+    /// Counts bytes that belong to the form but to no part's contents, such as
+    /// delimiters and part headers.
+    fn add_overhead(&mut self, length: usize) -> Result<(), MultipartError> {
+        self.total += length;
+        if self.total > self.limits.max_form_size {
+            return Err(MultipartError::FormTooLarge(self.limits.max_form_size));
+        }
+        Ok(())
+    }
+}
+
+/// `name`, or if another file of the same form already took it, `name (2)`,
+/// `name (3)` and so on, numbered before the extension.
+fn unique_file_name(name: String, files: &[SavedFile]) -> String {
+    let taken = |candidate: &str| files.iter().any(|file| file.file_name == candidate);
+    if !taken(&name) {
+        return name;
+    }
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name.as_str(), ""),
+    };
+    (2..)
+        .map(|n| {
+            // Shortens the stem so the name stays within 255 bytes
+            let suffix = format!(" ({}){}", n, extension);
+            let mut end = stem.len().min(255usize.saturating_sub(suffix.len()));
+            while !stem.is_char_boundary(end) {
+                end -= 1;
+            }
+            format!("{}{}", &stem[..end], suffix)
+        })
+        .find(|candidate| !taken(candidate))
+        .unwrap_or(name)
+}
+
+/// Parses a `multipart/form-data` body, saving each file part in `directory`
+/// under its sanitized file name, made unique within the form, and collecting
+/// the other fields. Files only
+/// replace existing ones once the whole form has been read, so a form that
+/// breaks a limit or is malformed leaves the directory untouched.
+pub fn save_form<R: Read>(
+    reader: R,
+    boundary: &str,
+    directory: &Path,
+    limits: &FormLimits,
+    sync_directory: bool,
+) -> Result<Form, MultipartError> {
+    let mut parser = Parser::new(reader, boundary);
+    let mut form = Form::default();
+    let mut pending = Vec::new();
+    let mut sizes = Sizes {
+        limits,
+        part: 0,
+        total: 0,
+    };
+
+    let mut parts = 0;
+    while let Some(part) = parser.next_part()? {
+        parts += 1;
+        if parts > limits.max_parts {
+            return Err(MultipartError::TooManyParts(limits.max_parts));
+        }
+        sizes.part = 0;
+        sizes.add_overhead(part.header_size)?;
+        match &part.file_name {
+            // A file input left empty
+            Some(file_name) if file_name.is_empty() => {
+                parser.read_body(|chunk| sizes.add(chunk.len()))?
+            }
+            Some(file_name) => {
+                let file_name = unique_file_name(sanitize_file_name(file_name), &form.files);
+                let mut file = AtomicFile::create(&directory.join(&file_name))?;
+                parser.read_body(|chunk| {
+                    sizes.add(chunk.len())?;
+                    file.write_all(chunk)?;
+                    Ok(())
+                })?;
+                form.files.push(SavedFile {
+                    field: part.name,
+                    file_name,
+                    size: sizes.part,
+                });
+                pending.push(file);
+            }
+            None => {
+                let mut value = Vec::new();
+                parser.read_body(|chunk| {
+                    sizes.add(chunk.len())?;
+                    value.extend_from_slice(chunk);
+                    Ok(())
+                })?;
+                let value = String::from_utf8_lossy(&value).into_owned();
+                form.fields.push((part.name, value));
+            }
+        }
+    }
+
+    for file in pending {
+        file.commit(sync_directory)?;
+    }
+    Ok(form)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const BOUNDARY: &str = "----form7MA4YWxkTrZu0gW";
+
+    fn body(parts: &[(&str, &str)]) -> Vec<u8> {
+        let mut body = b"preamble to ignore\r\n".to_vec();
+        for (headers, contents) in parts {
+            body.extend(format!("--{}\r\n{}\r\n\r\n{}\r\n", BOUNDARY, headers, contents).bytes());
+        }
+        body.extend(format!("--{}--\r\n", BOUNDARY).bytes());
+        body
+    }
+
+    /// Hands out the body a few bytes at a time, so delimiters end up split
+    /// across reads.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(3);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn boundary_from_content_type() {
+        assert_eq!(
+            boundary("multipart/form-data; boundary=abc").as_deref(),
+            Some("abc")
+        );
+        assert_eq!(
+            boundary("Multipart/Form-Data; charset=utf-8; boundary=\"a b;c\"").as_deref(),
+            Some("a b;c")
+        );
+        assert_eq!(boundary("multipart/form-data"), None);
+        assert_eq!(boundary("text/plain; boundary=abc"), None);
+    }
+
+    #[test]
+    fn parser_reads_parts_across_chunk_boundaries() {
+        let body = body(&[
+            ("Content-Disposition: form-data; name=\"title\"", "Hello"),
+            (
+                "Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\
+                 Content-Type: text/plain",
+                "line 1\r\n--not-the-boundary\r\nline 2",
+            ),
+        ]);
+        let mut parser = Parser::new(Trickle(&body), BOUNDARY);
+
+        let mut parts = Vec::new();
+        while let Some(part) = parser.next_part().unwrap() {
+            let mut contents = Vec::new();
+            parser
+                .read_body(|chunk| {
+                    contents.extend_from_slice(chunk);
+                    Ok(())
+                })
+                .unwrap();
+            parts.push((part, String::from_utf8(contents).unwrap()));
+        }
+
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].0.name, "title");
+        assert_eq!(parts[0].0.file_name, None);
+        assert_eq!(parts[0].1, "Hello");
+        assert_eq!(parts[1].0.file_name.as_deref(), Some("a.txt"));
+        assert_eq!(parts[1].0.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(parts[1].1, "line 1\r\n--not-the-boundary\r\nline 2");
+    }
+
+    #[test]
+    fn parser_rejects_malformed_bodies() {
+        let parse = |body: &[u8]| {
+            let mut parser = Parser::new(body, BOUNDARY);
+            while parser.next_part()?.is_some() {}
+            Ok::<_, MultipartError>(())
+        };
+        let unterminated = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nabc",
+            BOUNDARY
+        );
+        assert!(matches!(
+            parse(unterminated.as_bytes()),
+            Err(MultipartError::Malformed(_))
+        ));
+        let nameless = body(&[("Content-Disposition: form-data", "abc")]);
+        assert!(matches!(
+            parse(&nameless),
+            Err(MultipartError::Malformed(_))
+        ));
+        assert!(matches!(
+            parse(b"no boundary at all"),
+            Err(MultipartError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn sanitize_file_names() {
+        assert_eq!(sanitize_file_name("photo 1.jpg"), "photo 1.jpg");
+        assert_eq!(sanitize_file_name("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_file_name("C:\\Users\\me\\notes.txt"), "notes.txt");
+        assert_eq!(sanitize_file_name(".htaccess"), "htaccess");
+        assert_eq!(sanitize_file_name("a<b>:c?.txt"), "a_b__c_.txt");
+        assert_eq!(sanitize_file_name("résumé.pdf"), "résumé.pdf");
+        assert_eq!(sanitize_file_name(".."), "upload");
+        assert_eq!(sanitize_file_name(&"é".repeat(200)).len(), 254);
+    }
+
+    #[test]
+    fn save_form_writes_files_and_enforces_limits() {
+        let directory = utils::temp_dir("multipart");
+        let body = body(&[
+            ("Content-Disposition: form-data; name=\"note\"", "hi there"),
+            (
+                "Content-Disposition: form-data; name=\"upload\"; filename=\"../a.txt\"",
+                "contents of a",
+            ),
+            (
+                "Content-Disposition: form-data; name=\"upload\"; \
+                 filename*=UTF-8''%C3%A9t%C3%A9.txt; filename=\"ete.txt\"",
+                "summer",
+            ),
+            (
+                "Content-Disposition: form-data; name=\"empty\"; filename=\"\"",
+                "",
+            ),
+        ]);
+
+        let limits = FormLimits::default();
+        let form = save_form(&body[..], BOUNDARY, &directory, &limits, false).unwrap();
+        assert_eq!(
+            form.fields,
+            vec![("note".to_string(), "hi there".to_string())]
+        );
+        assert_eq!(
+            form.files,
+            vec![
+                SavedFile {
+                    field: "upload".to_string(),
+                    file_name: "a.txt".to_string(),
+                    size: 13,
+                },
+                SavedFile {
+                    field: "upload".to_string(),
+                    file_name: "été.txt".to_string(),
+                    size: 6,
+                },
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(directory.join("a.txt")).unwrap(),
+            "contents of a"
+        );
+        assert_eq!(
+            fs::read_to_string(directory.join("été.txt")).unwrap(),
+            "summer"
+        );
+        assert!(form
+            .to_json("/files/up/")
+            .contains("\"path\":\"/files/up/%C3%A9t%C3%A9.txt\",\"size\":6"));
+
+        // Nothing is written when a limit is broken, not even the parts before it
+        fs::remove_file(directory.join("a.txt")).unwrap();
+        let limits = FormLimits {
+            max_part_size: 10,
+            ..FormLimits::default()
+        };
+        let result = save_form(&body[..], BOUNDARY, &directory, &limits, false);
+        assert!(matches!(result, Err(MultipartError::PartTooLarge(10))));
+        let limits = FormLimits {
+            max_form_size: 20,
+            ..FormLimits::default()
+        };
+        let result = save_form(&body[..], BOUNDARY, &directory, &limits, false);
+        assert!(matches!(result, Err(MultipartError::FormTooLarge(20))));
+        assert!(!directory.join("a.txt").exists());
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 1);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn save_form_counts_parts_and_keeps_names_apart() {
+        let directory = utils::temp_dir("multipart-parts");
+        let file = |name: &str| {
+            format!(
+                "Content-Disposition: form-data; name=\"f\"; filename=\"{}\"",
+                name
+            )
+        };
+        let (a, nested_a, bare) = (file("a.txt"), file("dir/a.txt"), file("README"));
+        let duplicates = body(&[
+            (&a, "1"),
+            (&nested_a, "2"),
+            (&a, "3"),
+            (&bare, "4"),
+            (&bare, "5"),
+        ]);
+
+        let form = save_form(
+            &duplicates[..],
+            BOUNDARY,
+            &directory,
+            &FormLimits::default(),
+            false,
+        )
+        .unwrap();
+        let names: Vec<&str> = form
+            .files
+            .iter()
+            .map(|file| file.file_name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            ["a.txt", "a (2).txt", "a (3).txt", "README", "README (2)"]
+        );
+        assert_eq!(
+            fs::read_to_string(directory.join("a (3).txt")).unwrap(),
+            "3"
+        );
+        assert_eq!(unique_file_name("é".repeat(127), &[]).len(), 254);
+        let taken = [SavedFile {
+            field: "f".to_string(),
+            file_name: "é".repeat(127),
+            size: 0,
+        }];
+        assert!(unique_file_name("é".repeat(127), &taken).len() <= 255);
+
+        // Empty parts still count, by number and by the size of their headers
+        let empty = body(&vec![(file("x").as_str(), ""); 1000]);
+        let limits = FormLimits {
+            max_parts: 10,
+            ..FormLimits::default()
+        };
+        let result = save_form(&empty[..], BOUNDARY, &directory, &limits, false);
+        assert!(matches!(result, Err(MultipartError::TooManyParts(10))));
+        let limits = FormLimits {
+            max_form_size: 1000,
+            max_parts: 1000,
+            ..FormLimits::default()
+        };
+        let result = save_form(&empty[..], BOUNDARY, &directory, &limits, false);
+        assert!(matches!(result, Err(MultipartError::FormTooLarge(1000))));
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 5);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
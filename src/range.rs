@@ -0,0 +1,346 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::interface::PartialContentResponse;
+
+/// Requests asking for more ranges than this are served in full instead, so that a
+/// client cannot make us build a response out of thousands of tiny parts.
+pub const MAX_RANGES: usize = 32;
+
+/// An inclusive range of byte offsets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    fn content_range(&self, length: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, length)
+    }
+
+    fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// The header is missing, malformed or in a unit we do not know: send everything.
+    Full,
+    Partial(Vec<ByteRange>),
+    /// None of the ranges overlaps the representation.
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header against a representation of `length` bytes, following
+/// RFC 9110 section 14. Supports `first-last`, open-ended `first-` and suffix
+/// `-length` ranges. Overlapping and adjacent ranges are merged, so no byte is
+/// sent twice, and the result is in ascending order.
+pub fn parse(header: &str, length: u64) -> RangeRequest {
+    let specs = match header.trim().split_once('=') {
+        Some((unit, specs)) if unit.trim().eq_ignore_ascii_case("bytes") => specs,
+        _ => return RangeRequest::Full,
+    };
+
+    let mut ranges = Vec::new();
+    for spec in specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+    {
+        let (first, last) = match spec.split_once('-') {
+            Some(bounds) => bounds,
+            None => return RangeRequest::Full,
+        };
+        let range = match (first.trim(), last.trim()) {
+            ("", suffix) => match suffix.parse::<u64>() {
+                Ok(0) => None,
+                Ok(suffix) if length > 0 => Some(ByteRange {
+                    start: length.saturating_sub(suffix),
+                    end: length - 1,
+                }),
+                Ok(_) => None,
+                Err(_) => return RangeRequest::Full,
+            },
+            (first, last) => {
+                let start = match first.parse::<u64>() {
+                    Ok(start) => start,
+                    Err(_) => return RangeRequest::Full,
+                };
+                let end = match last {
+                    "" => u64::MAX,
+                    last => match last.parse::<u64>() {
+                        Ok(end) if end >= start => end,
+                        _ => return RangeRequest::Full,
+                    },
+                };
+                (start < length).then(|| ByteRange {
+                    start,
+                    end: end.min(length - 1),
+                })
+            }
+        };
+        ranges.extend(range);
+    }
+
+    if ranges.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+    RangeRequest::Partial(merge(ranges))
+}
+
+fn merge(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end)
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+fn boundary() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.subsec_nanos());
+    format!(
+        "byteranges-{:08x}{:04x}",
+        nanos,
+        COUNTER.fetch_add(1, Ordering::Relaxed) & 0xffff
+    )
+}
+
+/// Fills in the `206` response for `ranges` of `partial`'s body, a representation of
+/// `length` bytes: a single part with `Content-Range`, or several as
+/// `multipart/byteranges`. Only the part headers are built here; the ranges are
+/// read as the response is sent.
+pub fn respond(
+    ranges: &[ByteRange],
+    partial: PartialContentResponse,
+    length: u64,
+    content_type: &str,
+) -> PartialContentResponse {
+    if let [range] = ranges {
+        return partial
+            .with_part(String::new(), range.start, range.len())
+            .with_header("Content-Type", content_type)
+            .with_header("Content-Range", range.content_range(length));
+    }
+
+    let boundary = boundary();
+    let mut partial = partial.with_header(
+        "Content-Type",
+        format!("multipart/byteranges; boundary={}", boundary),
+    );
+    for (i, range) in ranges.iter().enumerate() {
+        // Each delimiter but the first also ends the part before it
+        let head = format!(
+            "{}--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            if i == 0 { "" } else { "\r\n" },
+            boundary,
+            content_type,
+            range.content_range(length)
+        );
+        partial = partial.with_part(head, range.start, range.len());
+    }
+    partial.with_trailer(format!("\r\n--{}--\r\n", boundary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::{HttpResponse, RangeNotSatisfiableResponse};
+    use crate::utils;
+    use std::fs::{self, File};
+    use std::io::{self, Write};
+    use std::sync::Arc;
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    #[test]
+    fn parse_range_forms() {
+        assert_eq!(
+            parse("bytes=0-4", 10),
+            RangeRequest::Partial(vec![range(0, 4)])
+        );
+        assert_eq!(
+            parse("bytes=5-", 10),
+            RangeRequest::Partial(vec![range(5, 9)])
+        );
+        assert_eq!(
+            parse("bytes=-3", 10),
+            RangeRequest::Partial(vec![range(7, 9)])
+        );
+        assert_eq!(
+            parse("bytes=-30", 10),
+            RangeRequest::Partial(vec![range(0, 9)])
+        );
+        assert_eq!(
+            parse("bytes=8-100", 10),
+            RangeRequest::Partial(vec![range(8, 9)])
+        );
+        assert_eq!(
+            parse("Bytes=0-0, -1", 10),
+            RangeRequest::Partial(vec![range(0, 0), range(9, 9)])
+        );
+    }
+
+    #[test]
+    fn parse_range_edge_cases() {
+        assert_eq!(parse("bytes=10-", 10), RangeRequest::Unsatisfiable);
+        assert_eq!(parse("bytes=-0", 10), RangeRequest::Unsatisfiable);
+        assert_eq!(parse("bytes=0-", 0), RangeRequest::Unsatisfiable);
+        // Unsatisfiable parts are dropped as long as one part remains
+        assert_eq!(
+            parse("bytes=20-30,1-2", 10),
+            RangeRequest::Partial(vec![range(1, 2)])
+        );
+
+        assert_eq!(parse("items=0-4", 10), RangeRequest::Full);
+        assert_eq!(parse("bytes=4-2", 10), RangeRequest::Full);
+        assert_eq!(parse("bytes=abc", 10), RangeRequest::Full);
+        let too_many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert_eq!(parse(&too_many, 10), RangeRequest::Full);
+    }
+
+    #[test]
+    fn parse_merges_overlapping_ranges() {
+        assert_eq!(
+            parse("bytes=6-7,0-2,1-3,-1", 10),
+            RangeRequest::Partial(vec![range(0, 3), range(6, 7), range(9, 9)])
+        );
+        // Adjacent ranges become one
+        assert_eq!(
+            parse("bytes=4-5,0-3", 10),
+            RangeRequest::Partial(vec![range(0, 5)])
+        );
+        // Asking for the same bytes over and over only sends them once
+        let repeated = format!("bytes={}", vec!["0-"; MAX_RANGES].join(","));
+        assert_eq!(
+            parse(&repeated, 10),
+            RangeRequest::Partial(vec![range(0, 9)])
+        );
+    }
+
+    #[test]
+    fn respond_builds_partial_content() {
+        let body = Arc::new(b"0123456789".to_vec());
+        let respond = |header: &str| match parse(header, 10) {
+            RangeRequest::Partial(ranges) => {
+                let partial = PartialContentResponse::shared(Arc::clone(&body));
+                let response = respond(&ranges, partial, 10, "text/plain");
+                String::from_utf8(response.response()).unwrap()
+            }
+            request => panic!("not a partial request: {:?}", request),
+        };
+
+        let response = respond("bytes=2-4");
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(response.contains("Content-Range: bytes 2-4/10\r\n"));
+        assert!(response.ends_with("\r\n\r\n234"));
+
+        let response = respond("bytes=-2,0-1");
+        let (head, multipart) = response.split_once("\r\n\r\n").unwrap();
+        let boundary = head
+            .split("boundary=")
+            .nth(1)
+            .unwrap()
+            .split("\r\n")
+            .next()
+            .unwrap();
+        assert_eq!(
+            multipart,
+            format!(
+                "--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+                 --{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n\
+                 --{b}--\r\n",
+                b = boundary
+            )
+        );
+
+        // A source shorter than announced is an error rather than a short part
+        let ranges = [range(8, 11)];
+        let partial = PartialContentResponse::shared(Arc::clone(&body));
+        let short = super::respond(&ranges, partial, 12, "text/plain");
+        let written = short.write_to(&mut Vec::new());
+        assert_eq!(written.err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+
+        assert_eq!(parse("bytes=10-", 10), RangeRequest::Unsatisfiable);
+        let response = RangeNotSatisfiableResponse { length: 10 }.response();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"));
+        assert!(response.contains("Content-Range: bytes */10\r\n"));
+    }
+
+    /// Accepts `limit` bytes, then fails like a client that went away.
+    struct Limited {
+        written: Vec<u8>,
+        limit: usize,
+    }
+
+    impl Write for Limited {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let room = self.limit - self.written.len();
+            if room == 0 {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            let length = buf.len().min(room);
+            self.written.extend_from_slice(&buf[..length]);
+            Ok(length)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn respond_streams_ranges_of_large_files() {
+        let directory = utils::temp_dir("sparse-ranges");
+        let path = directory.join("sparse.bin");
+        let length = 16 << 30;
+        let file = File::create(&path).unwrap();
+        file.set_len(length).unwrap();
+        drop(file);
+
+        // Nearly all of the file, which must not be read up front
+        let ranges = match parse("bytes=0-0,2-", length) {
+            RangeRequest::Partial(ranges) => ranges,
+            request => panic!("not a partial request: {:?}", request),
+        };
+        let partial = PartialContentResponse::from_file(File::open(&path).unwrap(), length);
+        let response = respond(&ranges, partial, length, "application/octet-stream");
+
+        let head = response.head();
+        let content_length: u64 = head
+            .split("Content-Length: ")
+            .nth(1)
+            .and_then(|rest| rest.split("\r\n").next())
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(content_length > length - 1);
+
+        let mut client = Limited {
+            written: Vec::new(),
+            limit: 64 * 1024,
+        };
+        assert!(response.write_to(&mut client).is_err());
+        let sent = String::from_utf8_lossy(&client.written);
+        assert!(sent.contains("Content-Range: bytes 0-0/17179869184\r\n\r\n\0\r\n--"));
+        assert!(sent.contains(&format!("Content-Range: bytes 2-{}/{}", length - 1, length)));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
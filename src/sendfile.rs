@@ -0,0 +1,205 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+
+/// Copies `length` bytes of `file`, starting at `offset`, to `writer` through a
+/// buffer.
+pub fn copy(file: &File, offset: u64, length: u64, writer: &mut dyn Write) -> io::Result<()> {
+    let mut file = file;
+    file.seek(SeekFrom::Start(offset))?;
+    let copied = io::copy(&mut file.take(length), writer)?;
+    if copied < length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "file shrank while it was sent",
+        ));
+    }
+    writer.flush()
+}
+
+/// What `send_file` sends to: a socket, which is told how far the transfer has got
+/// so that it can hold it to a deadline.
+pub trait Socket: Write {
+    /// The socket, ready for the next send.
+    fn stream(&mut self) -> io::Result<&TcpStream>;
+
+    /// Records `length` bytes sent past `Write`.
+    fn sent(&mut self, _length: u64) {}
+}
+
+impl Socket for &TcpStream {
+    fn stream(&mut self) -> io::Result<&TcpStream> {
+        Ok(self)
+    }
+}
+
+/// Sends `length` bytes of `file`, starting at `offset`, to `socket`. On Linux the
+/// kernel moves them with `sendfile(2)` without copying them through user space;
+/// elsewhere, or for files `sendfile` cannot read, they go through `copy`.
+pub fn send_file(
+    file: &File,
+    offset: u64,
+    length: u64,
+    mut socket: &mut dyn Socket,
+) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    let sent = linux::send_file(file, offset, length, socket)?;
+    #[cfg(not(target_os = "linux"))]
+    let sent = 0;
+
+    if sent < length {
+        copy(file, offset + sent, length - sent, &mut socket)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    use super::Socket;
+
+    /// Most bytes a single `sendfile` call transfers.
+    const MAX_CHUNK: u64 = 0x7fff_f000;
+
+    /// Returns how much was sent, which is less than `length` only if the file
+    /// does not support `sendfile` and the rest has to be copied.
+    pub fn send_file(
+        file: &File,
+        offset: u64,
+        length: u64,
+        socket: &mut dyn Socket,
+    ) -> io::Result<u64> {
+        let mut sent = 0;
+        while sent < length {
+            let mut position = (offset + sent) as libc::off64_t;
+            let count = (length - sent).min(MAX_CHUNK) as usize;
+            let stream = socket.stream()?.as_raw_fd();
+            // SAFETY: both descriptors stay open for the duration of the call, and
+            // `position` is a valid offset the kernel may update
+            let result =
+                unsafe { libc::sendfile64(stream, file.as_raw_fd(), &mut position, count) };
+            match result {
+                -1 => {
+                    let error = io::Error::last_os_error();
+                    match error.raw_os_error() {
+                        Some(libc::EINTR) => continue,
+                        Some(libc::EINVAL) | Some(libc::ENOSYS) => return Ok(sent),
+                        _ => return Err(error),
+                    }
+                }
+                0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "file shrank while it was sent",
+                    ))
+                }
+                written => {
+                    sent += written as u64;
+                    socket.sent(written as u64);
+                }
+            }
+        }
+        Ok(sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Instant;
+
+    /// Runs `send` on one end of a loopback connection and `receive` on the other.
+    fn over_socket<S, R, T>(send: S, receive: R) -> T
+    where
+        S: FnOnce(&TcpStream) -> io::Result<()>,
+        R: FnOnce(TcpStream) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let receiver = thread::spawn(move || receive(listener.accept().unwrap().0));
+        let stream = TcpStream::connect(address).unwrap();
+        send(&stream).unwrap();
+        drop(stream);
+        receiver.join().unwrap()
+    }
+
+    fn received<S>(send: S) -> Vec<u8>
+    where
+        S: FnOnce(&TcpStream) -> io::Result<()>,
+    {
+        over_socket(send, |mut stream| {
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            received
+        })
+    }
+
+    fn test_file(name: &str, length: usize) -> (std::path::PathBuf, Vec<u8>) {
+        let directory = utils::temp_dir(name);
+        let contents: Vec<u8> = (0..length).map(|i| (i % 251) as u8).collect();
+        fs::write(directory.join("data.bin"), &contents).unwrap();
+        (directory, contents)
+    }
+
+    #[test]
+    fn send_file_sends_the_requested_range() {
+        let (directory, contents) = test_file("sendfile", 300_000);
+        let file = File::open(directory.join("data.bin")).unwrap();
+
+        let sent = received(|mut stream| send_file(&file, 0, 300_000, &mut stream));
+        assert!(sent == contents);
+        let sent = received(|mut stream| send_file(&file, 1000, 5000, &mut stream));
+        assert!(sent[..] == contents[1000..6000]);
+        let sent = received(|mut stream| copy(&file, 299_990, 10, &mut stream));
+        assert!(sent[..] == contents[299_990..]);
+
+        let result = received(|mut stream| {
+            let error = send_file(&file, 299_990, 20, &mut stream).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+            Ok(())
+        });
+        assert_eq!(result.len(), 10);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    /// Compares `send_file` with buffered copying. Run with
+    /// `cargo test --release sendfile_throughput -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn sendfile_throughput() {
+        const LENGTH: usize = 256 * 1024 * 1024;
+        const ROUNDS: u32 = 8;
+        let (directory, _) = test_file("sendfile-bench", LENGTH);
+        let file = File::open(directory.join("data.bin")).unwrap();
+
+        let measure = |name: &str, send: &dyn Fn(&TcpStream) -> io::Result<()>| {
+            let start = Instant::now();
+            for _ in 0..ROUNDS {
+                let length = over_socket(send, |mut stream| {
+                    io::copy(&mut stream, &mut io::sink()).unwrap()
+                });
+                assert_eq!(length, LENGTH as u64);
+            }
+            let seconds = start.elapsed().as_secs_f64();
+            let throughput = (LENGTH as f64 * ROUNDS as f64) / seconds / (1024.0 * 1024.0);
+            println!("{:>10}: {:>8.1} MiB/s", name, throughput);
+        };
+        measure("sendfile", &|mut stream| {
+            send_file(&file, 0, LENGTH as u64, &mut stream)
+        });
+        measure("buffered", &|mut stream| {
+            copy(&file, 0, LENGTH as u64, &mut stream)
+        });
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
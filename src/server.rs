@@ -1,88 +1,1071 @@
-use std::io::{BufRead, BufReader, Error, Read, Write};
+use std::io::{self, BufRead, BufReader, Error, Read, Write};
 use std::net::TcpStream;
+use std::time::Instant;
 
-use crate::handler;
-use crate::interface::{HttpResponse, InternalServerErrorResponse};
+use crate::config::{Limits, Timeouts};
+use crate::encoding::{self, DecodeError};
+use crate::handler::{self, RequestBody};
+use crate::interface::{
+    BadRequestResponse, ContinueResponse, ExpectationFailedResponse, FileResponse, HttpResponse,
+    InternalServerErrorResponse, NotImplementedResponse, PartialContentResponse,
+    PayloadTooLargeResponse, RequestHeaderFieldsTooLargeResponse, RequestTimeoutResponse,
+    UnsupportedMediaTypeResponse, UriTooLongResponse,
+};
+use crate::sendfile::Socket;
+use crate::sse::EventStreamResponse;
+use crate::utils;
+use crate::websocket::{WebSocket, WebSocketUpgradeResponse};
 
-pub fn reader(buf_reader: &mut BufReader<&TcpStream>) -> (String, Vec<String>, String) {
-    let mut index: i32 = 0;
-    let mut request_line = String::new();
+type Request = (String, Vec<String>, Vec<u8>);
+
+/// Longest chunk-size line accepted, chunk extensions included.
+const MAX_CHUNK_LINE: usize = 1024;
+
+/// A request source whose reads can be bounded by an overall deadline.
+pub trait Deadline {
+    fn set_deadline(&mut self, deadline: Option<Instant>);
+}
+
+/// Socket reader that fails with `TimedOut` once its deadline has passed, however
+/// slowly the bytes trickle in. A plain read timeout only bounds each single read.
+struct TimedStream<'a> {
+    stream: &'a TcpStream,
+    deadline: Option<Instant>,
+}
+
+impl Read for TimedStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = match self.deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+                Some(remaining)
+            }
+            None => None,
+        };
+        self.stream.set_read_timeout(timeout)?;
+
+        let mut stream = self.stream;
+        stream.read(buf)
+    }
+}
+
+impl Deadline for TimedStream<'_> {
+    fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+}
+
+/// Socket writer that holds a response to the minimum write rate: by any point,
+/// the time taken may not exceed the write timeout for what has been sent so far.
+/// A plain write timeout only bounds each single write, so a client that reads a
+/// few bytes now and then could keep a large response going for ever.
+struct TimedWriter<'a> {
+    stream: &'a TcpStream,
+    timeouts: &'a Timeouts,
+    start: Instant,
+    sent: u64,
+}
+
+impl<'a> TimedWriter<'a> {
+    fn new(stream: &'a TcpStream, timeouts: &'a Timeouts) -> Self {
+        Self {
+            stream,
+            timeouts,
+            start: Instant::now(),
+            sent: 0,
+        }
+    }
+
+    /// Bounds the next write by what is left of the time allowed.
+    fn arm(&self) -> io::Result<()> {
+        let deadline = self.start + self.timeouts.write_timeout(self.sent);
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_write_timeout(Some(remaining))
+    }
+}
+
+impl Write for TimedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.arm()?;
+        let mut stream = self.stream;
+        let written = stream.write(buf)?;
+        self.sent += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut stream = self.stream;
+        stream.flush()
+    }
+}
+
+impl Socket for TimedWriter<'_> {
+    fn stream(&mut self) -> io::Result<&TcpStream> {
+        self.arm()?;
+        Ok(self.stream)
+    }
+
+    fn sent(&mut self, length: u64) {
+        self.sent += length;
+    }
+}
+
+impl Drop for TimedWriter<'_> {
+    fn drop(&mut self) {
+        // Whatever is written next is back to the plain per-write timeout
+        let _ = self.stream.set_write_timeout(Some(self.timeouts.write));
+    }
+}
+
+impl<T: Read + Deadline> Deadline for BufReader<T> {
+    fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.get_mut().set_deadline(deadline);
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    // Socket read timeouts surface as `WouldBlock` on Unix and `TimedOut` on Windows
+    matches!(
+        e.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    )
+}
+
+enum Line {
+    Complete(String),
+    TooLong,
+    Eof,
+}
+
+/// Reads a single CRLF terminated line, refusing to buffer more than `limit` bytes of it.
+fn read_line<R: BufRead>(buf_reader: &mut R, limit: usize) -> io::Result<Line> {
+    let mut line = Vec::new();
+    // Two extra bytes leave room for the line terminator itself
+    let read = buf_reader
+        .by_ref()
+        .take(limit as u64 + 2)
+        .read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(Line::Eof);
+    }
+
+    if line.ends_with(b"\n") {
+        line.pop();
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+    }
+    if line.len() > limit {
+        return Ok(Line::TooLong);
+    }
+
+    Ok(Line::Complete(String::from_utf8_lossy(&line).into_owned()))
+}
+
+/// Reads one request from the connection.
+///
+/// Returns `Err` with a final response when the request is refused before its body
+/// is read: it breaks one of the `limits`, arrives slower than `timeouts` allow, or
+/// announced an `Expect: 100-continue` upload we would reject anyway.
+pub fn reader<R: BufRead + Deadline, W: Write>(
+    buf_reader: &mut R,
+    writer: &mut W,
+    limits: &Limits,
+    timeouts: &Timeouts,
+) -> Result<Request, Box<dyn HttpResponse>> {
     let mut headers = Vec::new();
-    let mut request_body = String::new();
+    let mut request_body = Vec::new();
 
-    let mut content_length: Option<usize> = None;
+    buf_reader.set_deadline(Some(Instant::now() + timeouts.header));
 
-    for line in buf_reader.by_ref().lines() {
-        let line = match line {
-            Ok(line) => line,
+    let request_line = match read_line(buf_reader, limits.max_request_line) {
+        Ok(Line::Complete(line)) => line,
+        Ok(Line::TooLong) => return Err(Box::new(UriTooLongResponse)),
+        Ok(Line::Eof) => String::new(),
+        Err(e) if is_timeout(&e) => return Err(Box::new(RequestTimeoutResponse)),
+        Err(e) => {
+            println!("Error reading line: {}", e);
+            String::new()
+        }
+    };
+
+    let mut header_bytes = 0;
+    loop {
+        let remaining = limits.max_header_bytes.saturating_sub(header_bytes);
+        let line = match read_line(buf_reader, remaining) {
+            Ok(Line::Complete(line)) => line,
+            Ok(Line::TooLong) => return Err(Box::new(RequestHeaderFieldsTooLargeResponse)),
+            Ok(Line::Eof) => break,
+            Err(e) if is_timeout(&e) => return Err(Box::new(RequestTimeoutResponse)),
             Err(e) => {
                 println!("Error reading line: {}", e);
-                return (request_line, headers, request_body);
+                return Ok((request_line, headers, request_body));
             }
         };
-        if index == 0 {
-            request_line = line;
-        } else {
-            if line.is_empty() {
-                break;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() >= limits.max_headers {
+            return Err(Box::new(RequestHeaderFieldsTooLargeResponse));
+        }
+        header_bytes += line.len() + 2;
+        headers.push(line);
+    }
+
+    let framing = framing(&headers)?;
+    let streamed_limit = streamed_body_limit(&request_line, &headers);
+    let max_body_size = streamed_limit.unwrap_or(limits.max_body_size);
+    if matches!(framing, Framing::Length(length) if length > max_body_size) {
+        return Err(Box::new(PayloadTooLargeResponse));
+    }
+
+    // Refuse codings we cannot decode before the client sends the body
+    let content_encoding = utils::get_header(&headers, "Content-Encoding")
+        .filter(|_| limits.decode_bodies)
+        .map(|coding| coding.to_string());
+    if let Some(coding) = &content_encoding {
+        if encoding::parse_content_encoding(coding).is_err() {
+            return Err(Box::new(UnsupportedMediaTypeResponse));
+        }
+    }
+
+    // Clients that send `Expect: 100-continue` wait for our go-ahead before the body.
+    // Without a body there is nothing to wait for, and HTTP/1.0 clients must not be
+    // sent a 1xx response at all (RFC 9110 section 10.1.1).
+    if let Some(expectation) = utils::get_header(&headers, "Expect") {
+        if !expectation.eq_ignore_ascii_case("100-continue") {
+            return Err(Box::new(ExpectationFailedResponse));
+        }
+        if request_line.ends_with("HTTP/1.1") && framing != Framing::Empty {
+            if let Some(rejection) = handler::check_expectation(&request_line) {
+                return Err(rejection);
             }
-            // Check for Content-Length header
-            if let Some(length) = line.strip_prefix("Content-Length: ") {
-                content_length = length.parse().ok();
+            if let Err(e) = ContinueResponse.write_to(writer) {
+                println!("Error writing 100 Continue: {}", e);
+                return Ok((request_line, headers, request_body));
             }
-            headers.push(line);
         }
+    }
 
-        index += 1;
+    // Left on the connection for the handler, see `handle_connection`
+    if streamed_limit.is_some() {
+        return Ok((request_line, headers, request_body));
     }
 
-    // Read the request body if Content-Length header is present
-    if let Some(length) = content_length {
-        let mut body = vec![0; length];
-        if let Err(e) = buf_reader.read_exact(&mut body) {
-            println!("Error reading request body: {}", e);
-            return (request_line, headers, request_body);
+    let body = match framing {
+        Framing::Empty => None,
+        Framing::Length(length) => {
+            buf_reader.set_deadline(Some(Instant::now() + timeouts.body_timeout(length)));
+            let mut body = vec![0; length];
+            buf_reader.read_exact(&mut body).map_err(body_read_error)?;
+            Some(body)
         }
-        request_body = match String::from_utf8(body) {
-            Ok(body) => body,
-            Err(e) => {
-                println!("Error converting request body to string: {}", e);
-                return (request_line, headers, request_body);
-            }
-        };
+        Framing::Chunked => Some(read_chunked(buf_reader, limits, timeouts)?),
+    };
+
+    if let Some(mut body) = body {
+        if let Some(coding) = &content_encoding {
+            body = match encoding::decode(coding, body, limits.max_decoded_body_size) {
+                Ok(body) => body,
+                Err(DecodeError::TooLarge(_)) => return Err(Box::new(PayloadTooLargeResponse)),
+                Err(DecodeError::Unsupported(_)) => {
+                    return Err(Box::new(UnsupportedMediaTypeResponse))
+                }
+                Err(DecodeError::Malformed(e)) => {
+                    println!("Error decoding request body: {}", e);
+                    return Err(Box::new(BadRequestResponse));
+                }
+            };
+            headers.retain(|header| !is_header(header, "Content-Encoding"));
+        }
+        // Handlers see the body as if it had been sent unencoded and in one piece
+        if content_encoding.is_some() || framing == Framing::Chunked {
+            headers.retain(|header| {
+                !is_header(header, "Transfer-Encoding") && !is_header(header, "Content-Length")
+            });
+            headers.push(format!("Content-Length: {}", body.len()));
+        }
+        request_body = body;
     }
 
     println!(
         "received request: {:?}, headers: {:?}, request body: {:?}",
-        request_line, headers, request_body
+        request_line,
+        headers,
+        String::from_utf8_lossy(&request_body)
     );
 
-    (request_line, headers, request_body)
+    Ok((request_line, headers, request_body))
 }
 
-pub fn process_request(stream: Result<TcpStream, Error>) {
-    match stream {
-        Ok(mut stream) => {
-            println!("accepted new connection");
+/// How the end of a request's body is found, following RFC 9112 section 6.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    Empty,
+    Length(usize),
+    Chunked,
+}
+
+/// Works out the framing of a request's body. Anything ambiguous is refused,
+/// since a proxy in front of us could read it differently and take the rest of
+/// the body for another request: a `Content-Length` that is not a number, several
+/// differing ones, or one next to a `Transfer-Encoding`. Of the transfer codings
+/// only `chunked` on its own is supported.
+fn framing(headers: &[String]) -> Result<Framing, Box<dyn HttpResponse>> {
+    let values = |name: &str| -> Vec<&str> {
+        headers
+            .iter()
+            .filter(|header| is_header(header, name))
+            .filter_map(|header| header.split_once(':'))
+            .flat_map(|(_, value)| value.split(','))
+            .map(str::trim)
+            .collect()
+    };
+    let transfer_codings = values("Transfer-Encoding");
+    let lengths = values("Content-Length");
+
+    if !transfer_codings.is_empty() {
+        if !lengths.is_empty() {
+            return Err(Box::new(BadRequestResponse));
+        }
+        return match transfer_codings.as_slice() {
+            [coding] if coding.eq_ignore_ascii_case("chunked") => Ok(Framing::Chunked),
+            _ => Err(Box::new(NotImplementedResponse)),
+        };
+    }
+
+    let mut framing = Framing::Empty;
+    for value in lengths {
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(Box::new(BadRequestResponse));
+        }
+        // Only a length beyond any limit has this many digits
+        let length = match value.parse() {
+            Ok(length) => length,
+            Err(_) => return Err(Box::new(PayloadTooLargeResponse)),
+        };
+        if matches!(framing, Framing::Length(other) if other != length) {
+            return Err(Box::new(BadRequestResponse));
+        }
+        framing = Framing::Length(length);
+    }
+    Ok(framing)
+}
+
+/// The response to a body that could not be read in full.
+fn body_read_error(e: io::Error) -> Box<dyn HttpResponse> {
+    if is_timeout(&e) {
+        return Box::new(RequestTimeoutResponse);
+    }
+    println!("Error reading request body: {}", e);
+    Box::new(BadRequestResponse)
+}
+
+/// Reads a `Transfer-Encoding: chunked` body following RFC 9112 section 7.1,
+/// dropping chunk extensions and trailer fields. The limits and the minimum body
+/// rate apply to the decoded body as they do to one with a `Content-Length`.
+fn read_chunked<R: BufRead + Deadline>(
+    buf_reader: &mut R,
+    limits: &Limits,
+    timeouts: &Timeouts,
+) -> Result<Vec<u8>, Box<dyn HttpResponse>> {
+    let start = Instant::now();
+    let mut body = Vec::new();
+    loop {
+        buf_reader.set_deadline(Some(start + timeouts.body_timeout(body.len())));
+        let line = match read_line(buf_reader, MAX_CHUNK_LINE).map_err(body_read_error)? {
+            Line::Complete(line) => line,
+            Line::TooLong | Line::Eof => return Err(Box::new(BadRequestResponse)),
+        };
+        let size = line.split(';').next().unwrap_or_default().trim();
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(Box::new(BadRequestResponse));
+        }
+        let size = match usize::from_str_radix(size, 16) {
+            Ok(0) => break,
+            Ok(size) if size <= limits.max_body_size - body.len() => size,
+            _ => return Err(Box::new(PayloadTooLargeResponse)),
+        };
+
+        buf_reader.set_deadline(Some(start + timeouts.body_timeout(body.len() + size)));
+        let end = body.len();
+        body.resize(end + size, 0);
+        buf_reader
+            .read_exact(&mut body[end..])
+            .map_err(body_read_error)?;
+        // Each chunk's data ends with a CRLF of its own
+        match read_line(buf_reader, 0).map_err(body_read_error)? {
+            Line::Complete(line) if line.is_empty() => {}
+            _ => return Err(Box::new(BadRequestResponse)),
+        }
+    }
+
+    let mut trailer_bytes = 0;
+    loop {
+        let remaining = limits.max_header_bytes.saturating_sub(trailer_bytes);
+        match read_line(buf_reader, remaining).map_err(body_read_error)? {
+            Line::Complete(line) if line.is_empty() => return Ok(body),
+            Line::Complete(line) => trailer_bytes += line.len() + 2,
+            Line::TooLong => return Err(Box::new(RequestHeaderFieldsTooLargeResponse)),
+            Line::Eof => return Err(Box::new(BadRequestResponse)),
+        }
+    }
+}
+
+/// The limit on a body that the handler reads from the connection itself, for
+/// requests that have one. Bodies with a `Content-Encoding` or sent in chunks are
+/// always read in full first.
+fn streamed_body_limit(request_line: &str, headers: &[String]) -> Option<usize> {
+    if utils::get_header(headers, "Content-Encoding").is_some()
+        || utils::get_header(headers, "Transfer-Encoding").is_some()
+    {
+        return None;
+    }
+    handler::streamed_body_limit(request_line, headers)
+}
 
-            let mut buf_reader = BufReader::new(&stream);
-            let (request_line, headers, request_body) = reader(&mut buf_reader);
+fn is_header(line: &str, name: &str) -> bool {
+    line.split_once(':')
+        .is_some_and(|(header, _)| header.trim().eq_ignore_ascii_case(name))
+}
+
+/// Whether the client wants the connection kept open after this request.
+fn wants_keep_alive(request_line: &str, headers: &[String]) -> bool {
+    if utils::header_has_token(headers, "Connection", "close") {
+        return false;
+    }
+    // HTTP/1.1 connections are persistent by default, HTTP/1.0 ones have to opt in
+    request_line.ends_with("HTTP/1.1")
+        || utils::header_has_token(headers, "Connection", "keep-alive")
+}
 
-            let response =
-                match handler::handle_http_request(&request_line, &headers, &request_body) {
-                    Ok(response) => response,
-                    Err(e) => {
-                        println!("Error processing request: {:?}", e);
-                        InternalServerErrorResponse.response()
+fn handle_connection(stream: &TcpStream, limits: &Limits, timeouts: &Timeouts) {
+    if let Err(e) = stream.set_write_timeout(Some(timeouts.write)) {
+        println!("Error setting write timeout: {}", e);
+        return;
+    }
+
+    let mut buf_reader = BufReader::new(TimedStream {
+        stream,
+        deadline: None,
+    });
+    let mut writer = stream;
+
+    // A new connection must start its request within the header timeout; between
+    // requests on a kept-alive connection the idle timeout applies instead.
+    let mut idle_timeout = timeouts.header;
+    let mut first_request = true;
+
+    loop {
+        buf_reader.set_deadline(Some(Instant::now() + idle_timeout));
+        match buf_reader.fill_buf() {
+            Ok([]) => return,
+            Ok(_) => {}
+            Err(e) if is_timeout(&e) => {
+                if first_request {
+                    let _ = RequestTimeoutResponse.write_to(&mut writer);
+                }
+                return;
+            }
+            Err(e) => {
+                println!("Error reading request: {}", e);
+                return;
+            }
+        }
+
+        let (response, keep_alive): (Box<dyn HttpResponse>, bool) =
+            match reader(&mut buf_reader, &mut writer, limits, timeouts) {
+                Ok((request_line, headers, request_body)) => {
+                    let mut keep_alive = wants_keep_alive(&request_line, &headers);
+                    let routed = match streamed_body_limit(&request_line, &headers) {
+                        Some(_) => {
+                            let length = match framing(&headers) {
+                                Ok(Framing::Length(length)) => length,
+                                _ => 0,
+                            };
+                            buf_reader
+                                .set_deadline(Some(Instant::now() + timeouts.body_timeout(length)));
+                            let mut body = (&mut buf_reader).take(length as u64);
+                            let routed = handler::route_request(
+                                &request_line,
+                                &headers,
+                                RequestBody::Stream(&mut body),
+                            );
+                            // Whatever the handler left unread would pass for the next request
+                            keep_alive &= body.limit() == 0;
+                            routed
+                        }
+                        None => handler::route_request(
+                            &request_line,
+                            &headers,
+                            RequestBody::Bytes(&request_body),
+                        ),
+                    };
+                    match routed {
+                        Ok(response) => (response, keep_alive),
+                        Err(e) => {
+                            println!("Error processing request: {:?}", e);
+                            (Box::new(InternalServerErrorResponse), false)
+                        }
                     }
-                };
+                }
+                // The rest of a refused request is never read, so the connection is unusable
+                Err(response) => (response, false),
+            };
+
+        buf_reader.set_deadline(None);
 
-            if let Err(e) = stream.write(&response) {
-                println!("Error writing response: {}", e);
+        // Responses have to go out at the minimum write rate, with files going
+        // straight from disk to the socket. Event streams stay open for as long as
+        // they have events, so only their single writes are bounded.
+        let written = {
+            let mut timed = TimedWriter::new(stream, timeouts);
+            let any = response.as_any();
+            if let Some(file) = any.downcast_ref::<FileResponse>() {
+                file.send_to(&mut timed)
+            } else if let Some(partial) = any.downcast_ref::<PartialContentResponse>() {
+                partial.send_to(&mut timed)
+            } else if any.is::<EventStreamResponse>() {
+                response.write_to(&mut writer)
+            } else {
+                response.write_to(&mut timed)
             }
+        };
+        if let Err(e) = written {
+            println!("Error writing response: {}", e);
+            return;
+        }
+
+        // The handler accepted a WebSocket: hand the connection over to it
+        if let Some(upgrade) = response.as_any().downcast_ref::<WebSocketUpgradeResponse>() {
+            let mut socket = WebSocket::new(&mut buf_reader, &mut writer)
+                .with_idle_timeout(timeouts.websocket_idle);
+            upgrade.run(&mut socket);
+            return;
+        }
+
+        // Event streams are delimited by closing the connection
+        if !keep_alive || response.as_any().is::<EventStreamResponse>() {
+            return;
+        }
+
+        idle_timeout = timeouts.keep_alive;
+        first_request = false;
+    }
+}
+
+pub fn process_request(stream: Result<TcpStream, Error>) {
+    match stream {
+        Ok(stream) => {
+            println!("accepted new connection");
+            handle_connection(&stream, &Limits::from_env(), &Timeouts::from_env());
         }
         Err(e) => {
             println!("error: {}", e);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use std::io::Cursor;
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+    use std::time::Duration;
+
+    impl Deadline for Cursor<Vec<u8>> {
+        fn set_deadline(&mut self, _deadline: Option<Instant>) {}
+    }
+
+    fn read_with(input: &str, limits: &Limits) -> (Result<Request, Box<dyn HttpResponse>>, String) {
+        let mut buf_reader = Cursor::new(input.as_bytes().to_vec());
+        let mut output = Vec::new();
+        let request = reader(&mut buf_reader, &mut output, limits, &Timeouts::default());
+        (request, String::from_utf8(output).unwrap())
+    }
+
+    // Serves a single connection with the given timeouts on an ephemeral port
+    fn serve_once(timeouts: Timeouts) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(&stream, &Limits::default(), &timeouts);
+        });
+        addr
+    }
+
+    fn short_timeouts() -> Timeouts {
+        Timeouts {
+            header: Duration::from_millis(200),
+            body: Duration::from_millis(200),
+            min_body_rate: 1024 * 1024,
+            write: Duration::from_secs(1),
+            min_write_rate: 1024 * 1024,
+            keep_alive: Duration::from_millis(200),
+            websocket_idle: Duration::from_millis(200),
+        }
+    }
+
+    fn read(input: &str) -> (Result<Request, Box<dyn HttpResponse>>, String) {
+        read_with(input, &Limits::default())
+    }
+
+    fn rejection_status(request: Result<Request, Box<dyn HttpResponse>>) -> String {
+        let response = request.err().unwrap().response();
+        String::from_utf8(response).unwrap()[9..12].to_string()
+    }
+
+    #[test]
+    fn reader_parses_request() {
+        let (request, output) =
+            read("POST /echo/abc HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\n\r\nabc");
+        let (request_line, headers, body) = request.ok().unwrap();
+        assert_eq!(request_line, "POST /echo/abc HTTP/1.1");
+        assert_eq!(headers, vec!["Host: localhost", "Content-Length: 3"]);
+        assert_eq!(body, b"abc");
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn reader_sends_100_continue() {
+        let (request, output) =
+            read("POST /echo/abc HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 3\r\n\r\nabc");
+        assert_eq!(request.ok().unwrap().2, b"abc");
+        assert_eq!(output, "HTTP/1.1 100 Continue\r\n\r\n");
+
+        // Ignored for HTTP/1.0 clients, and for requests without a body
+        let (request, output) =
+            read("POST /echo/abc HTTP/1.0\r\nExpect: 100-continue\r\nContent-Length: 3\r\n\r\nabc");
+        assert_eq!(request.ok().unwrap().2, b"abc");
+        assert!(output.is_empty());
+
+        let (request, output) = read("GET /echo/abc HTTP/1.1\r\nExpect: 100-continue\r\n\r\n");
+        assert!(request.ok().unwrap().2.is_empty());
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn reader_rejects_oversized_expect_continue() {
+        let input = format!(
+            "POST /files/big HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: {}\r\n\r\n",
+            Limits::default().max_body_size + 1
+        );
+        let (request, output) = read(&input);
+        assert_eq!(rejection_status(request), "413");
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn reader_enforces_limits() {
+        let limits = Limits {
+            max_request_line: 32,
+            max_headers: 2,
+            max_header_bytes: 40,
+            max_body_size: 4,
+            ..Limits::default()
+        };
+
+        let long_uri = format!("GET /echo/{} HTTP/1.1\r\n\r\n", "a".repeat(32));
+        assert_eq!(rejection_status(read_with(&long_uri, &limits).0), "414");
+
+        let many_headers = "GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+        assert_eq!(rejection_status(read_with(many_headers, &limits).0), "431");
+
+        let long_header = format!("GET / HTTP/1.1\r\nA: {}\r\n\r\n", "a".repeat(40));
+        assert_eq!(rejection_status(read_with(&long_header, &limits).0), "431");
+
+        let big_body = "POST /echo/a HTTP/1.1\r\nContent-Length: 5\r\n\r\nabcde";
+        assert_eq!(rejection_status(read_with(big_body, &limits).0), "413");
+
+        let within_limits = "POST /echo/a HTTP/1.1\r\nContent-Length: 4\r\n\r\nabcd";
+        assert_eq!(read_with(within_limits, &limits).0.ok().unwrap().2, b"abcd");
+    }
+
+    fn read_bytes(input: &[u8], limits: &Limits) -> Result<Request, Box<dyn HttpResponse>> {
+        let mut buf_reader = Cursor::new(input.to_vec());
+        reader(
+            &mut buf_reader,
+            &mut Vec::new(),
+            limits,
+            &Timeouts::default(),
+        )
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn encoded_upload(coding: &str, body: &[u8]) -> Vec<u8> {
+        let mut request = format!(
+            "POST /files/upload HTTP/1.1\r\nContent-Encoding: {}\r\nContent-Length: {}\r\n\r\n",
+            coding,
+            body.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(body);
+        request
+    }
+
+    #[test]
+    fn reader_decodes_request_body() {
+        let input = encoded_upload("gzip", &gzip(b"hello world"));
+        let (_, headers, body) = read_bytes(&input, &Limits::default()).ok().unwrap();
+        assert_eq!(body, b"hello world");
+        assert_eq!(headers, vec!["Content-Length: 11"]);
+
+        // Decoding can be turned off, leaving the body to the handlers as sent
+        let limits = Limits {
+            decode_bodies: false,
+            ..Limits::default()
+        };
+        let input = encoded_upload("compress", b"abc");
+        assert_eq!(read_bytes(&input, &limits).ok().unwrap().2, b"abc");
+    }
+
+    #[test]
+    fn reader_rejects_undecodable_body() {
+        let limits = Limits {
+            max_decoded_body_size: 1024,
+            ..Limits::default()
+        };
+        let status = |input: Vec<u8>| rejection_status(read_bytes(&input, &limits));
+
+        assert_eq!(status(encoded_upload("compress", b"abc")), "415");
+        assert_eq!(status(encoded_upload("gzip", &gzip(&[0; 4096]))), "413");
+        assert_eq!(status(encoded_upload("gzip", b"not gzip")), "400");
+    }
+
+    #[test]
+    fn reader_rejects_unknown_expectation() {
+        let (request, output) = read("POST /echo/abc HTTP/1.1\r\nExpect: magic\r\n\r\n");
+        assert_eq!(rejection_status(request), "417");
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn reader_rejects_ambiguous_framing() {
+        let status = |headers: &str| {
+            let input = format!("POST /echo/a HTTP/1.1\r\n{}\r\n\r\nabcde", headers);
+            rejection_status(read(&input).0)
+        };
+
+        assert_eq!(status("Content-Length: abc"), "400");
+        assert_eq!(status("Content-Length: +5"), "400");
+        assert_eq!(status("Content-Length: 5\r\nContent-Length: 6"), "400");
+        assert_eq!(status("Content-Length: 5, 6"), "400");
+        assert_eq!(status("Content-Length: 99999999999999999999999"), "413");
+        assert_eq!(
+            status("Transfer-Encoding: chunked\r\nContent-Length: 5"),
+            "400"
+        );
+        assert_eq!(status("Transfer-Encoding: gzip"), "501");
+        assert_eq!(status("Transfer-Encoding: gzip, chunked"), "501");
+
+        // Repeating the same length is harmless
+        let input = "POST /echo/a HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nabcde";
+        assert_eq!(read(input).0.ok().unwrap().2, b"abcde");
+
+        // So is a body cut short, but the connection cannot be used after it
+        let input = "POST /echo/a HTTP/1.1\r\nContent-Length: 5\r\n\r\nabc";
+        assert_eq!(rejection_status(read(input).0), "400");
+    }
+
+    #[test]
+    fn reader_decodes_chunked_body() {
+        let input = "POST /files/a HTTP/1.1\r\nTransfer-Encoding: Chunked\r\n\r\n\
+                     3;name=value\r\nabc\r\nA\r\n0123456789\r\n0\r\nChecksum: x\r\n\r\n";
+        let (_, headers, body) = read(input).0.ok().unwrap();
+        assert_eq!(body, b"abc0123456789");
+        assert_eq!(headers, vec!["Content-Length: 13"]);
+
+        let limits = Limits {
+            max_body_size: 4,
+            ..Limits::default()
+        };
+        let status = |chunks: &str| {
+            let input = format!(
+                "POST /files/a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{}",
+                chunks
+            );
+            rejection_status(read_with(&input, &limits).0)
+        };
+        assert_eq!(status("3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n"), "413");
+        assert_eq!(status("ffffffffffffffffffff\r\n"), "413");
+        assert_eq!(status("x\r\nabc\r\n0\r\n\r\n"), "400");
+        assert_eq!(status("3\r\nabcd\r\n0\r\n\r\n"), "400");
+        assert_eq!(status("3\r\nabc\r\n"), "400");
+    }
+
+    #[test]
+    fn connection_is_closed_after_a_framing_error() {
+        let cases = [
+            ("Content-Length: abc", "HTTP/1.1 400 Bad Request\r\n"),
+            (
+                "Content-Length: 3\r\nContent-Length: 30",
+                "HTTP/1.1 400 Bad Request\r\n",
+            ),
+            (
+                "Transfer-Encoding: chunked\r\nContent-Length: 3",
+                "HTTP/1.1 400 Bad Request\r\n",
+            ),
+            (
+                "Transfer-Encoding: gzip",
+                "HTTP/1.1 501 Not Implemented\r\n",
+            ),
+        ];
+        for (headers, expected) in cases {
+            let mut client = TcpStream::connect(serve_once(short_timeouts())).unwrap();
+            let mut buf_reader = BufReader::new(client.try_clone().unwrap());
+
+            // Whatever follows must not be taken for a request of its own
+            let request = format!(
+                "POST /echo/a HTTP/1.1\r\n{}\r\n\r\nabc\r\n\r\nGET /echo/b HTTP/1.1\r\n\r\n",
+                headers
+            );
+            client.write_all(request.as_bytes()).unwrap();
+            let (status, _) = read_response(&mut buf_reader);
+            assert_eq!(status, expected, "{}", headers);
+
+            let mut rest = Vec::new();
+            buf_reader.read_to_end(&mut rest).unwrap();
+            assert!(rest.is_empty(), "{}", headers);
+        }
+    }
+
+    #[test]
+    fn chunked_body_keeps_the_connection_in_step() {
+        let mut client = TcpStream::connect(serve_once(short_timeouts())).unwrap();
+        let mut buf_reader = BufReader::new(client.try_clone().unwrap());
+
+        client
+            .write_all(
+                b"POST /echo/a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                  5\r\nGET /\r\n0\r\n\r\nGET /echo/b HTTP/1.1\r\n\r\n",
+            )
+            .unwrap();
+        for expected in ["a", "b"] {
+            let (status, body) = read_response(&mut buf_reader);
+            assert_eq!(status, "HTTP/1.1 200 OK\r\n");
+            assert_eq!(body, expected.as_bytes());
+        }
+    }
+
+    #[test]
+    fn connection_times_out_slow_headers() {
+        let mut client = TcpStream::connect(serve_once(short_timeouts())).unwrap();
+
+        // Trickle the request in without ever finishing the headers
+        client.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let _ = client.write_all(b"Host: localhost\r\n");
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+
+    #[test]
+    fn connection_times_out_slow_body() {
+        let mut client = TcpStream::connect(serve_once(short_timeouts())).unwrap();
+
+        client
+            .write_all(b"POST /echo/a HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc")
+            .unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+
+    #[test]
+    fn connection_cuts_off_slow_readers() {
+        let _env = utils::env_lock();
+        let directory = utils::temp_dir("slow-reader");
+        let length = 256 * 1024 * 1024;
+        File::create(directory.join("large.bin"))
+            .unwrap()
+            .set_len(length)
+            .unwrap();
+        std::env::set_var("APP_DIRECTORY", &directory);
+        let timeouts = Timeouts {
+            min_write_rate: 128 * 1024 * 1024,
+            ..short_timeouts()
+        };
+
+        // One client stops reading altogether, the other reads a little now and then
+        for trickle in [false, true] {
+            let mut client = TcpStream::connect(serve_once(timeouts.clone())).unwrap();
+            client
+                .write_all(b"GET /files/large.bin HTTP/1.1\r\n\r\n")
+                .unwrap();
+
+            let start = Instant::now();
+            let mut received = Vec::new();
+            while start.elapsed() < Duration::from_secs(2) {
+                if trickle {
+                    let mut buf = [0; 16 * 1024];
+                    let read = client.read(&mut buf).unwrap();
+                    received.extend_from_slice(&buf[..read]);
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+
+            // The server gave up long before the whole file was sent
+            client.read_to_end(&mut received).unwrap();
+            assert!(received.starts_with(b"HTTP/1.1 200 OK\r\n"));
+            assert!((received.len() as u64) < length, "trickle: {}", trickle);
+        }
+
+        std::env::remove_var("APP_DIRECTORY");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    /// Reads one response with a `Content-Length` and returns its status line and body.
+    fn read_response<R: BufRead>(buf_reader: &mut R) -> (String, Vec<u8>) {
+        let mut status = String::new();
+        buf_reader.read_line(&mut status).unwrap();
+
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            buf_reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                length = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; length];
+        buf_reader.read_exact(&mut body).unwrap();
+        (status, body)
+    }
+
+    #[test]
+    fn connection_is_kept_alive_until_idle() {
+        let mut client = TcpStream::connect(serve_once(short_timeouts())).unwrap();
+        let mut buf_reader = BufReader::new(client.try_clone().unwrap());
+
+        for expected in ["abc", "xyz"] {
+            let request = format!("GET /echo/{} HTTP/1.1\r\n\r\n", expected);
+            client.write_all(request.as_bytes()).unwrap();
+
+            let (status, body) = read_response(&mut buf_reader);
+            assert_eq!(status, "HTTP/1.1 200 OK\r\n");
+            assert_eq!(body, expected.as_bytes());
+        }
+
+        // The idle connection is closed by the server without a response
+        let mut rest = Vec::new();
+        buf_reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn idle_websocket_is_closed() {
+        let mut client = TcpStream::connect(serve_once(short_timeouts())).unwrap();
+
+        client
+            .write_all(
+                b"GET /ws/echo HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                  Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            )
+            .unwrap();
+
+        // Say nothing after the handshake: the server gives up with 1001 Going Away
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.ends_with(b"\r\n\r\n\x88\x02\x03\xE9"));
+    }
+
+    fn form_upload(body: &str) -> String {
+        format!(
+            "POST /files/ HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=xyz\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    #[test]
+    fn reader_leaves_form_uploads_to_the_handler() {
+        let _env = utils::env_lock();
+        std::env::set_var("APP_MAX_FORM_SIZE", "64");
+        let limits = Limits {
+            max_body_size: 4,
+            ..Limits::default()
+        };
+
+        // Bounded by the form limit instead, and not read before the handler runs
+        let input = form_upload(&"a".repeat(64));
+        let mut buf_reader = Cursor::new(input.as_bytes().to_vec());
+        let request = reader(
+            &mut buf_reader,
+            &mut Vec::new(),
+            &limits,
+            &Timeouts::default(),
+        );
+        assert!(request.ok().unwrap().2.is_empty());
+        assert_eq!(buf_reader.position() as usize, input.len() - 64);
+
+        let input = form_upload(&"a".repeat(65));
+        assert_eq!(rejection_status(read_with(&input, &limits).0), "413");
+
+        std::env::remove_var("APP_MAX_FORM_SIZE");
+    }
+
+    #[test]
+    fn form_upload_is_streamed_on_a_kept_alive_connection() {
+        let _env = utils::env_lock();
+        let directory = utils::temp_dir("streamed-form");
+        std::env::set_var("APP_DIRECTORY", &directory);
+        let mut client = TcpStream::connect(serve_once(short_timeouts())).unwrap();
+        let mut buf_reader = BufReader::new(client.try_clone().unwrap());
+
+        let form =
+            "--xyz\r\nContent-Disposition: form-data; name=\"f\"; filename=\"a.txt\"\r\n\r\n\
+                    streamed\r\n--xyz--\r\n";
+        client.write_all(form_upload(form).as_bytes()).unwrap();
+        let (status, body) = read_response(&mut buf_reader);
+        assert_eq!(status, "HTTP/1.1 200 OK\r\n");
+        assert!(String::from_utf8(body)
+            .unwrap()
+            .contains("\"name\":\"a.txt\""));
+
+        client
+            .write_all(b"GET /files/a.txt HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let (status, body) = read_response(&mut buf_reader);
+        assert_eq!(status, "HTTP/1.1 200 OK\r\n");
+        assert_eq!(body, b"streamed");
+
+        std::env::remove_var("APP_DIRECTORY");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn wants_keep_alive_follows_connection_header() {
+        assert!(wants_keep_alive("GET / HTTP/1.1", &[]));
+        assert!(!wants_keep_alive(
+            "GET / HTTP/1.1",
+            &["Connection: close".to_string()]
+        ));
+        assert!(!wants_keep_alive("GET / HTTP/1.0", &[]));
+        assert!(wants_keep_alive(
+            "GET / HTTP/1.0",
+            &["Connection: Keep-Alive".to_string()]
+        ));
+    }
+}
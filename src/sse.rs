@@ -0,0 +1,279 @@
+use std::any::Any;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::interface::HttpResponse;
+use crate::utils;
+
+/// How long a stream may stay silent before we send a keep-alive comment.
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long a stream is kept open. Browsers reconnect on their own, with the
+/// `Last-Event-ID` they saw, so this only bounds what a vanished client can hold.
+pub const MAX_DURATION: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: String,
+    retry: Option<u64>,
+}
+
+impl Event {
+    pub fn new<B: Into<String>>(data: B) -> Self {
+        Self {
+            id: None,
+            event: None,
+            data: data.into(),
+            retry: None,
+        }
+    }
+
+    pub fn with_id<B: Into<String>>(mut self, id: B) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    pub fn with_event<B: Into<String>>(mut self, event: B) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    /// Reconnection delay (in milliseconds) the browser should use if the stream drops.
+    pub fn with_retry(mut self, retry: u64) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Serializes the event in `text/event-stream` format, including the blank line
+    /// that dispatches it.
+    pub fn encode(&self) -> String {
+        let mut encoded = String::new();
+
+        if let Some(event) = &self.event {
+            encoded.push_str(&format!("event: {}\n", event));
+        }
+        if let Some(id) = &self.id {
+            encoded.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(retry) = self.retry {
+            encoded.push_str(&format!("retry: {}\n", retry));
+        }
+        // Every line of a multi-line payload needs its own data field
+        for line in self.data.split('\n') {
+            encoded.push_str(&format!("data: {}\n", line.trim_end_matches('\r')));
+        }
+
+        encoded.push('\n');
+        encoded
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("event stream closed")]
+pub struct StreamClosed;
+
+/// Handle given to an event producer. Sending fails once the client has gone away,
+/// which is the producer's cue to stop.
+pub struct EventSender {
+    sender: mpsc::Sender<Event>,
+    closed: Arc<AtomicBool>,
+}
+
+impl EventSender {
+    pub fn send(&self, event: Event) -> Result<(), StreamClosed> {
+        self.sender.send(event).map_err(|_| StreamClosed)
+    }
+
+    /// Whether the stream has ended, for producers that wait on something else
+    /// between events and would otherwise only find out on their next `send`.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+}
+
+/// Marks the stream closed however writing it ends.
+struct CloseOnDrop(Arc<AtomicBool>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+type EventProducer = Box<dyn FnOnce(Option<String>, EventSender) + Send>;
+
+/// A long-lived `text/event-stream` response.
+///
+/// The producer runs on its own thread and pushes events through an `EventSender`;
+/// the connection thread writes them out as they arrive and fills quiet periods with
+/// keep-alive comments, which also find out about clients that are gone. The stream
+/// ends when the producer returns or after `MAX_DURATION`.
+pub struct EventStreamResponse {
+    last_event_id: Option<String>,
+    producer: Mutex<Option<EventProducer>>,
+    keep_alive: Duration,
+    max_duration: Duration,
+}
+
+impl HttpResponse for EventStreamResponse {
+    fn response(&self) -> Vec<u8> {
+        // The stream has no length and is not chunked, so only closing the
+        // connection can end it
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"
+            .to_string()
+            .into_bytes()
+    }
+
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(&self.response())?;
+        writer.flush()?;
+
+        let producer = match self.producer.lock().ok().and_then(|mut p| p.take()) {
+            Some(producer) => producer,
+            None => return Ok(()),
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        let closed = CloseOnDrop(Arc::new(AtomicBool::new(false)));
+        let sender = EventSender {
+            sender,
+            closed: Arc::clone(&closed.0),
+        };
+        let last_event_id = self.last_event_id.clone();
+        thread::spawn(move || producer(last_event_id, sender));
+
+        let end = Instant::now() + self.max_duration;
+        loop {
+            let remaining = end.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(());
+            }
+            match receiver.recv_timeout(self.keep_alive.min(remaining)) {
+                Ok(event) => writer.write_all(event.encode().as_bytes())?,
+                Err(RecvTimeoutError::Timeout) if remaining <= self.keep_alive => return Ok(()),
+                Err(RecvTimeoutError::Timeout) => writer.write_all(b": keep-alive\n\n")?,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            writer.flush()?;
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+impl EventStreamResponse {
+    /// How long the stream may stay silent before a keep-alive comment is sent.
+    pub fn with_keep_alive(mut self, interval: Duration) -> Self {
+        self.keep_alive = interval;
+        self
+    }
+}
+
+/// Starts an event stream for a request. `producer` receives the client's
+/// `Last-Event-ID` (sent when the browser reconnects) so it can resume where the
+/// previous connection left off.
+pub fn stream<F>(headers: &[String], producer: F) -> EventStreamResponse
+where
+    F: FnOnce(Option<String>, EventSender) + Send + 'static,
+{
+    let last_event_id = utils::get_header(headers, "Last-Event-ID")
+        .filter(|id| !id.is_empty())
+        .map(|id| id.to_string());
+
+    EventStreamResponse {
+        last_event_id,
+        producer: Mutex::new(Some(Box::new(producer))),
+        keep_alive: KEEP_ALIVE_INTERVAL,
+        max_duration: MAX_DURATION,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(output: &[u8]) -> String {
+        let output = String::from_utf8(output.to_vec()).unwrap();
+        output.split_once("\r\n\r\n").unwrap().1.to_string()
+    }
+
+    #[test]
+    fn encode_event_fields() {
+        let event = Event::new("line one\nline two")
+            .with_id("7")
+            .with_event("tick")
+            .with_retry(1000);
+        assert_eq!(
+            event.encode(),
+            "event: tick\nid: 7\nretry: 1000\ndata: line one\ndata: line two\n\n"
+        );
+    }
+
+    #[test]
+    fn write_to_streams_events_until_producer_finishes() {
+        let response = stream(&[], |_, sender| {
+            sender.send(Event::new("a")).unwrap();
+            sender.send(Event::new("b")).unwrap();
+        });
+
+        let mut output = Vec::new();
+        response.write_to(&mut output).unwrap();
+
+        assert!(output.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(String::from_utf8_lossy(&output).contains("\r\nConnection: close\r\n"));
+        assert_eq!(body(&output), "data: a\n\ndata: b\n\n");
+    }
+
+    #[test]
+    fn write_to_sends_keep_alive_comments() {
+        let response = stream(&[], |_, sender| {
+            thread::sleep(Duration::from_millis(100));
+            sender.send(Event::new("late")).unwrap();
+        })
+        .with_keep_alive(Duration::from_millis(20));
+
+        let mut output = Vec::new();
+        response.write_to(&mut output).unwrap();
+
+        let body = body(&output);
+        assert!(body.starts_with(": keep-alive\n\n"));
+        assert!(body.ends_with("data: late\n\n"));
+    }
+
+    #[test]
+    fn write_to_ends_long_streams() {
+        let mut response = stream(&[], |_, sender| {
+            while sender.send(Event::new("tick")).is_ok() {
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+        response.max_duration = Duration::from_millis(50);
+
+        let mut output = Vec::new();
+        response.write_to(&mut output).unwrap();
+        assert!(body(&output).starts_with("data: tick\n\n"));
+    }
+
+    #[test]
+    fn stream_passes_last_event_id() {
+        let headers = vec!["Last-Event-ID: 41".to_string()];
+        let response = stream(&headers, |last_event_id, sender| {
+            sender.send(Event::new(last_event_id.unwrap())).unwrap();
+        });
+
+        let mut output = Vec::new();
+        response.write_to(&mut output).unwrap();
+        assert_eq!(body(&output), "data: 41\n\n");
+    }
+}
@@ -1,11 +1,23 @@
-use std::io::Write;
-use std::path::Path;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use flate2::write::GzEncoder;
-use flate2::Compression;
+use crate::config::{PathPolicy, SymlinkPolicy};
 
 #[cfg(test)]
 use std::env;
+#[cfg(test)]
+use std::sync::{Mutex, MutexGuard};
+
+/// Serializes tests that read or change process-wide environment variables such as
+/// `APP_DIRECTORY`, since the test harness runs tests in parallel.
+#[cfg(test)]
+pub fn env_lock() -> MutexGuard<'static, ()> {
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+    ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
 
 #[cfg(test)]
 pub fn get_project_root() -> Option<String> {
@@ -25,24 +37,625 @@ pub fn get_project_source() -> Option<String> {
     Some(format!("{}/src", root))
 }
 
-pub fn is_safe_path(path: &Path, base_dir: &Path) -> bool {
-    // For existing files, use the file path
-    if path.exists() {
-        return path.canonicalize().map_or(false, |canon_path| {
-            canon_path.starts_with(base_dir.canonicalize().unwrap_or_default())
-        });
+/// Looks up a header value by name, ignoring case. Headers are raw `Name: value` lines.
+pub fn get_header<'a>(headers: &'a [String], name: &str) -> Option<&'a str> {
+    headers.iter().find_map(|header| {
+        let (key, value) = header.split_once(':')?;
+        if key.trim().eq_ignore_ascii_case(name) {
+            Some(value.trim())
+        } else {
+            None
+        }
+    })
+}
+
+/// Checks whether a comma separated header (e.g. `Connection`) contains `token`, ignoring case.
+pub fn header_has_token(headers: &[String], name: &str, token: &str) -> bool {
+    get_header(headers, name).is_some_and(|value| {
+        value
+            .split(',')
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+/// Parses a header made of comma separated `token;q=value` entries (`Accept`,
+/// `Accept-Encoding`, ...) into lowercase `(token, qvalue)` pairs, skipping entries
+/// with a malformed qvalue.
+pub fn parse_qvalues(header: &str) -> Vec<(String, f32)> {
+    header
+        .split(',')
+        .filter_map(|entry| {
+            let mut params = entry.split(';');
+            let token = params.next()?.trim().to_ascii_lowercase();
+            if token.is_empty() {
+                return None;
+            }
+
+            let mut quality = 1.0;
+            for param in params {
+                let (name, value) = param.split_once('=')?;
+                if name.trim().eq_ignore_ascii_case("q") {
+                    quality = value.trim().parse::<f32>().ok()?;
+                    if !(0.0..=1.0).contains(&quality) {
+                        return None;
+                    }
+                }
+            }
+            Some((token, quality))
+        })
+        .collect()
+}
+
+/// Splits a request target into its path and query string.
+pub fn split_query(target: &str) -> (&str, &str) {
+    target.split_once('?').unwrap_or((target, ""))
+}
+
+/// Parses a query string into `(name, value)` pairs.
+pub fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Some((percent_decode(name)?, percent_decode(value)?))
+        })
+        .collect()
+}
+
+/// Decodes `%XX` escapes. Returns `None` for malformed escapes or non UTF-8 results.
+pub fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Escapes everything but unreserved characters and `/`, for use in URL paths.
+pub fn percent_encode(input: &str) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Quotes `text` as a JSON string.
+pub fn escape_json(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    escaped.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Formats a time as an HTTP date (IMF-fixdate), e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let days = secs / 86400;
+    let secs_of_day = secs % 86400;
+
+    // Days since the epoch to a proleptic Gregorian date, counting years from
+    // March so that the leap day comes last
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[((days + 4) % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// Parses an HTTP date in any of the three formats RFC 9110 section 5.6.7 requires
+/// recipients to accept. Dates before the epoch are rejected.
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    let (day, month, year, time) = match parts.as_slice() {
+        // IMF-fixdate: Sun, 06 Nov 1994 08:49:37 GMT
+        [_, day, month, year, time, "GMT"] => (*day, *month, year.parse::<u64>().ok()?, *time),
+        // RFC 850: Sunday, 06-Nov-94 08:49:37 GMT
+        [_, date, time, "GMT"] => {
+            let mut fields = date.split('-');
+            let (day, month) = (fields.next()?, fields.next()?);
+            let year = fields.next()?.parse::<u64>().ok()?;
+            // Two digit years that look more than 50 years ahead are in the past
+            let year = if year < 70 { year + 2000 } else { year + 1900 };
+            (day, month, year, *time)
+        }
+        // asctime: Sun Nov  6 08:49:37 1994
+        [_, month, day, time, year] => (*day, *month, year.parse::<u64>().ok()?, *time),
+        _ => return None,
+    };
+
+    let day: u64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as u64 + 1;
+    let mut clock = time.split(':').map(|field| field.parse::<u64>().ok());
+    let (hour, minute, second) = (clock.next()??, clock.next()??, clock.next()??);
+    if year < 1970 || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    // The inverse of the conversion in `http_date`
+    let year_from_march = if month <= 2 { year - 1 } else { year };
+    let era = year_from_march / 400;
+    let year_of_era = year_from_march % 400;
+    let month_from_march = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146_097 + day_of_era).checked_sub(719_468)?;
+
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Creates a fresh, empty directory under the system temp dir for a test to use.
+#[cfg(test)]
+pub fn temp_dir(name: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let dir = env::temp_dir().join(format!(
+        "http-server-test-{}-{}-{}",
+        std::process::id(),
+        name,
+        COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A file that replaces `path` only once it is complete: data goes to a temporary
+/// file in the same directory, which `commit` flushes to disk and renames over
+/// `path`. Readers see either the old or the new file, never a partial one. If it
+/// is dropped without being committed, the temporary file is removed.
+///
+/// A file being replaced keeps its permissions. A symbolic link at `path` is
+/// replaced itself; to write to its target, pass the path from `resolve`.
+pub struct AtomicFile {
+    file: File,
+    path: PathBuf,
+    temp_path: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+        let temp_path = directory_of(path).join(format!(
+            ".{}.{}-{}.tmp",
+            name.to_string_lossy(),
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
+        let existing = fs::symlink_metadata(path).ok().filter(|metadata| metadata.is_file());
+        if let Some(metadata) = existing {
+            file.set_permissions(metadata.permissions())?;
+        }
+
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+            temp_path,
+            committed: false,
+        })
     }
 
-    // For non-existent files, check the parent directory
-    let parent = path.parent().unwrap_or(Path::new(""));
-    parent.canonicalize().map_or(false, |canon_parent| {
-        canon_parent.starts_with(base_dir.canonicalize().unwrap_or_default())
+    /// Moves the file into place. With `sync_directory` the directory is synced as
+    /// well, so the rename itself survives a crash.
+    pub fn commit(mut self, sync_directory: bool) -> io::Result<()> {
+        self.file.sync_all()?;
+        fs::rename(&self.temp_path, &self.path)?;
+        self.committed = true;
+        if sync_directory {
+            sync_dir(directory_of(&self.path))?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+fn directory_of(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Replaces the file at `path` with `contents` through an [`AtomicFile`].
+pub fn write_atomic(path: &Path, contents: &[u8], sync_directory: bool) -> io::Result<()> {
+    let mut file = AtomicFile::create(path)?;
+    file.write_all(contents)?;
+    file.commit(sync_directory)
+}
+
+#[cfg(unix)]
+fn sync_dir(directory: &Path) -> io::Result<()> {
+    File::open(directory)?.sync_all()
+}
+
+// Directories cannot be opened as files elsewhere; renames are left to the OS there
+#[cfg(not(unix))]
+fn sync_dir(_directory: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Whether `path`, which should be `base_dir` joined with a requested path, may be
+/// accessed under `policy`.
+pub fn is_safe_path(path: &Path, base_dir: &Path, policy: &PathPolicy) -> bool {
+    let relative = match path.strip_prefix(base_dir) {
+        Ok(relative) => relative,
+        Err(_) => return false,
+    };
+    if !policy.allow_hidden && relative.components().any(is_hidden) {
+        return false;
+    }
+
+    let allowed = match policy.symlinks {
+        SymlinkPolicy::Never => !through_symlink(base_dir, relative) && is_within(path, base_dir),
+        SymlinkPolicy::WithinRoot => is_within(path, base_dir),
+        // Where a link points is fine, but `link/..` would resolve next to its
+        // target rather than back under `base_dir`
+        SymlinkPolicy::Always => relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir)),
+    };
+    allowed && (policy.allow_hidden || !is_hidden_target(path, base_dir))
+}
+
+fn is_hidden(component: Component) -> bool {
+    match component {
+        Component::Normal(name) => {
+            let name = name.to_string_lossy();
+            name.starts_with('.') && name != ".well-known"
+        }
+        _ => false,
+    }
+}
+
+/// Whether a symbolic link takes `path` into a dotfile or dot-directory, as
+/// `docs -> .git` would. Targets outside `base_dir` are judged by their whole path.
+fn is_hidden_target(path: &Path, base_dir: &Path) -> bool {
+    let (target, base_dir) = match (resolve(path), base_dir.canonicalize()) {
+        (Some(target), Ok(base_dir)) => (target, base_dir),
+        _ => return true,
+    };
+    let relative = target.strip_prefix(&base_dir).unwrap_or(&target);
+    relative.components().any(is_hidden)
+}
+
+/// Whether any existing step from `base_dir` along `relative` is a symbolic link.
+fn through_symlink(base_dir: &Path, relative: &Path) -> bool {
+    let mut current = base_dir.to_path_buf();
+    relative.components().any(|component| {
+        current.push(component);
+        current
+            .symlink_metadata()
+            .is_ok_and(|metadata| metadata.file_type().is_symlink())
     })
 }
 
+/// Whether `path` resolves to somewhere under `base_dir`, following symbolic links.
+fn is_within(path: &Path, base_dir: &Path) -> bool {
+    resolve(path)
+        .is_some_and(|target| target.starts_with(base_dir.canonicalize().unwrap_or_default()))
+}
+
+/// The inode number behind `metadata`, which changes when a file is replaced by a
+/// rename. Always 0 where there are no inodes.
+pub fn inode(metadata: &fs::Metadata) -> u64 {
+    #[cfg(unix)]
+    return std::os::unix::fs::MetadataExt::ino(metadata);
+    #[cfg(not(unix))]
+    return 0;
+}
+
+/// `path` with symbolic links resolved. For a missing file, that is its closest
+/// existing ancestor's canonical path followed by the missing part, which must not
+/// climb back out with `..` (that leaves it without a file name).
+pub fn resolve(path: &Path) -> Option<PathBuf> {
+    let mut ancestor = path;
+    while !ancestor.exists() {
+        ancestor.file_name()?;
+        ancestor = ancestor.parent()?;
+    }
+    let canonical = ancestor.canonicalize().ok()?;
+    match path.strip_prefix(ancestor).ok()? {
+        missing if missing.as_os_str().is_empty() => Some(canonical),
+        missing => Some(canonical.join(missing)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_date_formats_imf_fixdate() {
+        let date = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(http_date(date), "Sun, 06 Nov 1994 08:49:37 GMT");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(http_date(leap_day), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+
+    #[test]
+    fn parse_http_date_accepts_all_formats() {
+        let expected = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        for date in [
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+        ] {
+            assert_eq!(parse_http_date(date), Some(expected), "{}", date);
+        }
+
+        let now = UNIX_EPOCH + Duration::from_secs(1_792_379_639);
+        assert_eq!(parse_http_date(&http_date(now)), Some(now));
+
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 UTC"), None);
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 25:49:37 GMT"), None);
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+
+    #[test]
+    fn write_atomic_never_exposes_partial_files() {
+        use std::sync::atomic::AtomicBool;
+        use std::sync::Arc;
+        use std::thread;
 
-pub fn gzip_compress(data: &[u8]) -> Vec<u8> {
-    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-    encoder.write_all(data).unwrap();
-    encoder.finish().unwrap()
-}
\ No newline at end of file
+        let directory = temp_dir("write-atomic");
+        let path = directory.join("data.bin");
+        let versions: Vec<Vec<u8>> = (0..4u8).map(|i| vec![b'a' + i; 256 * 1024]).collect();
+        write_atomic(&path, &versions[0], false).unwrap();
+
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let (path, done) = (path.clone(), Arc::clone(&done));
+                thread::spawn(move || {
+                    let mut reads = 0;
+                    while !done.load(Ordering::Relaxed) || reads == 0 {
+                        let contents = fs::read(&path).unwrap();
+                        assert_eq!(contents.len(), 256 * 1024);
+                        assert!(contents.iter().all(|&b| b == contents[0]));
+                        reads += 1;
+                    }
+                })
+            })
+            .collect();
+
+        for i in 0..40 {
+            write_atomic(&path, &versions[i % versions.len()], i % 10 == 0).unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        // Only the target is left behind, no temporary files
+        let entries: Vec<_> = fs::read_dir(&directory).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(fs::read(&path).unwrap(), versions[39 % versions.len()]);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_atomic_keeps_permissions() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let directory = temp_dir("write-atomic-permissions");
+        let path = directory.join("script.sh");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o750)).unwrap();
+        write_atomic(&path, b"new", false).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o750);
+
+        // Writing through a link replaces its target and leaves the link alone
+        let link = directory.join("link.sh");
+        symlink(&path, &link).unwrap();
+        write_atomic(&resolve(&link).unwrap(), b"newer", false).unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read(&path).unwrap(), b"newer");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o750);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn is_safe_path_checks_missing_paths() {
+        let base = temp_dir("safe-path");
+        let policy = PathPolicy::default();
+        assert!(is_safe_path(&base.join("missing"), &base, &policy));
+        assert!(is_safe_path(&base.join("a/b/c"), &base, &policy));
+        assert!(!is_safe_path(&base.join("a/../../outside"), &base, &policy));
+        assert!(!is_safe_path(&base.join("../outside"), &base, &policy));
+        assert!(!is_safe_path(Path::new("/etc/passwd"), &base, &policy));
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn is_safe_path_denies_hidden_files_by_default() {
+        let base = temp_dir("safe-path-hidden");
+        let mut policy = PathPolicy::default();
+        assert!(!is_safe_path(&base.join(".env"), &base, &policy));
+        assert!(!is_safe_path(&base.join(".git/config"), &base, &policy));
+        assert!(!is_safe_path(
+            &base.join("app/.secrets/key.pem"),
+            &base,
+            &policy
+        ));
+        assert!(is_safe_path(&base.join("app/site.v2.css"), &base, &policy));
+        assert!(is_safe_path(
+            &base.join(".well-known/security.txt"),
+            &base,
+            &policy
+        ));
+
+        policy.allow_hidden = true;
+        assert!(is_safe_path(&base.join(".env"), &base, &policy));
+        assert!(is_safe_path(&base.join(".git/config"), &base, &policy));
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn is_safe_path_applies_symlink_policy() {
+        use std::os::unix::fs::symlink;
+
+        let root = temp_dir("safe-path-symlinks");
+        let (base, outside) = (root.join("site"), root.join("outside"));
+        fs::create_dir_all(base.join("assets")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(base.join("assets/app.js"), "app").unwrap();
+        fs::write(outside.join("secret.txt"), "secret").unwrap();
+        fs::write(root.join("next-to-site.txt"), "private").unwrap();
+        symlink(base.join("assets"), base.join("static")).unwrap();
+        symlink(&outside, base.join("shared")).unwrap();
+
+        let check = |symlinks: SymlinkPolicy, path: &str| {
+            let policy = PathPolicy {
+                symlinks,
+                allow_hidden: false,
+            };
+            is_safe_path(&base.join(path), &base, &policy)
+        };
+
+        for symlinks in [
+            SymlinkPolicy::Never,
+            SymlinkPolicy::WithinRoot,
+            SymlinkPolicy::Always,
+        ] {
+            assert!(check(symlinks, "assets/app.js"));
+            assert!(check(symlinks, "assets/new.js"));
+            assert!(!check(symlinks, "../next-to-site.txt"));
+        }
+
+        assert!(!check(SymlinkPolicy::Never, "static/app.js"));
+        assert!(!check(SymlinkPolicy::Never, "static/new.js"));
+        assert!(!check(SymlinkPolicy::Never, "shared/secret.txt"));
+
+        assert!(check(SymlinkPolicy::WithinRoot, "static/app.js"));
+        assert!(!check(SymlinkPolicy::WithinRoot, "shared/secret.txt"));
+        assert!(!check(SymlinkPolicy::WithinRoot, "shared/new.txt"));
+
+        assert!(check(SymlinkPolicy::Always, "static/app.js"));
+        assert!(check(SymlinkPolicy::Always, "shared/secret.txt"));
+        // `shared/..` is the parent of `outside`, not `site`
+        assert!(!check(SymlinkPolicy::Always, "shared/../next-to-site.txt"));
+
+        // Links must not lead into places the path itself could not name
+        fs::create_dir_all(base.join(".git")).unwrap();
+        fs::write(base.join(".git/config"), "[core]").unwrap();
+        symlink(base.join(".git"), base.join("docs")).unwrap();
+        symlink(base.join(".git/config"), base.join("config.txt")).unwrap();
+        for symlinks in [SymlinkPolicy::WithinRoot, SymlinkPolicy::Always] {
+            assert!(!check(symlinks, "docs/config"));
+            assert!(!check(symlinks, "docs/new"));
+            assert!(!check(symlinks, "config.txt"));
+        }
+        let allow_hidden = PathPolicy {
+            symlinks: SymlinkPolicy::WithinRoot,
+            allow_hidden: true,
+        };
+        assert!(is_safe_path(
+            &base.join("docs/config"),
+            &base,
+            &allow_hidden
+        ));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn percent_coding_round_trips() {
+        assert_eq!(percent_encode("a b/ü.txt"), "a%20b/%C3%BC.txt");
+        assert_eq!(percent_decode("a%20b/%C3%BC.txt").unwrap(), "a b/ü.txt");
+        assert!(percent_decode("bad%2").is_none());
+        assert!(percent_decode("%zz").is_none());
+        assert_eq!(
+            parse_query("sort=size&order=desc&flag"),
+            vec![
+                ("sort".to_string(), "size".to_string()),
+                ("order".to_string(), "desc".to_string()),
+                ("flag".to_string(), String::new()),
+            ]
+        );
+    }
+}
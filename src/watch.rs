@@ -0,0 +1,230 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, MutexGuard};
+#[cfg(target_os = "linux")]
+use std::thread;
+
+use crate::cache;
+
+fn subscribers() -> MutexGuard<'static, Vec<Sender<PathBuf>>> {
+    static SUBSCRIBERS: Mutex<Vec<Sender<PathBuf>>> = Mutex::new(Vec::new());
+    SUBSCRIBERS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Delivers every change seen from now on. Dropping the receiver unsubscribes.
+pub fn subscribe() -> Receiver<PathBuf> {
+    let (sender, receiver) = mpsc::channel();
+    subscribers().push(sender);
+    receiver
+}
+
+/// Drops what the cache holds for `path` and tells the subscribers about it.
+fn publish(path: &Path) {
+    println!("Changed: {:?}", path);
+    cache::invalidate(path);
+    subscribers().retain(|subscriber| subscriber.send(path.to_path_buf()).is_ok());
+}
+
+/// Watches `root` and every directory below it on a background thread, including
+/// directories created later. Only available on Linux, where it uses inotify.
+#[cfg(target_os = "linux")]
+pub fn start(root: &Path) -> io::Result<()> {
+    let mut watcher = linux::Watcher::new(root)?;
+    thread::spawn(move || loop {
+        match watcher.read() {
+            Ok(changes) => changes.iter().for_each(|path| publish(path)),
+            Err(e) => {
+                println!("Stopped watching {:?}: {}", watcher.root(), e);
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn start(_root: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "watching for changes needs inotify, which is only available on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::collections::HashMap;
+    use std::ffi::CString;
+    use std::fs::{self, File};
+    use std::io::{self, Read};
+    use std::mem;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::path::{Path, PathBuf};
+    use std::ptr;
+
+    const MASK: u32 = libc::IN_CLOSE_WRITE
+        | libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_ATTRIB;
+
+    /// Large enough for any single event, whose name is at most `NAME_MAX` bytes.
+    const BUFFER_SIZE: usize = 4096;
+
+    pub struct Watcher {
+        inotify: File,
+        root: PathBuf,
+        /// Directory each watch descriptor stands for.
+        watches: HashMap<i32, PathBuf>,
+    }
+
+    impl Watcher {
+        pub fn new(root: &Path) -> io::Result<Self> {
+            // SAFETY: plain system call without pointers
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+            if fd == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut watcher = Self {
+                // SAFETY: `fd` was just opened and nothing else owns it
+                inotify: unsafe { File::from_raw_fd(fd) },
+                root: root.to_path_buf(),
+                watches: HashMap::new(),
+            };
+            watcher.add_watch(root)?;
+            watcher.add_subdirectories(root);
+            Ok(watcher)
+        }
+
+        pub fn root(&self) -> &Path {
+            &self.root
+        }
+
+        fn add_watch(&mut self, directory: &Path) -> io::Result<()> {
+            let path = CString::new(directory.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            // SAFETY: `path` is a valid NUL-terminated string for the duration of the call
+            let wd =
+                unsafe { libc::inotify_add_watch(self.inotify.as_raw_fd(), path.as_ptr(), MASK) };
+            if wd == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            self.watches.insert(wd, directory.to_path_buf());
+            Ok(())
+        }
+
+        /// Watches the directories below `directory`. Symbolic links are not
+        /// followed, and directories that cannot be watched are skipped.
+        fn add_subdirectories(&mut self, directory: &Path) {
+            let entries = match fs::read_dir(directory) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    println!("Not watching {:?}: {}", directory, e);
+                    return;
+                }
+            };
+            for entry in entries.flatten() {
+                if !entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                    continue;
+                }
+                let path = entry.path();
+                match self.add_watch(&path) {
+                    Ok(()) => self.add_subdirectories(&path),
+                    Err(e) => println!("Not watching {:?}: {}", path, e),
+                }
+            }
+        }
+
+        /// Blocks until something changes and returns the paths affected.
+        pub fn read(&mut self) -> io::Result<Vec<PathBuf>> {
+            let mut buffer = [0u8; BUFFER_SIZE];
+            let length = self.inotify.read(&mut buffer)?;
+            let header = mem::size_of::<libc::inotify_event>();
+
+            let mut changes = Vec::new();
+            let mut offset = 0;
+            while offset + header <= length {
+                // SAFETY: the kernel wrote a whole event header at `offset`, which
+                // need not be aligned
+                let event: libc::inotify_event =
+                    unsafe { ptr::read_unaligned(buffer[offset..].as_ptr().cast()) };
+                let name = &buffer[offset + header..offset + header + event.len as usize];
+                let name = name.split(|&b| b == 0).next().unwrap_or_default();
+                offset += header + event.len as usize;
+
+                if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                    // Events were lost, so anything may have changed
+                    changes.push(self.root.clone());
+                    continue;
+                }
+                if event.mask & libc::IN_IGNORED != 0 {
+                    self.watches.remove(&event.wd);
+                    continue;
+                }
+                let path = match self.watches.get(&event.wd) {
+                    Some(directory) if name.is_empty() => directory.clone(),
+                    Some(directory) => directory.join(std::ffi::OsString::from_vec(name.to_vec())),
+                    None => continue,
+                };
+                let is_new_directory = event.mask & libc::IN_ISDIR != 0
+                    && event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0;
+                if is_new_directory {
+                    // Files may have appeared in it before the watch was in place
+                    match self.add_watch(&path) {
+                        Ok(()) => self.add_subdirectories(&path),
+                        Err(e) => println!("Not watching {:?}: {}", path, e),
+                    }
+                }
+                changes.push(path);
+            }
+            Ok(changes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils;
+    use std::fs;
+
+    #[test]
+    fn publish_reaches_subscribers() {
+        let changes = subscribe();
+        let unsubscribed = subscribe();
+        drop(unsubscribed);
+
+        publish(Path::new("/nowhere/changed.txt"));
+        let published: Vec<PathBuf> = changes.try_iter().collect();
+        assert!(published.contains(&PathBuf::from("/nowhere/changed.txt")));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn watcher_sees_changes_in_new_directories() {
+        let root = utils::temp_dir("watch");
+        fs::create_dir(root.join("existing")).unwrap();
+        let mut watcher = linux::Watcher::new(&root).unwrap();
+        let mut read_until = |expected: &Path| {
+            let mut seen = Vec::new();
+            while !seen.iter().any(|path: &PathBuf| path == expected) {
+                seen.extend(watcher.read().unwrap());
+            }
+        };
+
+        fs::write(root.join("existing/page.html"), "hello").unwrap();
+        read_until(&root.join("existing/page.html"));
+
+        fs::create_dir(root.join("new")).unwrap();
+        read_until(&root.join("new"));
+        fs::write(root.join("new/style.css"), "body {}").unwrap();
+        read_until(&root.join("new/style.css"));
+
+        fs::remove_file(root.join("existing/page.html")).unwrap();
+        read_until(&root.join("existing/page.html"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,603 @@
+use std::any::Any;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+use crate::interface::{
+    BadRequestResponse, HttpResponse, MethodNotAllowedResponse, UpgradeRequiredResponse,
+};
+use crate::server::Deadline;
+use crate::utils;
+
+// RFC 6455 section 1.3
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const WEBSOCKET_VERSION: &str = "13";
+
+/// Largest message (after reassembling fragments) we are willing to buffer.
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const INVALID_PAYLOAD: u16 = 1007;
+    pub const MESSAGE_TOO_BIG: u16 = 1009;
+    pub const INTERNAL_ERROR: u16 = 1011;
+
+    /// Whether a peer is allowed to send `code` in a close frame.
+    pub fn is_valid(code: u16) -> bool {
+        matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum WebSocketError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("protocol error: {0}")]
+    Protocol(&'static str),
+    #[error("text message is not valid utf-8")]
+    InvalidUtf8,
+    #[error("message exceeds {0} bytes")]
+    MessageTooBig(usize),
+    #[error("connection is closed")]
+    Closed,
+}
+
+impl WebSocketError {
+    /// Close code to report to the peer before dropping the connection.
+    fn close_code(&self) -> Option<u16> {
+        match self {
+            WebSocketError::Protocol(_) => Some(close_code::PROTOCOL_ERROR),
+            WebSocketError::InvalidUtf8 => Some(close_code::INVALID_PAYLOAD),
+            WebSocketError::MessageTooBig(_) => Some(close_code::MESSAGE_TOO_BIG),
+            // The peer went quiet for too long, but may still be listening
+            WebSocketError::Io(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                ) =>
+            {
+                Some(close_code::GOING_AWAY)
+            }
+            WebSocketError::Io(_) | WebSocketError::Closed => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+#[derive(Debug)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+fn read_frame(reader: &mut dyn Read, max_payload: usize) -> Result<Frame, WebSocketError> {
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head)?;
+
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    if head[0] & 0x70 != 0 {
+        // We do not negotiate any extensions, so the reserved bits must be clear.
+        return Err(WebSocketError::Protocol("reserved bits set"));
+    }
+
+    let masked = head[1] & 0x80 != 0;
+    if !masked {
+        return Err(WebSocketError::Protocol("client frames must be masked"));
+    }
+
+    let length = match head[1] & 0x7F {
+        126 => {
+            let mut buf = [0u8; 2];
+            reader.read_exact(&mut buf)?;
+            u16::from_be_bytes(buf) as u64
+        }
+        127 => {
+            let mut buf = [0u8; 8];
+            reader.read_exact(&mut buf)?;
+            let length = u64::from_be_bytes(buf);
+            if length >> 63 != 0 {
+                return Err(WebSocketError::Protocol("invalid payload length"));
+            }
+            length
+        }
+        length => length as u64,
+    };
+
+    if opcode >= OPCODE_CLOSE {
+        if !fin {
+            return Err(WebSocketError::Protocol("fragmented control frame"));
+        }
+        if length > 125 {
+            return Err(WebSocketError::Protocol("control frame too large"));
+        }
+    }
+
+    if length > max_payload as u64 {
+        return Err(WebSocketError::MessageTooBig(max_payload));
+    }
+
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask)?;
+
+    let mut payload = vec![0u8; length as usize];
+    reader.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    Ok(Frame {
+        fin,
+        opcode,
+        payload,
+    })
+}
+
+fn write_frame(writer: &mut dyn Write, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    // Server frames are never masked and we never fragment outgoing messages.
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+/// What a WebSocket reads its frames from: the connection, whose reads can be
+/// bounded by a deadline.
+pub trait Connection: Read + Deadline {}
+
+impl<T: Read + Deadline> Connection for T {}
+
+/// A server side WebSocket connection, created once the upgrade handshake has been sent.
+pub struct WebSocket<'a> {
+    reader: &'a mut dyn Connection,
+    writer: &'a mut dyn Write,
+    max_message_size: usize,
+    idle_timeout: Option<Duration>,
+    closed: bool,
+}
+
+impl<'a> WebSocket<'a> {
+    pub fn new(reader: &'a mut dyn Connection, writer: &'a mut dyn Write) -> Self {
+        Self {
+            reader,
+            writer,
+            max_message_size: MAX_MESSAGE_SIZE,
+            idle_timeout: None,
+            closed: false,
+        }
+    }
+
+    /// Gives up on the connection, closing it with `1001 Going Away`, once the
+    /// client has sent no frame for `timeout`.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Waits for the next complete message.
+    ///
+    /// Pings are answered and fragments are reassembled transparently. Returns `Ok(None)`
+    /// once the peer has closed the connection and the close handshake has completed.
+    pub fn recv(&mut self) -> Result<Option<Message>, WebSocketError> {
+        if self.closed {
+            return Ok(None);
+        }
+
+        match self.read_message() {
+            Ok(message) => Ok(message),
+            Err(e) => {
+                if let Some(code) = e.close_code() {
+                    let _ = self.close(code, "");
+                }
+                self.closed = true;
+                Err(e)
+            }
+        }
+    }
+
+    fn read_message(&mut self) -> Result<Option<Message>, WebSocketError> {
+        // Opcode and payload of a fragmented message that is still in progress
+        let mut fragmented: Option<(u8, Vec<u8>)> = None;
+
+        loop {
+            if let Some(timeout) = self.idle_timeout {
+                self.reader.set_deadline(Some(Instant::now() + timeout));
+            }
+            let frame = read_frame(self.reader, self.max_message_size)?;
+
+            match frame.opcode {
+                OPCODE_PING => write_frame(self.writer, OPCODE_PONG, &frame.payload)?,
+                OPCODE_PONG => {}
+                OPCODE_CLOSE => {
+                    self.handle_close(&frame.payload)?;
+                    return Ok(None);
+                }
+                OPCODE_TEXT | OPCODE_BINARY => {
+                    if fragmented.is_some() {
+                        return Err(WebSocketError::Protocol("expected continuation frame"));
+                    }
+                    if frame.fin {
+                        return to_message(frame.opcode, frame.payload).map(Some);
+                    }
+                    fragmented = Some((frame.opcode, frame.payload));
+                }
+                OPCODE_CONTINUATION => {
+                    let (opcode, mut payload) = fragmented
+                        .take()
+                        .ok_or(WebSocketError::Protocol("unexpected continuation frame"))?;
+                    if payload.len() + frame.payload.len() > self.max_message_size {
+                        return Err(WebSocketError::MessageTooBig(self.max_message_size));
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        return to_message(opcode, payload).map(Some);
+                    }
+                    fragmented = Some((opcode, payload));
+                }
+                _ => return Err(WebSocketError::Protocol("unknown opcode")),
+            }
+        }
+    }
+
+    fn handle_close(&mut self, payload: &[u8]) -> Result<(), WebSocketError> {
+        let code = match payload.len() {
+            0 => None,
+            1 => return Err(WebSocketError::Protocol("invalid close payload")),
+            _ => Some(u16::from_be_bytes([payload[0], payload[1]])),
+        };
+
+        if let Some(code) = code {
+            if !close_code::is_valid(code) {
+                return Err(WebSocketError::Protocol("invalid close code"));
+            }
+            if std::str::from_utf8(&payload[2..]).is_err() {
+                return Err(WebSocketError::InvalidUtf8);
+            }
+        }
+
+        // Echo the close back to complete the closing handshake
+        let reply = code.unwrap_or(close_code::NORMAL).to_be_bytes();
+        let result = write_frame(self.writer, OPCODE_CLOSE, &reply);
+        self.closed = true;
+        result.map_err(WebSocketError::from)
+    }
+
+    pub fn send(&mut self, message: &Message) -> Result<(), WebSocketError> {
+        if self.closed {
+            return Err(WebSocketError::Closed);
+        }
+
+        match message {
+            Message::Text(text) => write_frame(self.writer, OPCODE_TEXT, text.as_bytes())?,
+            Message::Binary(data) => write_frame(self.writer, OPCODE_BINARY, data)?,
+        }
+        Ok(())
+    }
+
+    /// Starts the closing handshake. Further sends fail with `WebSocketError::Closed`.
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<(), WebSocketError> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+
+        let mut payload = code.to_be_bytes().to_vec();
+        // Control frames are limited to 125 bytes, two of which hold the code
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+
+        write_frame(self.writer, OPCODE_CLOSE, &payload)?;
+        Ok(())
+    }
+}
+
+fn to_message(opcode: u8, payload: Vec<u8>) -> Result<Message, WebSocketError> {
+    if opcode == OPCODE_TEXT {
+        String::from_utf8(payload)
+            .map(Message::Text)
+            .map_err(|_| WebSocketError::InvalidUtf8)
+    } else {
+        Ok(Message::Binary(payload))
+    }
+}
+
+/// Computes the `Sec-WebSocket-Accept` value for a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    BASE64.encode(hasher.finalize())
+}
+
+pub type WebSocketHandler = Box<dyn Fn(&mut WebSocket) -> Result<(), WebSocketError> + Send>;
+
+/// `101 Switching Protocols` response. After it is written, the server hands the
+/// connection over to `handler`.
+pub struct WebSocketUpgradeResponse {
+    accept: String,
+    handler: WebSocketHandler,
+}
+
+impl WebSocketUpgradeResponse {
+    /// Runs the route's handler on the upgraded connection and closes it cleanly afterwards.
+    pub fn run(&self, socket: &mut WebSocket) {
+        let result = (self.handler)(socket);
+
+        if let Err(e) = &result {
+            println!("WebSocket handler error: {}", e);
+        }
+
+        if !socket.is_closed() {
+            let code = match result {
+                Ok(_) => close_code::NORMAL,
+                Err(_) => close_code::INTERNAL_ERROR,
+            };
+            if let Err(e) = socket.close(code, "") {
+                println!("Error closing WebSocket: {}", e);
+            }
+        }
+    }
+}
+
+impl HttpResponse for WebSocketUpgradeResponse {
+    fn response(&self) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            self.accept
+        )
+        .into_bytes()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Validates a WebSocket opening handshake and, if it is acceptable, returns the
+/// upgrade response that hands the connection to `handler`.
+///
+/// Routes that want to accept WebSockets return the result of this function directly.
+pub fn upgrade<F>(method: &str, headers: &[String], handler: F) -> Box<dyn HttpResponse>
+where
+    F: Fn(&mut WebSocket) -> Result<(), WebSocketError> + Send + 'static,
+{
+    if !method.eq_ignore_ascii_case("GET") {
+        return Box::new(MethodNotAllowedResponse { allow: "GET" });
+    }
+
+    let is_upgrade = utils::header_has_token(headers, "Upgrade", "websocket")
+        && utils::header_has_token(headers, "Connection", "upgrade");
+    if !is_upgrade {
+        return Box::new(UpgradeRequiredResponse);
+    }
+
+    if utils::get_header(headers, "Sec-WebSocket-Version") != Some(WEBSOCKET_VERSION) {
+        return Box::new(UpgradeRequiredResponse);
+    }
+
+    // The key must be a base64 encoded 16 byte nonce
+    let key = match utils::get_header(headers, "Sec-WebSocket-Key") {
+        Some(key) if BASE64.decode(key).is_ok_and(|nonce| nonce.len() == 16) => key,
+        _ => return Box::new(BadRequestResponse),
+    };
+
+    Box::new(WebSocketUpgradeResponse {
+        accept: accept_key(key),
+        handler: Box::new(handler),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![if fin { 0x80 } else { 0x00 } | opcode];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    fn run(input: Vec<u8>) -> (Vec<Result<Option<Message>, String>>, Vec<u8>) {
+        let mut reader = Cursor::new(input);
+        let mut output = Vec::new();
+        let mut results = Vec::new();
+        {
+            let mut socket = WebSocket::new(&mut reader, &mut output);
+            loop {
+                let result = socket.recv().map_err(|e| e.to_string());
+                let done = !matches!(result, Ok(Some(_)));
+                results.push(result);
+                if done {
+                    break;
+                }
+            }
+        }
+        (results, output)
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn recv_unmasks_text_frame() {
+        let mut input = client_frame(true, OPCODE_TEXT, b"Hello");
+        input.extend(client_frame(
+            true,
+            OPCODE_CLOSE,
+            &close_code::NORMAL.to_be_bytes(),
+        ));
+
+        let (results, output) = run(input);
+        assert_eq!(results[0], Ok(Some(Message::Text("Hello".to_string()))));
+        assert_eq!(results[1], Ok(None));
+        // Close frame echoed back with the same code
+        assert_eq!(output, vec![0x88, 0x02, 0x03, 0xE8]);
+    }
+
+    #[test]
+    fn recv_reassembles_fragments_around_ping() {
+        let mut input = client_frame(false, OPCODE_TEXT, b"Hel");
+        input.extend(client_frame(true, OPCODE_PING, b"hi"));
+        input.extend(client_frame(true, OPCODE_CONTINUATION, b"lo"));
+
+        let (results, output) = run(input);
+        assert_eq!(results[0], Ok(Some(Message::Text("Hello".to_string()))));
+        // Pong carries the ping payload
+        assert_eq!(&output[..4], &[0x8A, 0x02, b'h', b'i']);
+    }
+
+    #[test]
+    fn recv_rejects_unmasked_frames() {
+        let input = vec![0x81, 0x02, b'h', b'i'];
+
+        let (results, output) = run(input);
+        assert!(results[0].is_err());
+        assert_eq!(&output[..4], &[0x88, 0x02, 0x03, 0xEA]);
+    }
+
+    #[test]
+    fn recv_rejects_invalid_utf8() {
+        let input = client_frame(true, OPCODE_TEXT, &[0xff, 0xfe]);
+
+        let (results, output) = run(input);
+        assert!(results[0].is_err());
+        assert_eq!(&output[..4], &[0x88, 0x02, 0x03, 0xEF]);
+    }
+
+    #[test]
+    fn recv_rejects_oversized_messages() {
+        let mut reader = Cursor::new(client_frame(true, OPCODE_BINARY, &[0u8; 200]));
+        let mut output = Vec::new();
+        let mut socket = WebSocket::new(&mut reader, &mut output);
+        socket.max_message_size = 100;
+
+        assert!(matches!(
+            socket.recv(),
+            Err(WebSocketError::MessageTooBig(100))
+        ));
+        assert_eq!(&output[..4], &[0x88, 0x02, 0x03, 0xF1]);
+    }
+
+    /// A client that never sends anything, remembering the deadline it was given.
+    struct Silent {
+        deadline: Option<Instant>,
+    }
+
+    impl Read for Silent {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::TimedOut.into())
+        }
+    }
+
+    impl Deadline for Silent {
+        fn set_deadline(&mut self, deadline: Option<Instant>) {
+            self.deadline = deadline;
+        }
+    }
+
+    #[test]
+    fn recv_closes_idle_connections() {
+        let mut reader = Silent { deadline: None };
+        let mut output = Vec::new();
+        let mut socket =
+            WebSocket::new(&mut reader, &mut output).with_idle_timeout(Duration::from_secs(5));
+
+        assert!(matches!(socket.recv(), Err(WebSocketError::Io(_))));
+        assert!(socket.is_closed());
+        assert!(reader.deadline.is_some());
+        assert_eq!(output, vec![0x88, 0x02, 0x03, 0xE9]);
+    }
+
+    #[test]
+    fn send_uses_extended_length() {
+        let mut reader = Cursor::new(Vec::new());
+        let mut output = Vec::new();
+        let mut socket = WebSocket::new(&mut reader, &mut output);
+        socket.send(&Message::Binary(vec![7u8; 300])).unwrap();
+
+        assert_eq!(&output[..4], &[0x82, 126, 0x01, 0x2C]);
+        assert_eq!(output.len(), 304);
+    }
+
+    #[test]
+    fn upgrade_validates_handshake() {
+        let valid = vec![
+            "Upgrade: websocket".to_string(),
+            "Connection: keep-alive, Upgrade".to_string(),
+            "Sec-WebSocket-Version: 13".to_string(),
+            "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==".to_string(),
+        ];
+        let response = upgrade("GET", &valid, |_| Ok(())).response();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        let mut old_version = valid.clone();
+        old_version[2] = "Sec-WebSocket-Version: 8".to_string();
+        let response = upgrade("GET", &old_version, |_| Ok(())).response();
+        assert!(response.starts_with(b"HTTP/1.1 426 "));
+
+        let mut bad_key = valid.clone();
+        bad_key[3] = "Sec-WebSocket-Key: c2hvcnQ=".to_string();
+        let response = upgrade("GET", &bad_key, |_| Ok(())).response();
+        assert!(response.starts_with(b"HTTP/1.1 400 "));
+
+        let response = upgrade("POST", &valid, |_| Ok(())).response();
+        assert!(response.starts_with(b"HTTP/1.1 405 "));
+    }
+}